@@ -2,7 +2,7 @@ use anyhow::{anyhow, Error};
 use clap::{command, Arg};
 use dotenv::dotenv;
 use gtk::gdk::{Display, Texture};
-use gtk::gdk_pixbuf::PixbufLoader;
+use gtk::gdk_pixbuf::{Colorspace, Pixbuf, PixbufLoader};
 use gtk::glib::{clone, g_printerr};
 use gtk::pango::EllipsizeMode;
 use gtk::{
@@ -16,8 +16,10 @@ use gtk::{
 use hooya::proto::control_client::ControlClient;
 use hooya::proto::{
     CidInfoRequest, CidThumbnailRequest, ContentAtCidRequest,
-    LocalFilePageRequest, TagsRequest, Thumbnail,
+    ExecuteSavedSearchRequest, ListSavedSearchesRequest, LocalFilePageRequest,
+    RandomLocalFileRequest, SavedSearch, TagsRequest, Thumbnail,
 };
+use hooya::{duration_label, human_readable_size};
 use mason_grid_layout::MasonGridLayout;
 use std::collections::HashMap;
 use std::pin::Pin;
@@ -38,12 +40,22 @@ struct IncomingImage {
 }
 
 enum UiEvent {
-    GridItemClicked { file: hooya::proto::File },
+    GridItemClicked {
+        file: hooya::proto::File,
+    },
+    SavedSearchClicked {
+        id: i64,
+        name: String,
+        query: String,
+    },
+    ShuffleClicked,
+    SlideshowNext,
 }
 
 enum DataEvent {
     AppendImageToGrid {
         file: hooya::proto::File,
+        has_alpha: bool,
         stream: Pin<Box<dyn Stream<Item = IncomingImage> + Send>>,
     },
     ViewImage {
@@ -51,10 +63,21 @@ enum DataEvent {
         tags: HashMap<String, Vec<String>>,
         stream: Pin<Box<dyn Stream<Item = IncomingImage> + Send>>,
     },
+    ClearGrid {
+        title: String,
+    },
+    SavedSearches {
+        saved_searches: Vec<SavedSearch>,
+    },
+    Slide {
+        stream: Pin<Box<dyn Stream<Item = IncomingImage> + Send>>,
+    },
 }
 
 const APP_ID: &str = "org.hooya.hooya_gtk";
 
+const SLIDESHOW_INTERVAL_SECS: u32 = 5;
+
 fn main() -> glib::ExitCode {
     dotenv().ok();
     let matches = command!()
@@ -64,6 +87,12 @@ fn main() -> glib::ExitCode {
                 .env("HOOYAD_ENDPOINT")
                 .default_value(config::DEFAULT_HOOYAD_ENDPOINT),
         )
+        .arg(
+            Arg::new("owner")
+                .long("owner")
+                .env("USER")
+                .default_value(""),
+        )
         .get_matches();
 
     let application = Application::builder().application_id(APP_ID).build();
@@ -82,15 +111,16 @@ fn main() -> glib::ExitCode {
             matches.get_one::<String>("endpoint").unwrap()
         ))
         .unwrap();
+        let owner = matches.get_one::<String>("owner").unwrap().clone();
 
-        build_ui(app, endpoint);
+        build_ui(app, endpoint, owner);
     });
 
     let empty: Vec<String> = vec![];
     application.run_with_args(&empty)
 }
 
-fn build_ui(app: &Application, endpoint: Endpoint) {
+fn build_ui(app: &Application, endpoint: Endpoint, owner: String) {
     // Unbounded because this is called from buttons in the MainContext thread
     // and therefore .send() does not block
     let (ui_event_sender, mut ui_event_receiver) =
@@ -107,34 +137,30 @@ fn build_ui(app: &Application, endpoint: Endpoint) {
                     .expect("Connect to hooyad"); // TODO UI for this
             let mut client_2 = client_1.clone();
 
-            let j_1 = rt.spawn(clone!(@strong data_event_sender => async move {
+            let j_1 = rt.spawn(clone!(@strong data_event_sender, @strong owner => async move {
+                send_saved_searches(client_1.clone(), owner, &data_event_sender)
+                    .await;
+
                 let rand_files = client_1
                     .local_file_page(LocalFilePageRequest {
                         page_size: 100,
                         page_token: "0".to_string(),
                         oldest_first: false,
+                        query: "".to_string(),
                     }).await
                     .unwrap()
                     .into_inner()
                     .file;
 
-                for file in rand_files {
-                    let stream = request_cid_thumbnail(client_1.clone(), file.cid.clone())
-                        .await;
-                    let stream = match stream {
-                        Ok(s) => s,
-                        Err(e) => {
-                            g_printerr!("{}\n", e.to_string());
-                            continue
-                        },
-                    };
-                    data_event_sender
-                        .send(DataEvent::AppendImageToGrid { file, stream: Box::pin(stream) })
-                        .await
-                        .unwrap();
-                }
+                append_files_to_grid(client_1, rand_files, &data_event_sender)
+                    .await;
             }));
             let j_2 = rt.spawn(clone!(@strong data_event_sender => async move {
+                // Query behind whatever is shown in the grid
+                let mut current_query = String::new();
+                let mut slideshow_seed = None;
+                let mut slideshow_page_token = String::new();
+
                 while let Some(event) = ui_event_receiver.recv().await {
                     match event {
                         UiEvent::GridItemClicked { file } => {
@@ -150,6 +176,66 @@ fn build_ui(app: &Application, endpoint: Endpoint) {
                                 .await
                                 .unwrap();
                         }
+                        UiEvent::SavedSearchClicked { id, name, query } => {
+                            current_query = query;
+
+                            let files = client_2
+                                .execute_saved_search(ExecuteSavedSearchRequest {
+                                    id,
+                                    owner: owner.clone(),
+                                    page_size: 100,
+                                    page_token: "0".to_string(),
+                                }).await;
+                            let files = match files {
+                                Ok(f) => f.into_inner().file,
+                                Err(e) => {
+                                    g_printerr!("{}\n", e.to_string());
+                                    continue
+                                },
+                            };
+
+                            data_event_sender
+                                .send(DataEvent::ClearGrid { title: name })
+                                .await
+                                .unwrap();
+                            // Counts of new files are reset by running it
+                            send_saved_searches(
+                                client_2.clone(),
+                                owner.clone(),
+                                &data_event_sender
+                            ).await;
+                            append_files_to_grid(
+                                client_2.clone(),
+                                files,
+                                &data_event_sender
+                            ).await;
+                        }
+                        UiEvent::ShuffleClicked | UiEvent::SlideshowNext => {
+                            // Shuffling again starts a new sequence
+                            if let UiEvent::ShuffleClicked = event {
+                                slideshow_seed = None;
+                                slideshow_page_token = String::new();
+                            }
+
+                            let file = next_slide(
+                                client_2.clone(),
+                                &current_query,
+                                &mut slideshow_seed,
+                                &mut slideshow_page_token,
+                            ).await;
+                            let file = match file {
+                                Some(f) => f,
+                                None => continue,
+                            };
+
+                            let stream = Box::pin(
+                                request_data_at_cid(client_2.clone(), file.cid)
+                                .await);
+                            data_event_sender
+                                .send(DataEvent::Slide { stream })
+                                .await
+                                .unwrap();
+                        }
                     }
                 }
             }));
@@ -159,12 +245,109 @@ fn build_ui(app: &Application, endpoint: Endpoint) {
     });
 }
 
+async fn send_saved_searches(
+    mut client: ControlClient<Channel>,
+    owner: String,
+    data_event_sender: &Sender<DataEvent>,
+) {
+    let saved_searches = match client
+        .list_saved_searches(ListSavedSearchesRequest { owner })
+        .await
+    {
+        Ok(r) => r.into_inner().saved_search,
+        Err(e) => {
+            g_printerr!("{}\n", e.to_string());
+            return;
+        }
+    };
+
+    data_event_sender
+        .send(DataEvent::SavedSearches { saved_searches })
+        .await
+        .unwrap();
+}
+
+async fn append_files_to_grid(
+    client: ControlClient<Channel>,
+    files: Vec<hooya::proto::File>,
+    data_event_sender: &Sender<DataEvent>,
+) {
+    for file in files {
+        let thumbnail =
+            request_cid_thumbnail(client.clone(), file.cid.clone()).await;
+        let (has_alpha, stream) = match thumbnail {
+            Ok(s) => s,
+            Err(e) => {
+                g_printerr!("{}\n", e.to_string());
+                continue;
+            }
+        };
+        data_event_sender
+            .send(DataEvent::AppendImageToGrid {
+                file,
+                has_alpha,
+                stream: Box::pin(stream),
+            })
+            .await
+            .unwrap();
+    }
+}
+
+/// Next image in the seeded shuffle of a query, wrapping around once every
+/// match has been shown
+async fn next_slide(
+    mut client: ControlClient<Channel>,
+    query: &str,
+    seed: &mut Option<u64>,
+    page_token: &mut String,
+) -> Option<hooya::proto::File> {
+    // Bound the search in case the query matches only non-images
+    for _ in 0..100 {
+        let reply = client
+            .random_local_file(RandomLocalFileRequest {
+                count: 1,
+                query: query.to_string(),
+                seed: *seed,
+                page_token: page_token.clone(),
+            })
+            .await;
+        let reply = match reply {
+            Ok(r) => r.into_inner(),
+            Err(e) => {
+                g_printerr!("{}\n", e.to_string());
+                return None;
+            }
+        };
+
+        *seed = Some(reply.seed);
+        let wrapped = page_token.is_empty();
+        *page_token = reply.next_page_token;
+
+        match reply.file.into_iter().next() {
+            Some(f) => {
+                if let Some(hooya::proto::file::ExtFile::Image(_)) = f.ext_file
+                {
+                    return Some(f);
+                }
+            }
+            // Nothing matches at all
+            None if wrapped => return None,
+            None => *page_token = String::new(),
+        }
+    }
+
+    None
+}
+
 async fn request_data_at_cid(
     mut client: ControlClient<Channel>,
     cid: Vec<u8>,
 ) -> impl Stream<Item = IncomingImage> {
     let resp_res = client
-        .content_at_cid(ContentAtCidRequest { cid: cid.clone() })
+        .content_at_cid(ContentAtCidRequest {
+            cid: cid.clone(),
+            offset: None,
+        })
         .await;
     let inner_resp = resp_res.unwrap().into_inner();
 
@@ -179,10 +362,12 @@ async fn request_data_at_cid(
     )
 }
 
+/// Stream the thumbnail closest to grid size, along with whether it kept
+/// transparency
 async fn request_cid_thumbnail(
     mut client: ControlClient<Channel>,
     cid: Vec<u8>,
-) -> Result<impl Stream<Item = IncomingImage>, Error> {
+) -> Result<(bool, impl Stream<Item = IncomingImage>), Error> {
     let resp_file_info = client
         .cid_info(CidInfoRequest { cid: cid.clone() })
         .await?
@@ -195,11 +380,13 @@ async fn request_cid_thumbnail(
         None => return Err(anyhow!("No extended file information")),
     };
 
-    let thumbs = match ext_file {
-        hooya::proto::file::ExtFile::Image(i) => i.thumbnails,
-    };
+    let thumbs = ext_file.thumbnails().to_vec();
+    if thumbs.is_empty() {
+        return Err(anyhow!("No thumbnails"));
+    }
 
     let thumbnail = closest_thumbnail(&thumbs, 1280);
+    let has_alpha = thumbnail.has_alpha;
     let long_edge = if thumbnail.width > thumbnail.height {
         thumbnail.width
     } else {
@@ -212,18 +399,22 @@ async fn request_cid_thumbnail(
         .cid_thumbnail(CidThumbnailRequest {
             source_cid: cid,
             long_edge,
+            animated: false,
         })
         .await?
         .into_inner();
 
-    Ok(Box::pin(
-        chunk_stream
-            // Minimal delay to allow GUI to maybe update during stream
-            .throttle(Duration::from_millis(10))
-            .map(move |c| {
-                let chunk = c.unwrap().data;
-                IncomingImage { chunk }
-            }),
+    Ok((
+        has_alpha,
+        Box::pin(
+            chunk_stream
+                // Minimal delay to allow GUI to maybe update during stream
+                .throttle(Duration::from_millis(10))
+                .map(move |c| {
+                    let chunk = c.unwrap().data;
+                    IncomingImage { chunk }
+                }),
+        ),
     ))
 }
 fn build_browse_window(
@@ -263,6 +454,15 @@ fn build_browse_window(
 
     let h_box_search_button =
         Button::builder().icon_name("system-search").build();
+    let h_box_shuffle_button = Button::builder()
+        .icon_name("media-playlist-shuffle")
+        .tooltip_text("Shuffle this query")
+        .build();
+    h_box_shuffle_button.connect_clicked(
+        clone!(@strong ui_event_sender => move |_| {
+            ui_event_sender.send(UiEvent::ShuffleClicked).unwrap();
+        }),
+    );
     let h_box_text = Label::builder()
         .label("Browsing — All Files")
         .name("view-head")
@@ -270,6 +470,7 @@ fn build_browse_window(
         .hexpand(true)
         .build();
     h_box_head.append(&h_box_text);
+    h_box_head.append(&h_box_shuffle_button);
     h_box_head.append(&h_box_search_button);
     // let test_button = gtk::Button::builder()
     //     .label("Rip and tear!")
@@ -278,9 +479,29 @@ fn build_browse_window(
         .layout_manager(&MasonGridLayout::default())
         .name("view-grid")
         .build();
-    let h_box_browse = gtk::ScrolledWindow::builder().vexpand(true).build();
+    let h_box_browse = gtk::ScrolledWindow::builder()
+        .vexpand(true)
+        .hexpand(true)
+        .build();
     h_box_browse.set_child(Some(&m_grid));
-    v_box.append(&h_box_browse);
+
+    let saved_search_box = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .spacing(5)
+        .build();
+    let saved_search_sidebar = ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .name("saved-search-sidebar")
+        .child(&saved_search_box)
+        .build();
+
+    let h_box_main = gtk::Box::builder()
+        .orientation(Orientation::Horizontal)
+        .vexpand(true)
+        .build();
+    h_box_main.append(&saved_search_sidebar);
+    h_box_main.append(&h_box_browse);
+    v_box.append(&h_box_main);
 
     let h_box_footer = build_footer();
 
@@ -293,15 +514,28 @@ fn build_browse_window(
 
     // Network event receiver
     c.spawn_local(clone!(@weak app => async move {
+        let mut slideshow: Option<(ApplicationWindow, Picture)> = None;
+
         while let Some(event) = data_event_receiver.recv().await {
             match event {
-                DataEvent::AppendImageToGrid { file, mut stream } => {
+                DataEvent::AppendImageToGrid {
+                    file,
+                    has_alpha,
+                    mut stream,
+                } => {
                     let pb_loader = PixbufLoader::new();
                     let img = Picture::builder()
                         .content_fit(ContentFit::Fill)
                         .focusable(true)
                         .can_focus(true)
                         .build();
+                    // Checkerboard behind thumbnails that kept transparency
+                    if has_alpha {
+                        img.add_css_class("transparent");
+                    }
+                    if let Some(placeholder) = placeholder_texture(&file) {
+                        img.set_paintable(Some(&placeholder));
+                    }
                     m_grid.append(&img);
                     pb_loader.connect_area_prepared(clone!(@strong img => move |pb| {
                         let pixbuf = pb.pixbuf().unwrap();
@@ -342,6 +576,79 @@ fn build_browse_window(
                     build_file_view_window(&app, file, tags, stream)
                         .await;
                 }
+                DataEvent::ClearGrid { title } => {
+                    while let Some(child) = m_grid.first_child() {
+                        m_grid.remove(&child);
+                    }
+                    h_box_text.set_label(&format!("Browsing — {}", title));
+                }
+                DataEvent::SavedSearches { saved_searches } => {
+                    while let Some(child) = saved_search_box.first_child() {
+                        saved_search_box.remove(&child);
+                    }
+
+                    saved_search_box.append(
+                        &Label::builder()
+                            .label("Saved Searches")
+                            .halign(Align::Start)
+                            .css_classes(["subhead"])
+                            .build(),
+                    );
+
+                    for s in saved_searches {
+                        let label = if s.new_count > 0 {
+                            format!("{} ({} new)", s.name, s.new_count)
+                        } else {
+                            s.name.clone()
+                        };
+                        let button = Button::builder()
+                            .label(label)
+                            .tooltip_text(s.query.as_str())
+                            .build();
+
+                        button.connect_clicked(clone!(@strong ui_event_sender => move |_| {
+                            ui_event_sender
+                                .send(UiEvent::SavedSearchClicked {
+                                    id: s.id,
+                                    name: s.name.clone(),
+                                    query: s.query.clone(),
+                                })
+                                .unwrap();
+                        }));
+
+                        saved_search_box.append(&button);
+                    }
+                }
+                DataEvent::Slide { mut stream } => {
+                    let picture = match &slideshow {
+                        Some((window, picture)) if window.is_visible() => {
+                            picture.clone()
+                        }
+                        _ => {
+                            let (window, picture) = build_slideshow_window(
+                                &app,
+                                ui_event_sender.clone(),
+                            );
+                            slideshow = Some((window, picture.clone()));
+                            picture
+                        }
+                    };
+
+                    let pb_loader = PixbufLoader::new();
+                    pb_loader.connect_area_prepared(clone!(@strong picture => move |pb| {
+                        let pixbuf = pb.pixbuf().unwrap();
+                        picture.set_paintable(Some(&Texture::for_pixbuf(&pixbuf)));
+                    }));
+
+                    while let Some(i_img) = stream.next().await {
+                        let f_chunk = i_img.chunk;
+                        pb_loader.write(&f_chunk).unwrap();
+                    }
+
+                    if let Err(e) = pb_loader.close() {
+                        println!("AERR {}", e)
+                    }
+                }
             }
         }
     }));
@@ -350,6 +657,86 @@ fn build_browse_window(
     data_event_sender
 }
 
+fn build_slideshow_window(
+    app: &Application,
+    ui_event_sender: UnboundedSender<UiEvent>,
+) -> (ApplicationWindow, Picture) {
+    let window = ApplicationWindow::builder()
+        .application(app)
+        .title("Shuffle — HooYa!")
+        .default_width(800)
+        .default_height(800)
+        .build();
+
+    let v_box = gtk::Box::builder()
+        .orientation(Orientation::Vertical)
+        .build();
+
+    let picture = Picture::builder().vexpand(true).build();
+
+    let next_button = Button::builder()
+        .icon_name("media-skip-forward")
+        .halign(Align::Center)
+        .build();
+    next_button.connect_clicked(clone!(@strong ui_event_sender => move |_| {
+        ui_event_sender.send(UiEvent::SlideshowNext).unwrap();
+    }));
+
+    // Advance on a timer until the window goes away
+    glib::timeout_add_seconds_local(
+        SLIDESHOW_INTERVAL_SECS,
+        clone!(@weak window, @strong ui_event_sender => @default-return glib::Continue(false), move || {
+            if !window.is_visible() {
+                return glib::Continue(false);
+            }
+            ui_event_sender.send(UiEvent::SlideshowNext).unwrap();
+            glib::Continue(true)
+        }),
+    );
+
+    v_box.append(&picture);
+    v_box.append(&next_button);
+    window.set_child(Some(&v_box));
+    window.present();
+
+    (window, picture)
+}
+
+/// Blurred preview of an image, shaped like it, to show until its thumbnail
+/// arrives
+fn placeholder_texture(file: &hooya::proto::File) -> Option<Texture> {
+    const WIDTH: u32 = 32;
+
+    let image = match &file.ext_file {
+        Some(hooya::proto::file::ExtFile::Image(i)) => i,
+        _ => return None,
+    };
+    let hash = image.blurhash.as_ref()?;
+    let height = ((WIDTH as f32 / image.aspect_ratio).round() as u32).max(1);
+
+    let rgba = blurhash::decode(hash, WIDTH, height, 1.0).ok()?;
+    let pixbuf = Pixbuf::from_bytes(
+        &glib::Bytes::from_owned(rgba),
+        Colorspace::Rgb,
+        true,
+        8,
+        WIDTH as i32,
+        height as i32,
+        WIDTH as i32 * 4,
+    );
+
+    Some(Texture::for_pixbuf(&pixbuf))
+}
+
+fn dimensions_label(width: i64, height: i64) -> String {
+    format!(
+        "{}x{} ({:.1} MPixel)",
+        width,
+        height,
+        (width * height) as f64 / 1_000_000.0
+    )
+}
+
 fn build_footer() -> gtk::Box {
     let footer_peer_download_from_count_button =
         build_footer_peer_download_from_element();
@@ -483,8 +870,58 @@ async fn build_file_view_window(
     }
     props.push(("Size", human_readable_size(file.size), false));
 
-    // TODO Sample data until I write calculate this info server-side
-    props.push(("Dimensions", "1396x2500 (3.7 MPixel)".to_string(), false));
+    match &file.ext_file {
+        Some(hooya::proto::file::ExtFile::Image(i)) => {
+            props.push((
+                "Dimensions",
+                dimensions_label(i.width, i.height),
+                false,
+            ));
+        }
+        Some(hooya::proto::file::ExtFile::Video(v)) => {
+            props.push((
+                "Dimensions",
+                dimensions_label(v.width, v.height),
+                false,
+            ));
+
+            props.push(("Duration", duration_label(v.duration), false));
+
+            let codecs = match &v.audio_codec {
+                Some(a) => format!("{} / {}", v.video_codec, a),
+                None => v.video_codec.clone(),
+            };
+            props.push(("Codecs", codecs, false));
+            props.push((
+                "Frame rate",
+                format!("{:.2} fps", v.frame_rate),
+                false,
+            ));
+        }
+        Some(hooya::proto::file::ExtFile::Document(d)) => {
+            props.push(("Pages", d.page_count.to_string(), false));
+        }
+        Some(hooya::proto::file::ExtFile::Audio(a)) => {
+            for (name, value) in [
+                ("Title", &a.title),
+                ("Artist", &a.artist),
+                ("Album", &a.album),
+            ] {
+                if let Some(v) = value {
+                    props.push((name, v.clone(), false));
+                }
+            }
+            props.push(("Duration", duration_label(a.duration), false));
+            if a.sample_rate > 0 {
+                props.push((
+                    "Sample rate",
+                    format!("{:.1} kHz", a.sample_rate as f64 / 1000.0),
+                    false,
+                ));
+            }
+        }
+        None => {}
+    }
 
     for p in props {
         let row = gtk::Box::builder().spacing(10).build();
@@ -772,21 +1209,6 @@ fn build_page_nav(curr: u32, max: u32) -> gtk::Box {
     ret
 }
 
-fn human_readable_size(size: i64) -> String {
-    const SIZE_TRANSLATION: [(i64, &str); 4] =
-        [(4, "TiB"), (3, "GiB"), (2, "MiB"), (1, "KiB")];
-
-    for (power, label) in SIZE_TRANSLATION {
-        let dividend: i64 = 1 << (10 * power);
-        let div_res = size as f64 / dividend as f64;
-        if div_res.floor() >= 1.0 {
-            return format!("{:.2}{}", div_res, label);
-        }
-    }
-
-    format!("{}B", size)
-}
-
 fn closest_thumbnail(thumbnails: &[Thumbnail], long_edge: i64) -> &Thumbnail {
     thumbnails
         .iter()
@@ -1,12 +1,42 @@
 use anyhow::Result;
-use clap::{command, value_parser, Arg, ArgAction, Command};
+use clap::{command, value_parser, Arg, ArgAction, ArgGroup, Command};
 use dotenv::dotenv;
+use futures_util::StreamExt;
+use hooya::client::{AddOptions, AddReport, DirFilter, Progress, ReportEntry};
+use hooya::human_readable_size;
+use hooya::metadata::FieldMap;
 use hooya::proto::{
-    control_client::ControlClient, ContentAtCidRequest, ReimportRequest,
-    TagCidRequest,
+    control_client::ControlClient, CidInfoRequest, CreateSavedSearchRequest,
+    DeleteSavedSearchRequest, ExecuteSavedSearchRequest, FindDuplicatesRequest,
+    Job, ListJobsRequest, ListSavedSearchesRequest, LocalFilePageRequest,
+    RandomLocalFileRequest, RegenerateThumbnailsRequest, ReimportAllRequest,
+    ReimportRequest, RetryJobRequest, ReverseSearchRequest, TagCidRequest,
+    TagsRequest, WatchJobRequest,
 };
+use hooya::watch::{
+    AfterImport, FileStamp, Inbox, ProcessedLog, DEFAULT_SETTLE,
+    PROCESSED_LOG_NAME,
+};
+use import::{ImportReport, KeyKind, LocalFiles};
+use is_terminal::IsTerminal;
+use output::{
+    FileDetails, FilePage, FileSummary, Manifest, ManifestFile, Media,
+};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
 mod config;
+mod import;
+mod output;
+
+// How often add-dir redraws its progress line
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+// Files listed per request when downloading or exporting a query's results
+const DL_PAGE_SIZE: u32 = 100;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -19,6 +49,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .env("HOOYAD_ENDPOINT")
                 .default_value(config::DEFAULT_HOOYAD_ENDPOINT),
         )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Print listings and file details as JSON"),
+        )
         .subcommand(
             Command::new("add")
                 .arg(
@@ -42,6 +79,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .action(ArgAction::SetTrue)
                         .long("continue"),
                 )
+                .args(sidecar_args())
                 .arg(
                     Arg::new("files")
                         .action(ArgAction::Append)
@@ -66,12 +104,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .value_parser(value_parser!(hooya::proto::Tag))
                         .long("import-tag"),
                 )
+                .args(sidecar_args())
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(value_parser!(usize))
+                        .default_value("4")
+                        .help("Uploads in flight at once"),
+                )
+                .args(dir_filter_args())
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Write what became of every path here as JSON"),
+                )
                 .arg(
                     Arg::new("dirs")
                         .action(ArgAction::Append)
                         .value_parser(value_parser!(PathBuf)),
                 ),
         )
+        .subcommand(
+            Command::new("status")
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("import-tag")
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(hooya::proto::Tag))
+                        .long("import-tag")
+                        .help("Count a file missing this tag as differing"),
+                )
+                .arg(
+                    Arg::new("upload-missing")
+                        .action(ArgAction::SetTrue)
+                        .long("upload-missing")
+                        .help("Add the files hooyad doesn't have"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(value_parser!(usize))
+                        .default_value("4")
+                        .help("Files hashed or uploaded at once"),
+                )
+                .args(sidecar_args())
+                .args(dir_filter_args()),
+        )
         .subcommand(
             Command::new("tag").arg(Arg::new("cid").required(true)).arg(
                 Arg::new("tags")
@@ -80,10 +165,313 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .value_parser(value_parser!(hooya::proto::Tag)),
             ),
         )
-        .subcommand(Command::new("dl").arg(Arg::new("cid").required(true)))
+        .subcommand(
+            Command::new("ls")
+                .arg(Arg::new("query").long("query").default_value(""))
+                .arg(
+                    Arg::new("page-size")
+                        .long("page-size")
+                        .value_parser(value_parser!(u32))
+                        .default_value("50"),
+                )
+                .arg(
+                    Arg::new("page-token")
+                        .long("page-token")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("oldest-first")
+                        .action(ArgAction::SetTrue)
+                        .long("oldest-first"),
+                ),
+        )
+        .subcommand(
+            Command::new("random")
+                .arg(Arg::new("query").long("query").default_value(""))
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .value_parser(value_parser!(u32))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .value_parser(value_parser!(u64))
+                        .help("Repeat an earlier shuffle"),
+                )
+                .arg(
+                    Arg::new("page-token").long("page-token").default_value(""),
+                ),
+        )
+        .subcommand(Command::new("info").arg(Arg::new("cid").required(true)))
+        .subcommand(
+            Command::new("watch")
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("import-tag")
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(hooya::proto::Tag))
+                        .long("import-tag"),
+                )
+                .arg(
+                    Arg::new("unlink")
+                        .action(ArgAction::SetTrue)
+                        .long("unlink")
+                        .conflicts_with("archive-to"),
+                )
+                .arg(
+                    Arg::new("archive-to")
+                        .long("archive-to")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Move files here once imported"),
+                )
+                .arg(
+                    Arg::new("settle")
+                        .long("settle")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help(
+                            "Seconds a file must go unchanged before it is \
+                            imported, when nothing says it is complete",
+                        ),
+                )
+                .args(sidecar_args()),
+        )
+        .subcommand(
+            Command::new("dl")
+                .arg(Arg::new("cids").action(ArgAction::Append))
+                .arg(
+                    Arg::new("query")
+                        .long("query")
+                        .help("Download every file matching a tag query"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("File to write, or directory for several files"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(value_parser!(usize))
+                        .default_value("4"),
+                )
+                .group(
+                    ArgGroup::new("what")
+                        .args(["cids", "query"])
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            Command::new("import-hydrus")
+                .arg(
+                    Arg::new("db-dir")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory holding client.db"),
+                )
+                .arg(
+                    Arg::new("files")
+                        .long("files")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Hydrus file store, if not client_files there"),
+                )
+                .arg(field_arg())
+                .args(import_args()),
+        )
+        .subcommand(
+            Command::new("import-tags")
+                .arg(
+                    Arg::new("export")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .value_parser([
+                            "sha256", "sha1", "md5", "filename", "cid",
+                        ])
+                        .default_value("sha256")
+                        .help("What names the file each entry describes"),
+                )
+                .arg(
+                    Arg::new("key-field")
+                        .long("key-field")
+                        .help("Field holding the key, if not named after it"),
+                )
+                .arg(
+                    Arg::new("files")
+                        .long("files")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory of the files the export describes"),
+                )
+                .arg(field_arg())
+                .args(import_args()),
+        )
+        .subcommand(
+            Command::new("import-manifest")
+                .arg(
+                    Arg::new("manifest")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("manifest.json written by hooya export"),
+                )
+                .args(import_args()),
+        )
+        .subcommand(
+            Command::new("export")
+                .arg(
+                    Arg::new("dest")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(Arg::new("query").default_value(""))
+                .arg(
+                    Arg::new("by-tag")
+                        .action(ArgAction::SetTrue)
+                        .long("by-tag")
+                        .help("Link files into a by-tag directory per tag"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_parser(value_parser!(usize))
+                        .default_value("4"),
+                ),
+        )
         .subcommand(
             Command::new("reimport").arg(Arg::new("cid").required(true)),
         )
+        .subcommand(
+            Command::new("reimport-all")
+                .arg(
+                    Arg::new("query")
+                        .long("query")
+                        .help(
+                            "Only reprocess files matching this, eg type:image",
+                        )
+                        .default_value(""),
+                )
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .help("ID of an interrupted run to pick up")
+                        .value_parser(value_parser!(i64))
+                        .conflicts_with("query"),
+                ),
+        )
+        .subcommand(Command::new("regenerate-thumbnails"))
+        .subcommand(
+            Command::new("dupes").arg(
+                Arg::new("distance")
+                    .long("distance")
+                    .value_parser(value_parser!(u32))
+                    .default_value("10"),
+            ),
+        )
+        .subcommand(
+            Command::new("lookup")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("distance")
+                        .long("distance")
+                        .value_parser(value_parser!(u32))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .value_parser(value_parser!(u32))
+                        .default_value("20"),
+                ),
+        )
+        .subcommand(
+            Command::new("saved")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("owner")
+                        .long("owner")
+                        .env("USER")
+                        .default_value(""),
+                )
+                .subcommand(Command::new("list"))
+                .subcommand(
+                    Command::new("add")
+                        .arg(Arg::new("name").required(true))
+                        .arg(Arg::new("query").required(true))
+                        .arg(
+                            Arg::new("sort")
+                                .long("sort")
+                                .value_parser(["newest", "oldest"])
+                                .default_value("newest"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("rm").arg(
+                        Arg::new("id")
+                            .required(true)
+                            .value_parser(value_parser!(i64)),
+                    ),
+                )
+                .subcommand(
+                    Command::new("run")
+                        .arg(
+                            Arg::new("id")
+                                .required(true)
+                                .value_parser(value_parser!(i64)),
+                        )
+                        .arg(
+                            Arg::new("page-size")
+                                .long("page-size")
+                                .value_parser(value_parser!(u32))
+                                .default_value("100"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("jobs")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .arg(Arg::new("state").long("state").value_parser([
+                            "pending", "running", "done", "failed",
+                        ]))
+                        .arg(
+                            Arg::new("limit")
+                                .long("limit")
+                                .value_parser(value_parser!(u32))
+                                .default_value("0"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("watch").arg(
+                        Arg::new("id")
+                            .required(true)
+                            .value_parser(value_parser!(i64)),
+                    ),
+                )
+                .subcommand(
+                    Command::new("retry").arg(
+                        Arg::new("id")
+                            .required(true)
+                            .value_parser(value_parser!(i64)),
+                    ),
+                ),
+        )
         .get_matches();
 
     let mut client = ControlClient::connect(format!(
@@ -92,9 +480,91 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     ))
     .await?;
 
+    let json = matches.get_flag("json");
+
     match matches.subcommand() {
+        Some(("ls", sub_matches)) => {
+            let page_size = *sub_matches.get_one::<u32>("page-size").unwrap();
+            let reply = client
+                .local_file_page(LocalFilePageRequest {
+                    page_size,
+                    page_token: sub_matches
+                        .get_one::<String>("page-token")
+                        .unwrap()
+                        .clone(),
+                    oldest_first: sub_matches.get_flag("oldest-first"),
+                    query: sub_matches
+                        .get_one::<String>("query")
+                        .unwrap()
+                        .clone(),
+                })
+                .await?
+                .into_inner();
+
+            let full_page = reply.file.len() == page_size as usize;
+            let page = FilePage {
+                files: reply.file.iter().map(FileSummary::from).collect(),
+                next_page_token: reply.next_page_token,
+                seed: None,
+            };
+            page.print(json)?;
+
+            if !json && full_page {
+                eprintln!("More: --page-token {}", page.next_page_token);
+            }
+        }
+        Some(("random", sub_matches)) => {
+            let reply = client
+                .random_local_file(RandomLocalFileRequest {
+                    count: *sub_matches.get_one::<u32>("count").unwrap(),
+                    query: sub_matches
+                        .get_one::<String>("query")
+                        .unwrap()
+                        .clone(),
+                    seed: sub_matches.get_one::<u64>("seed").copied(),
+                    page_token: sub_matches
+                        .get_one::<String>("page-token")
+                        .unwrap()
+                        .clone(),
+                })
+                .await?
+                .into_inner();
+
+            FilePage {
+                files: reply.file.iter().map(FileSummary::from).collect(),
+                next_page_token: reply.next_page_token,
+                seed: Some(reply.seed),
+            }
+            .print(json)?;
+        }
+        Some(("info", sub_matches)) => {
+            let encoded_cid = sub_matches.get_one::<String>("cid").unwrap();
+            let (_, cid) = hooya::cid::decode(encoded_cid)?;
+
+            let info = client
+                .cid_info(CidInfoRequest { cid: cid.clone() })
+                .await?
+                .into_inner();
+            let file = info.file.ok_or_else(|| {
+                anyhow::anyhow!("{} is not indexed", encoded_cid)
+            })?;
+            let tags =
+                client.tags(TagsRequest { cid }).await?.into_inner().tags;
+
+            FileDetails {
+                file: FileSummary::from(&file),
+                media: file.ext_file.as_ref().map(Media::from),
+                analysis: info.analysis.map(|a| a.status),
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                thumbnails: file
+                    .ext_file
+                    .as_ref()
+                    .map(|e| e.thumbnails().iter().map(Into::into).collect())
+                    .unwrap_or_default(),
+            }
+            .print(json)?;
+        }
         Some(("add", sub_matches)) => {
-            use std::fs::File;
             let just_hash =
                 *sub_matches.get_one::<bool>("just-hash").unwrap_or(&false);
             let unlink =
@@ -106,6 +576,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .unwrap_or_default()
                 .cloned()
                 .collect();
+            let sidecars = sidecar_field_map(sub_matches)?;
             let files = sub_matches
                 .get_many::<PathBuf>("files")
                 .unwrap_or_default()
@@ -113,14 +584,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             for f in &files {
                 if just_hash {
-                    let fh = File::open(f)?;
-                    let chunks = hooya::ChunkedReader::new(fh);
-                    let mut sha_context = hooya::cid::new_digest_context();
-
-                    for c in chunks {
-                        sha_context.update(&c?);
-                    }
-                    let digest = hooya::cid::wrap_digest(sha_context.finish())?;
+                    let digest = hooya::client::hash_file(f)?;
                     println!(
                         "hashed {} {}",
                         hooya::cid::encode(digest),
@@ -128,14 +592,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     );
                     continue;
                 }
-                hooya::client::stream_file_to_remote_filestore(
+                let result = hooya::client::stream_file_to_remote_filestore(
                     client.clone(),
                     f,
                     unlink,
-                    cont_inue,
                     import_tags.clone(),
+                    sidecars.as_ref(),
                 )
-                .await?;
+                .await;
+                match result {
+                    Ok(()) => {}
+                    Err(e) if cont_inue => eprintln!("{}", e),
+                    Err(e) => return Err(e.into()),
+                }
             }
         }
         Some(("add-dir", sub_matches)) => {
@@ -153,16 +622,176 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .unwrap_or_default()
                 .cloned()
                 .collect();
+            let options = AddOptions {
+                unlink,
+                cont_inue,
+                init_tags: import_tags,
+                sidecars: sidecar_field_map(sub_matches)?,
+                concurrency: *sub_matches.get_one::<usize>("jobs").unwrap(),
+                filter: dir_filter(sub_matches)?,
+            };
 
+            let progress = Arc::new(Progress::default());
+            let ticker = std::io::stderr()
+                .is_terminal()
+                .then(|| tokio::spawn(show_progress(progress.clone())));
+
+            let mut report = AddReport::default();
             for d in &dirs {
-                hooya::client::stream_dir_to_remote_filestore(
+                let result = hooya::client::stream_dir_to_remote_filestore(
                     client.clone(),
                     d,
-                    unlink,
-                    cont_inue,
-                    import_tags.clone(),
+                    &options,
+                    progress.clone(),
                 )
-                .await?;
+                .await;
+                match result {
+                    Ok(r) => report.merge(r),
+                    Err(e) => {
+                        if let Some(t) = &ticker {
+                            t.abort();
+                        }
+                        return Err(e.into());
+                    }
+                }
+            }
+
+            if let Some(t) = ticker {
+                t.abort();
+                eprintln!();
+            }
+
+            if let Some(path) = sub_matches.get_one::<PathBuf>("report") {
+                std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                for f in &report.failed {
+                    eprintln!(
+                        "failed {}: {}",
+                        f.path.display(),
+                        f.reason.as_deref().unwrap_or_default()
+                    );
+                }
+                eprintln!(
+                    "{} added, {} duplicate, {} skipped, {} failed",
+                    report.added.len(),
+                    report.duplicate.len(),
+                    report.skipped.len(),
+                    report.failed.len()
+                );
+            }
+        }
+        Some(("status", sub_matches)) => {
+            let dir = sub_matches.get_one::<PathBuf>("dir").unwrap();
+            let options = AddOptions {
+                unlink: false,
+                cont_inue: true,
+                init_tags: sub_matches
+                    .get_many::<hooya::proto::Tag>("import-tag")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                sidecars: sidecar_field_map(sub_matches)?,
+                concurrency: *sub_matches.get_one::<usize>("jobs").unwrap(),
+                filter: dir_filter(sub_matches)?,
+            };
+
+            let status =
+                hooya::client::dir_status(client.clone(), dir, &options)
+                    .await?;
+
+            // Only the files found missing are uploaded, each with the tags
+            // add-dir would give it
+            let mut uploaded = AddReport::default();
+            if sub_matches.get_flag("upload-missing") {
+                let results = futures_util::stream::iter(&status.new)
+                    .map(|entry| {
+                        let client = client.clone();
+                        let options = &options;
+                        async move {
+                            let tags = hooya::client::file_tags(
+                                &entry.path,
+                                &options.init_tags,
+                                options.sidecars.as_ref(),
+                            );
+                            let result = match tags {
+                                Ok(tags) => {
+                                    hooya::client::upload_file(
+                                        client,
+                                        &entry.path,
+                                        tags,
+                                        None,
+                                    )
+                                    .await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            (entry.path.clone(), result)
+                        }
+                    })
+                    .buffer_unordered(options.concurrency.max(1))
+                    .collect::<Vec<_>>()
+                    .await;
+                for (path, result) in results {
+                    match result {
+                        Ok(u) => uploaded.added.push(ReportEntry {
+                            path,
+                            cid: Some(hooya::cid::encode(u.cid)),
+                            reason: None,
+                        }),
+                        Err(e) => uploaded.failed.push(ReportEntry {
+                            path,
+                            cid: None,
+                            reason: Some(e.to_string()),
+                        }),
+                    }
+                }
+            }
+
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "status": status,
+                        "uploaded": uploaded,
+                    }))?
+                );
+            } else {
+                for f in &status.new {
+                    println!("new     {}", f.path.display());
+                }
+                for d in &status.tags_differ {
+                    println!(
+                        "tags    {}  missing {}",
+                        d.path.display(),
+                        d.missing.join(" ")
+                    );
+                }
+                for f in status.failed.iter().chain(&uploaded.failed) {
+                    eprintln!(
+                        "failed {}: {}",
+                        f.path.display(),
+                        f.reason.as_deref().unwrap_or_default()
+                    );
+                }
+                eprintln!(
+                    "{} new, {} stored ({} with differing tags), {} skipped, \
+                    {} failed",
+                    status.new.len(),
+                    status.stored.len(),
+                    status.tags_differ.len(),
+                    status.skipped.len(),
+                    status.failed.len()
+                );
+                if sub_matches.get_flag("upload-missing") {
+                    eprintln!(
+                        "{} uploaded, {} failed to upload",
+                        uploaded.added.len(),
+                        uploaded.failed.len()
+                    );
+                }
             }
         }
         Some(("tag", sub_matches)) => {
@@ -175,22 +804,364 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .collect();
             client.tag_cid(TagCidRequest { cid, tags }).await?;
         }
+        Some(("watch", sub_matches)) => {
+            let dir = sub_matches.get_one::<PathBuf>("dir").unwrap();
+            let dir = dir.canonicalize()?;
+            let import_tags: Vec<hooya::proto::Tag> = sub_matches
+                .get_many::<hooya::proto::Tag>("import-tag")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            let after = match sub_matches.get_one::<PathBuf>("archive-to") {
+                Some(d) => AfterImport::Archive(d.clone()),
+                None if sub_matches.get_flag("unlink") => AfterImport::Delete,
+                None => AfterImport::Keep,
+            };
+            after.check(&dir)?;
+            let settle = match sub_matches.get_one::<u64>("settle") {
+                Some(s) => Duration::from_secs(*s),
+                None => DEFAULT_SETTLE,
+            };
+            let sidecars = sidecar_field_map(sub_matches)?;
+
+            let mut processed =
+                ProcessedLog::load(&dir.join(PROCESSED_LOG_NAME))?;
+            let mut inbox = Inbox::new(&dir, settle, sidecars.is_some())?;
+            eprintln!("watching {}", dir.display());
+
+            loop {
+                for path in inbox.next().await? {
+                    let stamp = match FileStamp::of(&path) {
+                        Ok(s) => s,
+                        Err(_) => continue,
+                    };
+                    if stamp.size == 0 || processed.contains(&path, stamp) {
+                        continue;
+                    }
+
+                    // Sidecars are read now, before anything moves them
+                    let sidecar_paths = match sidecars {
+                        Some(_) => hooya::metadata::sidecar_paths(&path),
+                        None => vec![],
+                    };
+                    let tags = match hooya::client::file_tags(
+                        &path,
+                        &import_tags,
+                        sidecars.as_ref(),
+                    ) {
+                        Ok(t) => t,
+                        Err(e) => {
+                            eprintln!("{}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+
+                    let uploaded = match hooya::client::upload_file(
+                        client.clone(),
+                        &path,
+                        tags,
+                        None,
+                    )
+                    .await
+                    {
+                        Ok(u) => u,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        }
+                    };
+                    println!(
+                        "{} {} {}",
+                        if uploaded.duplicate {
+                            "duplicate"
+                        } else {
+                            "added"
+                        },
+                        hooya::cid::encode(&uploaded.cid),
+                        path.display()
+                    );
+
+                    // Sidecars go where their file went. One shared with
+                    // another file may be gone already
+                    for p in std::iter::once(&path).chain(&sidecar_paths) {
+                        if !p.exists() {
+                            continue;
+                        }
+                        if let Err(e) = after.apply(&dir, p) {
+                            eprintln!("{}: {}", p.display(), e);
+                        }
+                    }
+                    // Files moved away or deleted won't be seen again
+                    if let AfterImport::Keep = after {
+                        processed.record(&path, stamp)?;
+                    }
+                }
+            }
+        }
         Some(("dl", sub_matches)) => {
-            use std::fs::File;
-            use std::io::Write;
+            let query = sub_matches.get_one::<String>("query");
+            let output = sub_matches.get_one::<PathBuf>("output");
+            let jobs = *sub_matches.get_one::<usize>("jobs").unwrap();
 
-            let encoded_cid = sub_matches.get_one::<String>("cid").unwrap();
-            let (_, cid) = hooya::cid::decode(encoded_cid)?;
-            let mut chunk_stream = client
-                .content_at_cid(ContentAtCidRequest { cid })
-                .await?
-                .into_inner();
+            // Mimetypes are known up front for query results only
+            let mut named: Vec<(Vec<u8>, Option<String>)> = vec![];
+            for encoded_cid in
+                sub_matches.get_many::<String>("cids").unwrap_or_default()
+            {
+                let (_, cid) = hooya::cid::decode(encoded_cid)?;
+                named.push((cid, None));
+            }
+            if let Some(query) = query {
+                named.extend(
+                    matching_files(&mut client, query)
+                        .await?
+                        .into_iter()
+                        .map(|f| (f.cid, f.mimetype)),
+                );
+            }
+
+            // A CID named twice, or also matched by the query, is fetched
+            // once so concurrent downloads never write the same file
+            let mut wanted: Vec<(Vec<u8>, Option<String>)> = vec![];
+            let mut positions: HashMap<Vec<u8>, usize> = HashMap::new();
+            for (cid, mimetype) in named {
+                match positions.get(&cid) {
+                    Some(&i) => {
+                        if wanted[i].1.is_none() {
+                            wanted[i].1 = mimetype;
+                        }
+                    }
+                    None => {
+                        positions.insert(cid.clone(), wanted.len());
+                        wanted.push((cid, mimetype));
+                    }
+                }
+            }
+
+            // Only a lone CID may be written to a file of the user's naming
+            let single_file = match output {
+                Some(o)
+                    if query.is_none() && wanted.len() == 1 && !o.is_dir() =>
+                {
+                    Some(o.clone())
+                }
+                _ => None,
+            };
+            let dir = output.cloned().unwrap_or_else(|| PathBuf::from("."));
+            if single_file.is_none() {
+                std::fs::create_dir_all(&dir)?;
+            }
+
+            let results = futures_util::stream::iter(wanted)
+                .map(|(cid, mimetype)| {
+                    let mut client = client.clone();
+                    let single_file = single_file.clone();
+                    let dir = dir.clone();
+                    async move {
+                        let encoded_cid = hooya::cid::encode(&cid);
+                        let dest = match single_file {
+                            Some(f) => f,
+                            None => {
+                                let mimetype = match mimetype {
+                                    Some(m) => Some(m),
+                                    None => client
+                                        .cid_info(CidInfoRequest {
+                                            cid: cid.clone(),
+                                        })
+                                        .await?
+                                        .into_inner()
+                                        .file
+                                        .and_then(|f| f.mimetype),
+                                };
+                                dir.join(dl_file_name(&encoded_cid, mimetype))
+                            }
+                        };
 
-            // For now just write to a file in the current path
-            let mut file = File::create(encoded_cid)?;
+                        if dest.exists() {
+                            println!(
+                                "exists {} {}",
+                                encoded_cid,
+                                dest.display()
+                            );
+                            return Ok(());
+                        }
 
-            while let Some(m) = chunk_stream.message().await? {
-                file.write_all(&m.data)?;
+                        hooya::client::download_cid_to_file(client, cid, &dest)
+                            .await?;
+                        println!(
+                            "downloaded {} {}",
+                            encoded_cid,
+                            dest.display()
+                        );
+                        Ok::<_, anyhow::Error>(())
+                    }
+                })
+                .buffer_unordered(jobs)
+                .collect::<Vec<_>>()
+                .await;
+
+            // Failures don't stop the rest of a bulk download, but are
+            // reported at the end
+            let failed = results.iter().filter(|r| r.is_err()).count();
+            for e in results.into_iter().filter_map(|r| r.err()) {
+                eprintln!("{}", e);
+            }
+            if failed > 0 {
+                return Err(
+                    anyhow::anyhow!("{} downloads failed", failed).into()
+                );
+            }
+        }
+        Some(("import-hydrus", sub_matches)) => {
+            let db_dir = sub_matches.get_one::<PathBuf>("db-dir").unwrap();
+            let files_dir = sub_matches
+                .get_one::<PathBuf>("files")
+                .cloned()
+                .unwrap_or_else(|| db_dir.join("client_files"));
+
+            let (entries, local) =
+                import::hydrus_entries(db_dir, &files_dir).await?;
+            let report = import::import_entries(
+                client.clone(),
+                entries,
+                &local,
+                &import_field_map(sub_matches)?,
+                !sub_matches.get_flag("no-upload"),
+            )
+            .await?;
+            finish_import(sub_matches, &report, json)?;
+        }
+        Some(("import-tags", sub_matches)) => {
+            let export = sub_matches.get_one::<PathBuf>("export").unwrap();
+            let key = sub_matches.get_one::<String>("key").unwrap();
+            let kind: KeyKind = key.parse()?;
+            let key_field =
+                sub_matches.get_one::<String>("key-field").unwrap_or(key);
+
+            let entries = match export.extension().and_then(|e| e.to_str()) {
+                Some("csv") => import::csv_entries(export, key_field)?,
+                _ => import::json_entries(export, key_field)?,
+            };
+            let local = match sub_matches.get_one::<PathBuf>("files") {
+                Some(dir) => LocalFiles::index(dir, kind)?,
+                None => LocalFiles::empty(kind),
+            };
+            let report = import::import_entries(
+                client.clone(),
+                entries,
+                &local,
+                &import_field_map(sub_matches)?,
+                !sub_matches.get_flag("no-upload"),
+            )
+            .await?;
+            finish_import(sub_matches, &report, json)?;
+        }
+        Some(("import-manifest", sub_matches)) => {
+            let manifest = sub_matches.get_one::<PathBuf>("manifest").unwrap();
+
+            let (entries, local) = import::manifest_entries(manifest)?;
+            let report = import::import_entries(
+                client.clone(),
+                entries,
+                &local,
+                &FieldMap::default(),
+                !sub_matches.get_flag("no-upload"),
+            )
+            .await?;
+            finish_import(sub_matches, &report, json)?;
+        }
+        Some(("export", sub_matches)) => {
+            let dest = sub_matches.get_one::<PathBuf>("dest").unwrap();
+            let query = sub_matches.get_one::<String>("query").unwrap();
+            let jobs = *sub_matches.get_one::<usize>("jobs").unwrap();
+            std::fs::create_dir_all(dest.join("files"))?;
+
+            // Names are chosen in listing order, so a rerun picks the same ones
+            let mut manifest = Manifest::default();
+            let mut taken = HashSet::new();
+            for f in matching_files(&mut client, query).await? {
+                let file = client
+                    .cid_info(CidInfoRequest { cid: f.cid.clone() })
+                    .await?
+                    .into_inner()
+                    .file
+                    .unwrap_or(f);
+                let tags = client
+                    .tags(TagsRequest {
+                        cid: file.cid.clone(),
+                    })
+                    .await?
+                    .into_inner()
+                    .tags;
+
+                let encoded_cid = hooya::cid::encode(&file.cid);
+                let name = export_file_name(&file, &encoded_cid, &mut taken);
+                manifest.files.push(ManifestFile {
+                    cid: encoded_cid,
+                    path: Path::new("files").join(name),
+                    names: file.names.clone(),
+                    mimetype: file.mimetype.clone(),
+                    size: file.size,
+                    media: file.ext_file.as_ref().map(Media::from),
+                    tags: tags.iter().map(|t| t.to_string()).collect(),
+                });
+            }
+
+            let results = futures_util::stream::iter(&manifest.files)
+                .map(|m| {
+                    let client = client.clone();
+                    let path = dest.join(&m.path);
+                    async move {
+                        // A copy from an earlier export is kept, once its
+                        // contents are known to be the same
+                        let existing = std::fs::metadata(&path).ok();
+                        if existing.map(|e| e.len() as i64) == Some(m.size) {
+                            let hashed = path.clone();
+                            let digest =
+                                tokio::task::spawn_blocking(move || {
+                                    hooya::client::hash_file(&hashed)
+                                })
+                                .await??;
+                            if hooya::cid::encode(digest) == m.cid {
+                                return Ok(());
+                            }
+                        }
+
+                        let (_, cid) = hooya::cid::decode(&m.cid)?;
+                        hooya::client::download_cid_to_file(client, cid, &path)
+                            .await?;
+                        if !json {
+                            println!("exported {} {}", m.cid, path.display());
+                        }
+                        Ok::<_, anyhow::Error>(())
+                    }
+                })
+                .buffer_unordered(jobs)
+                .collect::<Vec<_>>()
+                .await;
+
+            if sub_matches.get_flag("by-tag") {
+                write_tag_links(&dest.join("by-tag"), &manifest.files)?;
+            }
+
+            std::fs::write(
+                dest.join("manifest.json"),
+                serde_json::to_string_pretty(&manifest)?,
+            )?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&manifest)?);
+            }
+
+            let failed = results.iter().filter(|r| r.is_err()).count();
+            for e in results.into_iter().filter_map(|r| r.err()) {
+                eprintln!("{}", e);
+            }
+            if failed > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} files failed to export",
+                    failed
+                )
+                .into());
             }
         }
         Some(("reimport", sub_matches)) => {
@@ -199,8 +1170,506 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             client.reimport(ReimportRequest { cid }).await?;
         }
+        Some(("reimport-all", sub_matches)) => {
+            let mut progress = client
+                .reimport_all(ReimportAllRequest {
+                    query: sub_matches
+                        .get_one::<String>("query")
+                        .unwrap()
+                        .clone(),
+                    resume: sub_matches.get_one::<i64>("resume").copied(),
+                })
+                .await?
+                .into_inner();
+
+            let mut failed = 0;
+            let mut last = None;
+            while let Some(p) = progress.message().await? {
+                if last.is_none() {
+                    eprintln!(
+                        "Reimport run {}, resume with --resume {}",
+                        p.run_id, p.run_id
+                    );
+                }
+
+                if p.error.is_empty() {
+                    println!(
+                        "{}/{} {}",
+                        p.processed,
+                        p.total,
+                        hooya::cid::encode(&p.cid)
+                    );
+                } else {
+                    failed += 1;
+                    eprintln!(
+                        "{}/{} {} {}",
+                        p.processed,
+                        p.total,
+                        hooya::cid::encode(&p.cid),
+                        p.error
+                    );
+                }
+                last = Some(p);
+            }
+
+            match last {
+                Some(p) => eprintln!(
+                    "Processed {} of {} files, {} failed",
+                    p.processed, p.total, failed
+                ),
+                None => eprintln!("Nothing to reprocess"),
+            }
+        }
+        Some(("regenerate-thumbnails", _)) => {
+            let mut progress = client
+                .regenerate_thumbnails(RegenerateThumbnailsRequest {})
+                .await?
+                .into_inner();
+
+            let (mut checked, mut regenerated, mut failed) = (0, 0, 0);
+            while let Some(p) = progress.message().await? {
+                checked += 1;
+                if !p.error.is_empty() {
+                    failed += 1;
+                    eprintln!("{} {}", hooya::cid::encode(&p.cid), p.error);
+                } else if p.regenerated {
+                    regenerated += 1;
+                    println!("{}", hooya::cid::encode(&p.cid));
+                }
+            }
+
+            eprintln!(
+                "Checked {} files, regenerated {}, {} failed",
+                checked, regenerated, failed
+            );
+        }
+        Some(("dupes", sub_matches)) => {
+            let max_distance = *sub_matches.get_one::<u32>("distance").unwrap();
+
+            let groups = client
+                .find_duplicates(FindDuplicatesRequest { max_distance })
+                .await?
+                .into_inner()
+                .group;
+
+            for (i, g) in groups.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                for f in &g.file {
+                    println!("{} {}", hooya::cid::encode(&f.cid), f.distance);
+                }
+            }
+        }
+        Some(("lookup", sub_matches)) => {
+            let f = sub_matches.get_one::<PathBuf>("file").unwrap();
+            let max_distance = *sub_matches.get_one::<u32>("distance").unwrap();
+            let count = *sub_matches.get_one::<u32>("count").unwrap();
+
+            let chunks = hooya::ChunkedReader::new(std::fs::File::open(f)?)
+                .map(move |c| ReverseSearchRequest {
+                    data: c.unwrap(),
+                    max_distance,
+                    count,
+                });
+
+            let results = client
+                .reverse_search(futures_util::stream::iter(chunks))
+                .await?
+                .into_inner()
+                .result;
+
+            for r in results {
+                println!(
+                    "{} {} {:.1}",
+                    hooya::cid::encode(r.cid),
+                    r.distance,
+                    r.color_distance
+                );
+            }
+        }
+        Some(("saved", sub_matches)) => {
+            let owner = sub_matches.get_one::<String>("owner").unwrap().clone();
+
+            match sub_matches.subcommand() {
+                Some(("list", _)) => {
+                    let saved_searches = client
+                        .list_saved_searches(ListSavedSearchesRequest { owner })
+                        .await?
+                        .into_inner()
+                        .saved_search;
+
+                    for s in saved_searches {
+                        println!(
+                            "{}\t{}\t{} new\t{} ({})",
+                            s.id, s.name, s.new_count, s.query, s.sort
+                        );
+                    }
+                }
+                Some(("add", add_matches)) => {
+                    let saved_search = client
+                        .create_saved_search(CreateSavedSearchRequest {
+                            name: add_matches
+                                .get_one::<String>("name")
+                                .unwrap()
+                                .clone(),
+                            query: add_matches
+                                .get_one::<String>("query")
+                                .unwrap()
+                                .clone(),
+                            sort: add_matches
+                                .get_one::<String>("sort")
+                                .unwrap()
+                                .clone(),
+                            owner,
+                        })
+                        .await?
+                        .into_inner()
+                        .saved_search
+                        .unwrap();
+
+                    println!("saved {} {}", saved_search.id, saved_search.name);
+                }
+                Some(("rm", rm_matches)) => {
+                    let id = *rm_matches.get_one::<i64>("id").unwrap();
+                    client
+                        .delete_saved_search(DeleteSavedSearchRequest {
+                            id,
+                            owner,
+                        })
+                        .await?;
+                }
+                Some(("run", run_matches)) => {
+                    let id = *run_matches.get_one::<i64>("id").unwrap();
+                    let page_size =
+                        *run_matches.get_one::<u32>("page-size").unwrap();
+
+                    let files = client
+                        .execute_saved_search(ExecuteSavedSearchRequest {
+                            id,
+                            owner,
+                            page_size,
+                            page_token: "0".to_string(),
+                        })
+                        .await?
+                        .into_inner()
+                        .file;
+
+                    for f in files {
+                        println!(
+                            "{} {}",
+                            hooya::cid::encode(f.cid),
+                            f.mimetype.unwrap_or_default()
+                        );
+                    }
+                }
+                _ => unreachable!("Exhausted list of subcommands"),
+            }
+        }
+        Some(("jobs", sub_matches)) => match sub_matches.subcommand() {
+            Some(("list", list_matches)) => {
+                let jobs = client
+                    .list_jobs(ListJobsRequest {
+                        state: list_matches.get_one::<String>("state").cloned(),
+                        limit: *list_matches.get_one::<u32>("limit").unwrap(),
+                    })
+                    .await?
+                    .into_inner()
+                    .job;
+
+                for j in jobs {
+                    print_job(&j);
+                }
+            }
+            Some(("watch", watch_matches)) => {
+                let id = *watch_matches.get_one::<i64>("id").unwrap();
+                let mut updates = client
+                    .watch_job(WatchJobRequest { id })
+                    .await?
+                    .into_inner();
+
+                while let Some(j) = updates.message().await? {
+                    print_job(&j);
+                }
+            }
+            Some(("retry", retry_matches)) => {
+                let id = *retry_matches.get_one::<i64>("id").unwrap();
+                let job = client
+                    .retry_job(RetryJobRequest { id })
+                    .await?
+                    .into_inner()
+                    .job
+                    .unwrap();
+
+                print_job(&job);
+            }
+            _ => unreachable!("Exhausted list of subcommands"),
+        },
         _ => unreachable!("Exhausted list of subcommands"),
     }
 
     Ok(())
 }
+
+/// Redraw a line of files and bytes uploaded so far until cancelled
+async fn show_progress(progress: Arc<Progress>) {
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+    // The first tick is immediate, too soon to have a rate
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        let bytes_done = progress.bytes_done.load(Ordering::Relaxed);
+        let rate = bytes_done as f64 / started.elapsed().as_secs_f64();
+        eprint!(
+            "\r{}/{} files  {}/{}  {}/s   ",
+            progress.files_done.load(Ordering::Relaxed),
+            progress.files_total.load(Ordering::Relaxed),
+            human_readable_size(bytes_done as i64),
+            human_readable_size(
+                progress.bytes_total.load(Ordering::Relaxed) as i64
+            ),
+            human_readable_size(rate as i64),
+        );
+    }
+}
+
+/// Every file matching a query, oldest first
+async fn matching_files(
+    client: &mut ControlClient<Channel>,
+    query: &str,
+) -> Result<Vec<hooya::proto::File>> {
+    let mut files = vec![];
+    let mut page_token = "0".to_string();
+    loop {
+        let page = client
+            .local_file_page(LocalFilePageRequest {
+                page_size: DL_PAGE_SIZE,
+                page_token,
+                oldest_first: true,
+                query: query.to_string(),
+            })
+            .await?
+            .into_inner();
+        if page.file.is_empty() {
+            break;
+        }
+        files.extend(page.file);
+        page_token = page.next_page_token;
+    }
+    Ok(files)
+}
+
+/// Name to export a file under: the first name it was uploaded with that no
+/// other exported file has taken, or else its CID
+fn export_file_name(
+    file: &hooya::proto::File,
+    encoded_cid: &str,
+    taken: &mut HashSet<String>,
+) -> String {
+    let name = file
+        .names
+        .iter()
+        .filter_map(|n| Path::new(n).file_name())
+        .map(|n| n.to_string_lossy().to_string())
+        .find(|n| !n.starts_with('.') && !taken.contains(n))
+        .unwrap_or_else(|| dl_file_name(encoded_cid, file.mimetype.clone()));
+    taken.insert(name.clone());
+    name
+}
+
+/// Link every exported file from a directory per tag it has, removing links
+/// left from earlier exports for tags files no longer have
+fn write_tag_links(by_tag: &Path, files: &[ManifestFile]) -> Result<()> {
+    let mut wanted = HashSet::new();
+    for m in files {
+        let name = m.path.file_name().unwrap();
+        for tag in &m.tags {
+            let dir = by_tag.join(tag.replace('/', "_"));
+            std::fs::create_dir_all(&dir)?;
+            let link = dir.join(name);
+            if link.symlink_metadata().is_err() {
+                symlink(&Path::new("../..").join(&m.path), &link)?;
+            }
+            wanted.insert(link);
+        }
+    }
+
+    // Only links are removed, so files put there by hand survive
+    for dir in std::fs::read_dir(by_tag)? {
+        let dir = dir?.path();
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir)? {
+            let link = entry?.path();
+            let is_link = link.symlink_metadata()?.file_type().is_symlink();
+            if is_link && !wanted.contains(&link) {
+                std::fs::remove_file(&link)?;
+            }
+        }
+        // Fails, harmlessly, unless the directory is now empty
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+/// Name of a downloaded file, with an extension when its mimetype is known
+fn dl_file_name(encoded_cid: &str, mimetype: Option<String>) -> String {
+    match mimetype.as_deref().and_then(hooya::mimetype_extension) {
+        Some(ext) => format!("{}.{}", encoded_cid, ext),
+        None => encoded_cid.to_string(),
+    }
+}
+
+fn sidecar_args() -> [Arg; 2] {
+    [
+        Arg::new("no-sidecars")
+            .action(ArgAction::SetTrue)
+            .long("no-sidecars")
+            .help("Don't read tags from sidecar metadata files"),
+        Arg::new("sidecar-field")
+            .action(ArgAction::Append)
+            .long("sidecar-field")
+            .value_name("FIELD=NAMESPACE")
+            .help(
+                "Import a sidecar field into a namespace; * for fields \
+                holding tags, empty to skip the field",
+            ),
+    ]
+}
+
+/// Options choosing which files of a directory tree are looked at
+fn dir_filter_args() -> [Arg; 5] {
+    [
+        Arg::new("include")
+            .action(ArgAction::Append)
+            .long("include")
+            .value_name("GLOB")
+            .help("Only add files matching a glob"),
+        Arg::new("exclude")
+            .action(ArgAction::Append)
+            .long("exclude")
+            .value_name("GLOB")
+            .help("Leave out files and directories matching a glob"),
+        Arg::new("hidden")
+            .action(ArgAction::SetTrue)
+            .long("hidden")
+            .help("Add dotfiles and walk dot-directories"),
+        Arg::new("symlinks")
+            .long("symlinks")
+            .value_parser(["skip", "follow"])
+            .default_value("skip"),
+        Arg::new("max-depth")
+            .long("max-depth")
+            .value_parser(value_parser!(usize))
+            .help("Levels of subdirectories to walk"),
+    ]
+}
+
+fn dir_filter(matches: &clap::ArgMatches) -> Result<DirFilter> {
+    let strings = |id| {
+        matches
+            .get_many::<String>(id)
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    Ok(DirFilter {
+        include: hooya::client::glob_set(&strings("include"))?,
+        exclude: hooya::client::glob_set(&strings("exclude"))?,
+        hidden: matches.get_flag("hidden"),
+        symlinks: matches.get_one::<String>("symlinks").unwrap().parse()?,
+        max_depth: matches.get_one::<usize>("max-depth").copied(),
+    })
+}
+
+/// How sidecar fields map to namespaces, or None when sidecars are ignored
+fn sidecar_field_map(matches: &clap::ArgMatches) -> Result<Option<FieldMap>> {
+    if matches.get_flag("no-sidecars") {
+        return Ok(None);
+    }
+
+    let mut field_map = FieldMap::default();
+    for entry in matches
+        .get_many::<String>("sidecar-field")
+        .unwrap_or_default()
+    {
+        field_map.set(entry)?;
+    }
+
+    Ok(Some(field_map))
+}
+
+/// Options shared by the importers
+fn import_args() -> [Arg; 2] {
+    [
+        Arg::new("no-upload")
+            .action(ArgAction::SetTrue)
+            .long("no-upload")
+            .help("Only tag files hooya already has"),
+        Arg::new("report")
+            .long("report")
+            .value_parser(value_parser!(PathBuf))
+            .help("Write what became of every entry here as JSON"),
+    ]
+}
+
+fn field_arg() -> Arg {
+    Arg::new("field")
+        .action(ArgAction::Append)
+        .long("field")
+        .value_name("FIELD=NAMESPACE")
+        .help(
+            "Import a field into a namespace; * for fields holding tags, \
+            empty to skip the field",
+        )
+}
+
+fn import_field_map(matches: &clap::ArgMatches) -> Result<FieldMap> {
+    let mut field_map = FieldMap::default();
+    for entry in matches.get_many::<String>("field").unwrap_or_default() {
+        field_map.set(entry)?;
+    }
+    Ok(field_map)
+}
+
+fn finish_import(
+    matches: &clap::ArgMatches,
+    report: &ImportReport,
+    json: bool,
+) -> Result<()> {
+    if let Some(path) = matches.get_one::<PathBuf>("report") {
+        std::fs::write(path, serde_json::to_string_pretty(report)?)?;
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+    } else {
+        report.print_summary();
+    }
+    Ok(())
+}
+
+fn print_job(j: &Job) {
+    println!(
+        "{}\t{}\t{}\t{}\t{} attempts\t{}",
+        j.id,
+        j.kind,
+        hooya::cid::encode(&j.cid),
+        j.state,
+        j.attempts,
+        j.error
+    );
+}
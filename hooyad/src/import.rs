@@ -0,0 +1,589 @@
+use crate::output::Manifest;
+use anyhow::Result;
+use hooya::metadata::{FieldMap, Fields};
+use hooya::proto::{control_client::ControlClient, CidInfoRequest, Tag};
+use serde::Serialize;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+use sqlx::{ConnectOptions, Connection, Row};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tonic::transport::Channel;
+
+// Hydrus service types holding tags: a tag repository, and local tags
+const HYDRUS_TAG_SERVICES: [i64; 2] = [0, 5];
+const HYDRUS_NUMERICAL_RATING: i64 = 6;
+const HYDRUS_LIKE_RATING: i64 = 7;
+
+// Hydrus namespaces going by another name in hooya
+const HYDRUS_NAMESPACES: [(&str, &str); 2] =
+    [("creator", "artist"), ("series", "copyright")];
+
+/// How an export names the file each entry describes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    Sha256,
+    Sha1,
+    Md5,
+    Filename,
+    Cid,
+}
+
+/// A file described by an export, and the fields to tag it from
+pub struct Entry {
+    pub key: String,
+    pub fields: Fields,
+    // Tags taken as they are, without going through the field map
+    pub tags: Vec<Tag>,
+}
+
+/// Files an export's entries were matched to, and the entries that weren't
+#[derive(Default, Serialize)]
+pub struct ImportReport {
+    pub matched: Vec<Matched>,
+    pub unmatched: Vec<Unmatched>,
+}
+
+#[derive(Serialize)]
+pub struct Matched {
+    pub key: String,
+    pub cid: String,
+    pub tags: usize,
+}
+
+#[derive(Serialize)]
+pub struct Unmatched {
+    pub key: String,
+    pub reason: String,
+}
+
+/// Local files by the key exports name them with
+pub struct LocalFiles {
+    kind: KeyKind,
+    files: HashMap<String, PathBuf>,
+}
+
+impl FromStr for KeyKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(KeyKind::Sha256),
+            "sha1" => Ok(KeyKind::Sha1),
+            "md5" => Ok(KeyKind::Md5),
+            "filename" => Ok(KeyKind::Filename),
+            "cid" => Ok(KeyKind::Cid),
+            _ => Err(anyhow::anyhow!("Unknown key kind \"{}\"", s)),
+        }
+    }
+}
+
+impl LocalFiles {
+    pub fn empty(kind: KeyKind) -> Self {
+        LocalFiles {
+            kind,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Index every file below `dir`, hashing each one unless files are
+    /// matched by name
+    pub fn index(dir: &Path, kind: KeyKind) -> Result<Self> {
+        let mut local = LocalFiles::empty(kind);
+        local.index_dir(dir)?;
+        Ok(local)
+    }
+
+    fn index_dir(&mut self, dir: &Path) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.index_dir(&path)?;
+                continue;
+            }
+
+            let key = match self.kind {
+                KeyKind::Filename => match path.file_name() {
+                    Some(n) => n.to_string_lossy().to_string(),
+                    None => continue,
+                },
+                KeyKind::Cid => {
+                    let digest = decode_hex(&hex_digest(&path, self.kind)?)
+                        .unwrap_or_default();
+                    hooya::cid::encode(hooya::cid::from_sha256(&digest)?)
+                }
+                _ => hex_digest(&path, self.kind)?,
+            };
+            self.files.insert(key, path);
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Option<&PathBuf> {
+        match self.kind {
+            KeyKind::Filename | KeyKind::Cid => self.files.get(key),
+            _ => self.files.get(&key.to_lowercase()),
+        }
+    }
+}
+
+impl ImportReport {
+    pub fn print_summary(&self) {
+        for u in &self.unmatched {
+            eprintln!("unmatched {}: {}", u.key, u.reason);
+        }
+        eprintln!(
+            "{} matched, {} unmatched",
+            self.matched.len(),
+            self.unmatched.len()
+        );
+    }
+}
+
+/// Entries of a JSON array of objects, a JSON object with one such array,
+/// or JSON lines, keyed by `key_field`
+pub fn json_entries(path: &Path, key_field: &str) -> Result<Vec<Entry>> {
+    let contents = std::fs::read_to_string(path)?;
+    let objects = match serde_json::from_str::<serde_json::Value>(&contents) {
+        Ok(serde_json::Value::Array(a)) => a,
+        Ok(serde_json::Value::Object(o)) => {
+            match o.into_iter().find_map(|(_, v)| match v {
+                serde_json::Value::Array(a) => Some(a),
+                _ => None,
+            }) {
+                Some(a) => a,
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "No list of entries in {}",
+                        path.display()
+                    ))
+                }
+            }
+        }
+        Ok(_) => {
+            return Err(anyhow::anyhow!(
+                "No list of entries in {}",
+                path.display()
+            ))
+        }
+        Err(_) => contents
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?,
+    };
+
+    Ok(objects
+        .iter()
+        .map(|o| keyed_entry(hooya::metadata::json_fields(o), key_field))
+        .collect())
+}
+
+/// Entries of a CSV file with a header row naming the fields
+pub fn csv_entries(path: &Path, key_field: &str) -> Result<Vec<Entry>> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = reader.headers()?.clone();
+
+    let mut entries = vec![];
+    for record in reader.records() {
+        let fields = headers
+            .iter()
+            .zip(record?.iter())
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect();
+        entries.push(keyed_entry(fields, key_field));
+    }
+    Ok(entries)
+}
+
+fn keyed_entry(fields: Fields, key_field: &str) -> Entry {
+    let key = fields
+        .iter()
+        .find(|(f, _)| f == key_field)
+        .map(|(_, v)| v.clone())
+        .unwrap_or_default();
+    Entry {
+        key,
+        fields,
+        tags: vec![],
+    }
+}
+
+/// Entries for the files in a manifest written by hooya export, with the
+/// exported copies as local files
+pub fn manifest_entries(path: &Path) -> Result<(Vec<Entry>, LocalFiles)> {
+    let manifest: Manifest =
+        serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let base = path.parent().unwrap_or(Path::new("."));
+
+    let mut entries = vec![];
+    let mut local = LocalFiles::empty(KeyKind::Cid);
+    for file in manifest.files {
+        local.files.insert(file.cid.clone(), base.join(&file.path));
+        entries.push(Entry {
+            key: file.cid,
+            fields: vec![],
+            tags: file.tags.iter().map(|t| Tag::from(t.as_str())).collect(),
+        });
+    }
+
+    Ok((entries, local))
+}
+
+/// Entries for the files in a Hydrus client's file store, with their tags,
+/// ratings, URLs and notes as fields. Hydrus must not be running
+pub async fn hydrus_entries(
+    db_dir: &Path,
+    files_dir: &Path,
+) -> Result<(Vec<Entry>, LocalFiles)> {
+    let mut conn = SqliteConnectOptions::new()
+        .filename(db_dir.join("client.db"))
+        .read_only(true)
+        .connect()
+        .await?;
+    for (db, schema) in [
+        ("client.master.db", "master"),
+        ("client.mappings.db", "mappings"),
+    ] {
+        sqlx::query(&format!("ATTACH DATABASE ? AS {}", schema))
+            .bind(db_dir.join(db).to_string_lossy().to_string())
+            .execute(&mut conn)
+            .await?;
+    }
+
+    let services: Vec<(i64, String, i64)> =
+        sqlx::query("SELECT service_id, name, service_type FROM services")
+            .try_map(|r: sqlx::sqlite::SqliteRow| {
+                Ok((r.try_get(0)?, r.try_get(1)?, r.try_get(2)?))
+            })
+            .fetch_all(&mut conn)
+            .await?;
+
+    // Hydrus stores files as f<first two hex digits>/<sha256>.<ext>, and
+    // their thumbnails under t<first two hex digits>
+    let mut local = LocalFiles::empty(KeyKind::Sha256);
+    for bucket in std::fs::read_dir(files_dir)? {
+        let bucket = bucket?.path();
+        let is_file_bucket = bucket
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix('f'))
+            .map_or(false, |n| n.len() == 2 && decode_hex(n).is_some());
+        if !is_file_bucket || !bucket.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(&bucket)? {
+            let path = file?.path();
+            let hash = match path.file_stem() {
+                Some(s) => s.to_string_lossy().to_lowercase(),
+                None => continue,
+            };
+            if hash.len() == 64 && decode_hex(&hash).is_some() {
+                local.files.insert(hash, path);
+            }
+        }
+    }
+
+    let mut entries = vec![];
+    for hash in local.files.keys() {
+        let fields =
+            hydrus_fields(&mut conn, &services, &decode_hex(hash).unwrap())
+                .await?;
+        entries.push(Entry {
+            key: hash.clone(),
+            fields,
+            tags: vec![],
+        });
+    }
+
+    conn.close().await?;
+    Ok((entries, local))
+}
+
+async fn hydrus_fields(
+    conn: &mut SqliteConnection,
+    services: &[(i64, String, i64)],
+    hash: &[u8],
+) -> Result<Fields> {
+    let mut fields = vec![];
+    let hash_id: i64 =
+        match sqlx::query("SELECT hash_id FROM master.hashes WHERE hash = ?")
+            .bind(hash)
+            .try_map(|r: sqlx::sqlite::SqliteRow| r.try_get(0))
+            .fetch_optional(&mut *conn)
+            .await?
+        {
+            Some(id) => id,
+            // In the file store but unknown to the database
+            None => return Ok(fields),
+        };
+
+    // Tables differ between Hydrus versions, so what a version lacks is
+    // left out rather than failing the import (see `or_missing`)
+    for (service_id, name, service_type) in services {
+        if HYDRUS_TAG_SERVICES.contains(service_type) {
+            let tags: Vec<(String, String)> = or_missing(
+                sqlx::query(&format!(
+                    "SELECT namespace, subtag
+                    FROM mappings.current_mappings_{} m
+                    JOIN master.tags t ON t.tag_id = m.tag_id
+                    JOIN master.namespaces n ON n.namespace_id = t.namespace_id
+                    JOIN master.subtags s ON s.subtag_id = t.subtag_id
+                    WHERE m.hash_id = ?",
+                    service_id
+                ))
+                .bind(hash_id)
+                .try_map(|r: sqlx::sqlite::SqliteRow| {
+                    Ok((r.try_get(0)?, r.try_get(1)?))
+                })
+                .fetch_all(&mut *conn)
+                .await,
+            )?;
+
+            for (namespace, subtag) in tags {
+                let namespace = HYDRUS_NAMESPACES
+                    .iter()
+                    .find(|(from, _)| *from == namespace)
+                    .map(|(_, to)| to.to_string())
+                    .unwrap_or(namespace);
+                let tag = if namespace.is_empty() {
+                    subtag
+                } else {
+                    format!("{}:{}", namespace, subtag)
+                };
+                fields.push(("tags".to_string(), tag));
+            }
+        }
+
+        if *service_type == HYDRUS_NUMERICAL_RATING
+            || *service_type == HYDRUS_LIKE_RATING
+        {
+            let rating: Option<f64> = or_missing(
+                sqlx::query(
+                    "SELECT rating FROM local_ratings
+                    WHERE service_id = ? AND hash_id = ?",
+                )
+                .bind(service_id)
+                .bind(hash_id)
+                .try_map(|r: sqlx::sqlite::SqliteRow| r.try_get(0))
+                .fetch_optional(&mut *conn)
+                .await,
+            )?;
+
+            // Ratings are stored between 0 and 1; numerical ones become a
+            // percentage
+            let value = match rating {
+                Some(r) if *service_type == HYDRUS_LIKE_RATING => {
+                    if r >= 0.5 {
+                        "like".to_string()
+                    } else {
+                        "dislike".to_string()
+                    }
+                }
+                Some(r) => ((r * 100.0).round() as i64).to_string(),
+                None => continue,
+            };
+            fields.push(("rating".to_string(), format!("{}_{}", name, value)));
+        }
+    }
+
+    let urls: Vec<String> = or_missing(
+        sqlx::query(
+            "SELECT url FROM url_map JOIN master.urls USING (url_id)
+            WHERE hash_id = ?",
+        )
+        .bind(hash_id)
+        .try_map(|r: sqlx::sqlite::SqliteRow| r.try_get(0))
+        .fetch_all(&mut *conn)
+        .await,
+    )?;
+    fields.extend(urls.into_iter().map(|u| ("source".to_string(), u)));
+
+    let notes: Vec<(String, String)> = or_missing(
+        sqlx::query(
+            "SELECT label, note FROM file_notes f
+            JOIN master.labels l ON l.label_id = f.name_id
+            JOIN master.notes n ON n.note_id = f.note_id
+            WHERE f.hash_id = ?",
+        )
+        .bind(hash_id)
+        .try_map(|r: sqlx::sqlite::SqliteRow| {
+            Ok((r.try_get(0)?, r.try_get(1)?))
+        })
+        .fetch_all(&mut *conn)
+        .await,
+    )?;
+    fields.extend(notes.into_iter().map(|(label, note)| {
+        ("description".to_string(), format!("{}: {}", label, note))
+    }));
+
+    Ok(fields)
+}
+
+/// The result of a query, or nothing when it uses a table or column this
+/// Hydrus version lacks. Other errors, such as a locked database, are
+/// returned
+fn or_missing<T: Default>(result: sqlx::Result<T>) -> Result<T> {
+    match result {
+        Err(sqlx::Error::Database(e))
+            if e.message().starts_with("no such table")
+                || e.message().starts_with("no such column") =>
+        {
+            Ok(T::default())
+        }
+        r => Ok(r?),
+    }
+}
+
+/// Find the file each entry describes, uploading local copies hooya doesn't
+/// have yet when `upload` is set, and tag it from the entry's fields
+pub async fn import_entries(
+    client: ControlClient<Channel>,
+    entries: Vec<Entry>,
+    local: &LocalFiles,
+    field_map: &FieldMap,
+    upload: bool,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    for entry in entries {
+        if entry.key.is_empty() {
+            report.unmatched.push(Unmatched {
+                key: String::new(),
+                reason: "Entry has no key".to_string(),
+            });
+            continue;
+        }
+
+        let cid = match resolve(client.clone(), &entry.key, local, upload).await
+        {
+            Ok(cid) => cid,
+            Err(reason) => {
+                report.unmatched.push(Unmatched {
+                    key: entry.key,
+                    reason,
+                });
+                continue;
+            }
+        };
+
+        let mut tags = field_map.tags(&entry.fields);
+        for tag in entry.tags {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        let tag_count = tags.len();
+        if !tags.is_empty() {
+            client
+                .clone()
+                .tag_cid(hooya::proto::TagCidRequest {
+                    cid: cid.clone(),
+                    tags,
+                })
+                .await?;
+        }
+        report.matched.push(Matched {
+            key: entry.key,
+            cid: hooya::cid::encode(cid),
+            tags: tag_count,
+        });
+    }
+
+    Ok(report)
+}
+
+/// CID of the file an entry's key names, or why there isn't one
+async fn resolve(
+    client: ControlClient<Channel>,
+    key: &str,
+    local: &LocalFiles,
+    upload: bool,
+) -> std::result::Result<Vec<u8>, String> {
+    // A SHA-256 is all a CID needs, so those files may be in hooya already
+    let known_cid = match local.kind {
+        KeyKind::Sha256 => {
+            decode_hex(key).and_then(|d| hooya::cid::from_sha256(&d).ok())
+        }
+        KeyKind::Cid => hooya::cid::decode(key).ok().map(|(_, cid)| cid),
+        _ => None,
+    };
+    if let Some(cid) = &known_cid {
+        if is_indexed(client.clone(), cid).await {
+            return Ok(cid.clone());
+        }
+    }
+
+    let path = match local.get(key) {
+        Some(p) => p,
+        None if known_cid.is_some() => {
+            return Err("Not in hooya and no local copy".to_string())
+        }
+        None => return Err("No local file matches".to_string()),
+    };
+
+    if upload {
+        return hooya::client::upload_file(client, path, vec![], None)
+            .await
+            .map(|u| u.cid)
+            .map_err(|e| e.to_string());
+    }
+
+    let cid = hex_digest(path, KeyKind::Sha256)
+        .ok()
+        .and_then(|h| decode_hex(&h))
+        .and_then(|d| hooya::cid::from_sha256(&d).ok())
+        .ok_or_else(|| format!("Could not hash {}", path.display()))?;
+    if is_indexed(client, &cid).await {
+        Ok(cid)
+    } else {
+        Err(format!("{} is not in hooya", path.display()))
+    }
+}
+
+async fn is_indexed(mut client: ControlClient<Channel>, cid: &[u8]) -> bool {
+    client
+        .cid_info(CidInfoRequest { cid: cid.to_vec() })
+        .await
+        .is_ok()
+}
+
+fn hex_digest(path: &Path, kind: KeyKind) -> Result<String> {
+    use md5::Digest;
+
+    let mut fh = File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    let mut sha256 = hooya::cid::new_digest_context();
+    let mut sha1 = sha1::Sha1::new();
+    let mut md5 = md5::Md5::new();
+    loop {
+        let n = fh.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match kind {
+            KeyKind::Sha1 => sha1.update(&buf[..n]),
+            KeyKind::Md5 => md5.update(&buf[..n]),
+            _ => sha256.update(&buf[..n]),
+        }
+    }
+
+    let digest = match kind {
+        KeyKind::Sha1 => sha1.finalize().to_vec(),
+        KeyKind::Md5 => md5.finalize().to_vec(),
+        _ => sha256.finish().as_ref().to_vec(),
+    };
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
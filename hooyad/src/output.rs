@@ -0,0 +1,279 @@
+use hooya::proto::{self, file::ExtFile};
+use hooya::{duration_label, human_readable_size};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A file as listed by ls and random
+#[derive(Serialize)]
+pub struct FileSummary {
+    pub cid: String,
+    pub mimetype: Option<String>,
+    pub size: i64,
+}
+
+/// A page of listed files and where the next one starts
+#[derive(Serialize)]
+pub struct FilePage {
+    pub files: Vec<FileSummary>,
+    pub next_page_token: String,
+    // Only random listings have one; passing it back repeats the shuffle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// Type-specific details of a file
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Media {
+    Image {
+        width: i64,
+        height: i64,
+    },
+    Video {
+        width: i64,
+        height: i64,
+        // Seconds
+        duration: f32,
+    },
+    Audio {
+        title: Option<String>,
+        artist: Option<String>,
+        album: Option<String>,
+        // Seconds
+        duration: f32,
+    },
+    Document {
+        pages: u32,
+    },
+}
+
+#[derive(Serialize)]
+pub struct ThumbnailSummary {
+    pub cid: String,
+    pub mimetype: String,
+    pub width: i64,
+    pub height: i64,
+    pub animated: bool,
+}
+
+/// Everything info shows about a file
+#[derive(Serialize)]
+pub struct FileDetails {
+    #[serde(flatten)]
+    pub file: FileSummary,
+    pub media: Option<Media>,
+    pub analysis: Option<String>,
+    pub tags: Vec<String>,
+    pub thumbnails: Vec<ThumbnailSummary>,
+}
+
+/// What hooya export wrote, enough to restore tags with import-manifest
+#[derive(Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub cid: String,
+    // Relative to the manifest
+    pub path: PathBuf,
+    // Names the file was uploaded under
+    pub names: Vec<String>,
+    pub mimetype: Option<String>,
+    pub size: i64,
+    pub media: Option<Media>,
+    pub tags: Vec<String>,
+}
+
+impl From<&proto::File> for FileSummary {
+    fn from(f: &proto::File) -> Self {
+        FileSummary {
+            cid: hooya::cid::encode(&f.cid),
+            mimetype: f.mimetype.clone(),
+            size: f.size,
+        }
+    }
+}
+
+impl From<&ExtFile> for Media {
+    fn from(e: &ExtFile) -> Self {
+        match e {
+            ExtFile::Image(i) => Media::Image {
+                width: i.width,
+                height: i.height,
+            },
+            ExtFile::Video(v) => Media::Video {
+                width: v.width,
+                height: v.height,
+                duration: v.duration,
+            },
+            ExtFile::Audio(a) => Media::Audio {
+                title: a.title.clone(),
+                artist: a.artist.clone(),
+                album: a.album.clone(),
+                duration: a.duration,
+            },
+            ExtFile::Document(d) => Media::Document {
+                pages: d.page_count,
+            },
+        }
+    }
+}
+
+impl From<&proto::Thumbnail> for ThumbnailSummary {
+    fn from(t: &proto::Thumbnail) -> Self {
+        ThumbnailSummary {
+            cid: hooya::cid::encode(&t.cid),
+            mimetype: t.mimetype.clone(),
+            width: t.width,
+            height: t.height,
+            animated: t.is_animated,
+        }
+    }
+}
+
+impl FilePage {
+    pub fn print(&self, json: bool) -> serde_json::Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            return Ok(());
+        }
+
+        print_table(
+            self.files
+                .iter()
+                .map(|f| {
+                    vec![
+                        f.cid.clone(),
+                        f.mimetype.clone().unwrap_or_else(|| "-".to_string()),
+                        human_readable_size(f.size),
+                    ]
+                })
+                .collect(),
+        );
+        Ok(())
+    }
+}
+
+impl FileDetails {
+    pub fn print(&self, json: bool) -> serde_json::Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            return Ok(());
+        }
+
+        let mut rows = vec![
+            vec!["CID".to_string(), self.file.cid.clone()],
+            vec![
+                "Mimetype".to_string(),
+                self.file
+                    .mimetype
+                    .clone()
+                    .unwrap_or_else(|| "-".to_string()),
+            ],
+            vec!["Size".to_string(), human_readable_size(self.file.size)],
+        ];
+
+        match &self.media {
+            Some(Media::Image { width, height }) => {
+                rows.push(vec![
+                    "Dimensions".to_string(),
+                    format!("{}x{}", width, height),
+                ]);
+            }
+            Some(Media::Video {
+                width,
+                height,
+                duration,
+            }) => {
+                rows.push(vec![
+                    "Dimensions".to_string(),
+                    format!("{}x{}", width, height),
+                ]);
+                rows.push(vec![
+                    "Duration".to_string(),
+                    duration_label(*duration),
+                ]);
+            }
+            Some(Media::Audio {
+                title,
+                artist,
+                album,
+                duration,
+            }) => {
+                for (label, value) in
+                    [("Title", title), ("Artist", artist), ("Album", album)]
+                {
+                    if let Some(value) = value {
+                        rows.push(vec![label.to_string(), value.clone()]);
+                    }
+                }
+                rows.push(vec![
+                    "Duration".to_string(),
+                    duration_label(*duration),
+                ]);
+            }
+            Some(Media::Document { pages }) => {
+                rows.push(vec!["Pages".to_string(), pages.to_string()]);
+            }
+            None => {}
+        }
+
+        if let Some(analysis) = &self.analysis {
+            rows.push(vec!["Analysis".to_string(), analysis.clone()]);
+        }
+        rows.push(vec!["Tags".to_string(), self.tags.join(" ")]);
+        print_table(rows);
+
+        if !self.thumbnails.is_empty() {
+            println!("Thumbnails");
+            print_table(
+                self.thumbnails
+                    .iter()
+                    .map(|t| {
+                        vec![
+                            String::new(),
+                            format!("{}x{}", t.width, t.height),
+                            t.mimetype.clone(),
+                            if t.animated { "animated" } else { "still" }
+                                .to_string(),
+                            t.cid.clone(),
+                        ]
+                    })
+                    .collect(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Print rows with every column but the last padded to line up
+fn print_table(rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = vec![];
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            match widths.get_mut(i) {
+                Some(w) => *w = (*w).max(cell.chars().count()),
+                None => widths.push(cell.chars().count()),
+            }
+        }
+    }
+
+    for row in rows {
+        let last = row.len().saturating_sub(1);
+        let line: Vec<String> = row
+            .into_iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                if i == last {
+                    cell
+                } else {
+                    format!("{:width$}", cell, width = widths[i])
+                }
+            })
+            .collect();
+        println!("{}", line.join("  "));
+    }
+}
@@ -1,31 +1,53 @@
-use clap::{command, value_parser, Arg};
+use clap::{command, value_parser, Arg, ArgAction};
 use dotenv::dotenv;
 use futures_util::Stream;
+use hooya::image::{ThumbnailFormat, Transparency};
+use hooya::jobs::{JobKind, JobState};
+use hooya::metadata::FieldMap;
 use hooya::proto::{
     control_server::{Control, ControlServer},
-    CidInfoReply, CidInfoRequest, CidThumbnailRequest, ContentAtCidRequest,
-    FileChunk, ForgetFileReply, ForgetFileRequest, LocalFilePageReply,
+    CidInfoReply, CidInfoRequest, CidThumbnailRequest, ContentAtCidPageRequest,
+    ContentAtCidRequest, CreateSavedSearchReply, CreateSavedSearchRequest,
+    DeleteSavedSearchReply, DeleteSavedSearchRequest, ExecuteSavedSearchReply,
+    ExecuteSavedSearchRequest, FileChunk, FindDuplicatesReply,
+    FindDuplicatesRequest, ForgetFileReply, ForgetFileRequest,
+    IndexedCidsReply, IndexedCidsRequest, Job, ListJobsReply, ListJobsRequest,
+    ListSavedSearchesReply, ListSavedSearchesRequest, LocalFilePageReply,
     LocalFilePageRequest, RandomLocalFileReply, RandomLocalFileRequest,
-    ReimportReply, ReimportRequest, StreamToFilestoreReply, TagCidReply,
-    TagCidRequest, TagsReply, TagsRequest, VersionReply, VersionRequest,
+    RegenerateThumbnailsReply, RegenerateThumbnailsRequest, ReimportAllReply,
+    ReimportAllRequest, ReimportReply, ReimportRequest, RetryJobReply,
+    RetryJobRequest, ReverseSearchReply, ReverseSearchRequest,
+    SimilarFilesReply, SimilarFilesRequest, StreamToFilestoreReply, Tag,
+    TagCidReply, TagCidRequest, TagsReply, TagsRequest, VersionReply,
+    VersionRequest, WatchJobRequest,
 };
-use hooya::runtime::Runtime;
+use hooya::query::Query;
+use hooya::runtime::{Runtime, ThumbnailConfig};
+use hooya::watch::AfterImport;
 use rand::distributions::DistString;
 use sqlx::migrate::MigrateDatabase;
 use sqlx::{Sqlite, SqlitePool};
 use std::{
+    collections::{HashMap, HashSet},
     fs::{create_dir_all, File},
-    io::Write,
+    io::{Seek, SeekFrom, Write},
     path::{Path, PathBuf},
     pin::Pin,
+    str::FromStr,
+    sync::Arc,
 };
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
 use tonic::{transport::Server, Request, Response, Status};
 
 mod config;
 
+// Largest probe image accepted by ReverseSearch
+const MAX_REVERSE_SEARCH_BYTES: usize = 64 * 1024 * 1024;
+
 struct IControl {
-    pub runtime: Runtime,
+    pub runtime: Arc<Runtime>,
 }
 
 #[tonic::async_trait]
@@ -55,6 +77,11 @@ impl Control for IControl {
         r: Request<tonic::Streaming<FileChunk>>,
     ) -> Result<Response<StreamToFilestoreReply>, Status> {
         let runtime = &self.runtime;
+        let file_name = r
+            .metadata()
+            .get_bin(hooya::client::FILE_NAME_KEY)
+            .and_then(|v| v.to_bytes().ok())
+            .map(|b| String::from_utf8_lossy(&b).to_string());
         let mut chunk_stream = r.into_inner();
         let mut sha_context = hooya::cid::new_digest_context();
 
@@ -80,6 +107,25 @@ impl Control for IControl {
         let cid = hooya::cid::wrap_digest(sha_context.finish())
             .map_err(|e| Status::internal(e.to_string()))?;
         let cid_store_path = runtime.derive_store_path(&cid).unwrap();
+        if let Some(name) = file_name {
+            runtime
+                .db
+                .add_file_name(cid.clone(), &name)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+
+        // Already stored and indexed, so there is nothing to redo
+        if cid_store_path.is_file()
+            && runtime.db.file_row(cid.clone()).await.is_ok()
+        {
+            std::fs::remove_file(tmp_path)?;
+            return Ok(Response::new(StreamToFilestoreReply {
+                cid,
+                job_id: None,
+                duplicate: true,
+            }));
+        }
 
         // I know this always has a parent so .unwrap() okie
         let parent = cid_store_path.parent().unwrap();
@@ -89,12 +135,19 @@ impl Control for IControl {
         }
         std::fs::rename(tmp_path, cid_store_path)?;
 
-        self.runtime
-            .import_from_filestore(cid.clone())
+        // Decoding and thumbnailing is left to the worker pool so the upload
+        // returns as soon as the blob is stored
+        let job = self
+            .runtime
+            .index_upload(cid.clone())
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let reply = StreamToFilestoreReply { cid };
+        let reply = StreamToFilestoreReply {
+            cid,
+            job_id: job.map(|j| j.id),
+            duplicate: false,
+        };
         Ok(Response::new(reply))
     }
 
@@ -112,6 +165,289 @@ impl Control for IControl {
         Ok(Response::new(reply))
     }
 
+    type ReimportAllStream = Pin<
+        Box<
+            dyn Stream<Item = Result<ReimportAllReply, Status>>
+                + Send
+                + 'static,
+        >,
+    >;
+    async fn reimport_all(
+        &self,
+        r: Request<ReimportAllRequest>,
+    ) -> Result<Response<Self::ReimportAllStream>, Status> {
+        let r = r.into_inner();
+        let runtime = self.runtime.clone();
+
+        let run_id = match r.resume {
+            Some(id) => id,
+            None => {
+                Query::from_str(&r.query)
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?;
+                runtime
+                    .db
+                    .new_reimport_run(&r.query)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?
+            }
+        };
+        let run = runtime
+            .db
+            .reimport_run(run_id)
+            .await
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let query = Query::from_str(&run.query)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        // Files that failed are not marked done, so resuming a run also
+        // retries them
+        let (remaining, done) = runtime
+            .db
+            .reimport_remaining(run_id, &query)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let total = done + remaining.len() as u64;
+
+        // Subscribe before queueing so no job can finish unseen
+        let updates = runtime.watch_jobs();
+
+        // A resumed run finds its earlier jobs still queued, or requeued by
+        // a restart. Those are followed instead of reimporting files twice
+        let queued: HashMap<Vec<u8>, i64> = runtime
+            .db
+            .unfinished_jobs(&JobKind::Reimport.to_string())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .into_iter()
+            .map(|(id, cid)| (cid, id))
+            .collect();
+        let mut ids = HashMap::new();
+        for cid in remaining {
+            let id = match queued.get(&cid) {
+                Some(id) => *id,
+                None => {
+                    runtime
+                        .enqueue_job(JobKind::Reimport, cid.clone())
+                        .await
+                        .map_err(|e| Status::internal(e.to_string()))?
+                        .id
+                }
+            };
+            ids.insert(id, cid);
+        }
+        let mut pending = PendingJobs {
+            runtime: runtime.clone(),
+            updates,
+            ids: ids.keys().copied().collect(),
+            caught_up: vec![],
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut processed = done;
+            while let Some(job) = pending.next_finished().await {
+                let cid = ids.remove(&job.id).unwrap_or_default();
+                let mut error = job.error;
+                if error.is_empty() {
+                    if let Err(e) =
+                        runtime.db.mark_reimported(run_id, cid.clone()).await
+                    {
+                        error = e.to_string();
+                    }
+                }
+
+                processed += 1;
+                let reply = ReimportAllReply {
+                    run_id,
+                    cid,
+                    error,
+                    processed,
+                    total,
+                };
+
+                // Stop following if the client went away; queued files are
+                // still reimported, and the run can be resumed to record them
+                if tx.send(Ok(reply)).await.is_err() {
+                    return;
+                }
+            }
+
+            if let Err(e) = runtime.db.finish_reimport_run(run_id).await {
+                let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    type RegenerateThumbnailsStream = Pin<
+        Box<
+            dyn Stream<Item = Result<RegenerateThumbnailsReply, Status>>
+                + Send
+                + 'static,
+        >,
+    >;
+    async fn regenerate_thumbnails(
+        &self,
+        _: Request<RegenerateThumbnailsRequest>,
+    ) -> Result<Response<Self::RegenerateThumbnailsStream>, Status> {
+        let runtime = self.runtime.clone();
+        let cids = runtime
+            .db
+            .file_cids()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            // Files already up to date are answered straight away; the rest
+            // are left to the worker pool
+            let updates = runtime.watch_jobs();
+            let mut ids = HashSet::new();
+            for cid in cids {
+                let outdated = runtime.thumbnails_outdated(cid.clone()).await;
+                let reply = match outdated {
+                    Ok(true) => {
+                        match runtime
+                            .enqueue_job(
+                                JobKind::RegenerateThumbnails,
+                                cid.clone(),
+                            )
+                            .await
+                        {
+                            Ok(job) => {
+                                ids.insert(job.id);
+                                continue;
+                            }
+                            Err(e) => RegenerateThumbnailsReply {
+                                cid,
+                                regenerated: false,
+                                error: e.to_string(),
+                            },
+                        }
+                    }
+                    Ok(false) => RegenerateThumbnailsReply {
+                        cid,
+                        regenerated: false,
+                        error: String::new(),
+                    },
+                    Err(e) => RegenerateThumbnailsReply {
+                        cid,
+                        regenerated: false,
+                        error: e.to_string(),
+                    },
+                };
+
+                // Stop early if the client went away
+                if tx.send(Ok(reply)).await.is_err() {
+                    return;
+                }
+            }
+
+            let mut pending = PendingJobs {
+                runtime,
+                updates,
+                ids,
+                caught_up: vec![],
+            };
+            while let Some(job) = pending.next_finished().await {
+                let reply = RegenerateThumbnailsReply {
+                    cid: job.cid,
+                    regenerated: job.error.is_empty(),
+                    error: job.error,
+                };
+                if tx.send(Ok(reply)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn list_jobs(
+        &self,
+        r: Request<ListJobsRequest>,
+    ) -> Result<Response<ListJobsReply>, Status> {
+        let r = r.into_inner();
+        let state = r
+            .state
+            .map(|s| JobState::from_str(&s))
+            .transpose()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let job = self
+            .runtime
+            .jobs(state, r.limit)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let reply = ListJobsReply { job };
+        Ok(Response::new(reply))
+    }
+
+    type WatchJobStream =
+        Pin<Box<dyn Stream<Item = Result<Job, Status>> + Send + 'static>>;
+    async fn watch_job(
+        &self,
+        r: Request<WatchJobRequest>,
+    ) -> Result<Response<Self::WatchJobStream>, Status> {
+        let id = r.into_inner().id;
+
+        // Subscribe before reading the current state so no update in between
+        // is missed
+        let mut updates = self.runtime.watch_jobs();
+        let job = self
+            .runtime
+            .job(id)
+            .await
+            .map_err(|e| Status::not_found(e.to_string()))?;
+
+        let runtime = self.runtime.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut job = job;
+            loop {
+                let finished = is_finished(&job);
+                if tx.send(Ok(job)).await.is_err() || finished {
+                    break;
+                }
+
+                job = loop {
+                    match updates.recv().await {
+                        Ok(j) if j.id == id => break j,
+                        Ok(_) => {}
+                        // Fell too far behind to know what was missed;
+                        // catch up from the database instead
+                        Err(RecvError::Lagged(_)) => {
+                            match runtime.job(id).await {
+                                Ok(j) => break j,
+                                Err(_) => return,
+                            }
+                        }
+                        Err(RecvError::Closed) => return,
+                    }
+                };
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn retry_job(
+        &self,
+        r: Request<RetryJobRequest>,
+    ) -> Result<Response<RetryJobReply>, Status> {
+        let job = self
+            .runtime
+            .retry_job(r.into_inner().id)
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        let reply = RetryJobReply { job: Some(job) };
+        Ok(Response::new(reply))
+    }
+
     async fn tag_cid(
         &self,
         r: Request<TagCidRequest>,
@@ -139,16 +475,21 @@ impl Control for IControl {
         &self,
         r: Request<ContentAtCidRequest>,
     ) -> Result<Response<Self::ContentAtCidStream>, Status> {
-        let cid = r.into_inner().cid;
+        let req = r.into_inner();
 
         // NOTE this is safe because we are in charge of encoding the binary
         // data and the set of characters in base32 cannot be used for
         // malicious dir traversal
         let local_file = self
             .runtime
-            .derive_store_path(&cid)
+            .derive_store_path(&req.cid)
             .map_err(|e| Status::internal(e.to_string()))?;
-        let fh = File::open(local_file)?;
+        let mut fh = File::open(local_file)?;
+
+        // Lets clients resume a download they already have the start of
+        if let Some(offset) = req.offset {
+            fh.seek(SeekFrom::Start(offset))?;
+        }
 
         let chunks = hooya::ChunkedReader::new(fh);
         let stream = tokio_stream::iter(chunks).map(move |c| {
@@ -159,6 +500,47 @@ impl Control for IControl {
         Ok(Response::new(Box::pin(stream)))
     }
 
+    type ContentAtCidPageStream =
+        Pin<Box<dyn Stream<Item = Result<FileChunk, Status>> + Send + 'static>>;
+    async fn content_at_cid_page(
+        &self,
+        r: Request<ContentAtCidPageRequest>,
+    ) -> Result<Response<Self::ContentAtCidPageStream>, Status> {
+        let req = r.into_inner();
+
+        let (archive, page) = self
+            .runtime
+            .archive_page(req.cid, req.page)
+            .await
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let (name, mimetype) = (page.name, page.mimetype);
+
+        // Decompressing the entry is blocking work, so it gets its own thread
+        // and hands chunks over as they are read
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let result = hooya::archive::stream_page(&archive, &name, |c| {
+                let chunk = c
+                    .map(|data| FileChunk { data })
+                    .map_err(|e| Status::internal(e.to_string()));
+                tx.blocking_send(chunk).is_ok()
+            });
+
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(Status::internal(e.to_string())));
+            }
+        });
+
+        let mut response =
+            Response::new(Box::pin(ReceiverStream::new(rx))
+                as Self::ContentAtCidPageStream);
+        if let Ok(m) = mimetype.parse() {
+            response.metadata_mut().insert("mimetype", m);
+        }
+
+        Ok(response)
+    }
+
     type CidThumbnailStream =
         Pin<Box<dyn Stream<Item = Result<FileChunk, Status>> + Send + 'static>>;
     async fn cid_thumbnail(
@@ -170,9 +552,10 @@ impl Control for IControl {
         // NOTE this is safe because we are in charge of encoding the binary
         // data and the set of characters in base32 cannot be used for
         // malicious dir traversal
-        let local_file = self
+        let (local_file, mimetype) = self
             .runtime
-            .derive_thumb_path(&req.source_cid, req.long_edge)
+            .cid_thumbnail(&req.source_cid, req.long_edge, req.animated)
+            .await
             .map_err(|e| Status::internal(e.to_string()))?;
         let fh = File::open(local_file)?;
 
@@ -182,7 +565,14 @@ impl Control for IControl {
             Ok(FileChunk { data })
         });
 
-        Ok(Response::new(Box::pin(stream)))
+        // Sizes made on demand have no Thumbnails row to read this from
+        let mut response =
+            Response::new(Box::pin(stream) as Self::CidThumbnailStream);
+        if let Ok(m) = mimetype.parse() {
+            response.metadata_mut().insert("mimetype", m);
+        }
+
+        Ok(response)
     }
     async fn local_file_page(
         &self,
@@ -192,7 +582,12 @@ impl Control for IControl {
 
         let (file, next_page_token) = self
             .runtime
-            .local_file_page(req.page_size, req.page_token, req.oldest_first)
+            .local_file_page(
+                req.page_size,
+                req.page_token,
+                req.oldest_first,
+                &req.query,
+            )
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
@@ -210,13 +605,17 @@ impl Control for IControl {
     ) -> Result<Response<RandomLocalFileReply>, Status> {
         let req = r.into_inner();
 
-        let file = self
+        let (file, seed, next_page_token) = self
             .runtime
-            .random_local_file(req.count)
+            .random_local_file(req.count, &req.query, req.seed, req.page_token)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let resp = RandomLocalFileReply { file };
+        let resp = RandomLocalFileReply {
+            file,
+            seed,
+            next_page_token,
+        };
 
         Ok(Response::new(resp))
     }
@@ -257,18 +656,258 @@ impl Control for IControl {
         r: Request<CidInfoRequest>,
     ) -> Result<Response<CidInfoReply>, Status> {
         let req = r.into_inner();
+        let exif = self
+            .runtime
+            .exif(req.cid.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let analysis = self
+            .runtime
+            .analysis(req.cid.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
         let file =
             Some(self.runtime.indexed_file(req.cid).await.map_err(|_| {
                 Status::internal("CID is not indexed so it cannot be tagged")
             })?);
 
-        Ok(Response::new(CidInfoReply { file }))
+        Ok(Response::new(CidInfoReply {
+            file,
+            exif,
+            analysis,
+        }))
+    }
+
+    async fn indexed_cids(
+        &self,
+        r: Request<IndexedCidsRequest>,
+    ) -> Result<Response<IndexedCidsReply>, Status> {
+        let req = r.into_inner();
+
+        let cid = self
+            .runtime
+            .db
+            .indexed_cids(&req.cid)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut tags = vec![];
+        if req.with_tags {
+            for c in &cid {
+                let file_tags = self
+                    .runtime
+                    .tags(c.clone())
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                tags.push(TagsReply { tags: file_tags });
+            }
+        }
+
+        Ok(Response::new(IndexedCidsReply { cid, tags }))
+    }
+
+    async fn create_saved_search(
+        &self,
+        r: Request<CreateSavedSearchRequest>,
+    ) -> Result<Response<CreateSavedSearchReply>, Status> {
+        let req = r.into_inner();
+
+        let saved_search = self
+            .runtime
+            .create_saved_search(req.name, req.query, req.sort, req.owner)
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(CreateSavedSearchReply {
+            saved_search: Some(saved_search),
+        }))
+    }
+
+    async fn list_saved_searches(
+        &self,
+        r: Request<ListSavedSearchesRequest>,
+    ) -> Result<Response<ListSavedSearchesReply>, Status> {
+        let req = r.into_inner();
+
+        let saved_search = self
+            .runtime
+            .saved_searches(req.owner)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(ListSavedSearchesReply { saved_search }))
+    }
+
+    async fn delete_saved_search(
+        &self,
+        r: Request<DeleteSavedSearchRequest>,
+    ) -> Result<Response<DeleteSavedSearchReply>, Status> {
+        let req = r.into_inner();
+
+        let deleted = self
+            .runtime
+            .delete_saved_search(req.id, req.owner)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if !deleted {
+            return Err(Status::not_found("No such saved search"));
+        }
+
+        Ok(Response::new(DeleteSavedSearchReply {}))
+    }
+
+    async fn execute_saved_search(
+        &self,
+        r: Request<ExecuteSavedSearchRequest>,
+    ) -> Result<Response<ExecuteSavedSearchReply>, Status> {
+        let req = r.into_inner();
+
+        let (file, next_page_token) = self
+            .runtime
+            .execute_saved_search(
+                req.id,
+                req.owner,
+                req.page_size,
+                req.page_token,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found("No such saved search"))?;
+
+        Ok(Response::new(ExecuteSavedSearchReply {
+            file,
+            next_page_token,
+        }))
+    }
+
+    async fn similar_files(
+        &self,
+        r: Request<SimilarFilesRequest>,
+    ) -> Result<Response<SimilarFilesReply>, Status> {
+        let req = r.into_inner();
+
+        let similar = self
+            .runtime
+            .similar_files(&req.cid, req.max_distance, req.count as usize)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or_else(|| Status::not_found("No perceptual hash for CID"))?;
+
+        Ok(Response::new(SimilarFilesReply { similar }))
+    }
+
+    async fn reverse_search(
+        &self,
+        r: Request<tonic::Streaming<ReverseSearchRequest>>,
+    ) -> Result<Response<ReverseSearchReply>, Status> {
+        let mut chunk_stream = r.into_inner();
+        let mut data = vec![];
+        let mut params = None;
+
+        while let Some(res) = chunk_stream.next().await {
+            let mut chunk = res?;
+            // Search parameters are taken from the first message
+            if params.is_none() {
+                params = Some((chunk.max_distance, chunk.count));
+            }
+            data.append(&mut chunk.data);
+
+            if data.len() > MAX_REVERSE_SEARCH_BYTES {
+                return Err(Status::resource_exhausted(
+                    "Probe image too large",
+                ));
+            }
+        }
+
+        let (max_distance, count) = match params {
+            Some(p) => p,
+            None => return Err(Status::invalid_argument("Empty file")),
+        };
+
+        let result = self
+            .runtime
+            .reverse_search(data, max_distance, count as usize)
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(ReverseSearchReply { result }))
+    }
+
+    async fn find_duplicates(
+        &self,
+        r: Request<FindDuplicatesRequest>,
+    ) -> Result<Response<FindDuplicatesReply>, Status> {
+        let req = r.into_inner();
+
+        let runtime = self.runtime.clone();
+        let group = tokio::task::spawn_blocking(move || {
+            runtime.find_duplicates(req.max_distance)
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(FindDuplicatesReply { group }))
+    }
+}
+
+fn is_finished(job: &Job) -> bool {
+    JobState::from_str(&job.state)
+        .map(|s| s.is_finished())
+        .unwrap_or(true)
+}
+
+/// Queued jobs being waited on. Subscribe to job updates before queueing
+/// them so none finishes unseen
+struct PendingJobs {
+    runtime: Arc<Runtime>,
+    updates: broadcast::Receiver<Job>,
+    ids: HashSet<i64>,
+    // Finished jobs read back from the database after falling behind
+    caught_up: Vec<Job>,
+}
+
+impl PendingJobs {
+    /// The next of the jobs to finish, or None once they all have
+    async fn next_finished(&mut self) -> Option<Job> {
+        loop {
+            if let Some(job) = self.caught_up.pop() {
+                if self.ids.remove(&job.id) {
+                    return Some(job);
+                }
+                continue;
+            }
+            if self.ids.is_empty() {
+                return None;
+            }
+
+            match self.updates.recv().await {
+                Ok(job) if is_finished(&job) && self.ids.remove(&job.id) => {
+                    return Some(job)
+                }
+                Ok(_) => {}
+                // Fell too far behind to know what was missed; catch up from
+                // the database instead
+                Err(RecvError::Lagged(_)) => {
+                    for id in &self.ids {
+                        let job = self.runtime.job(*id).await.ok()?;
+                        if is_finished(&job) {
+                            self.caught_up.push(job);
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("warn"),
+    )
+    .init();
 
     // Derive a path to use as filestore
     let mut default_filestore_path = Path::new(".hooya").to_path_buf();
@@ -301,6 +940,119 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .value_parser(value_parser!(PathBuf)),
         )
         .arg(Arg::new("db-uri").long("db-uri").env("HOOYAD_DB_URI"))
+        .arg(
+            Arg::new("thumbnail-sizes")
+                .long("thumbnail-sizes")
+                .env("HOOYAD_THUMBNAIL_SIZES")
+                .help("Comma-separated long edges of generated thumbnails")
+                .value_parser(value_parser!(u32).range(1..))
+                .value_delimiter(',')
+                .default_value("320,640,1280"),
+        )
+        .arg(
+            Arg::new("thumbnail-format")
+                .long("thumbnail-format")
+                .env("HOOYAD_THUMBNAIL_FORMAT")
+                .value_parser(["jpeg", "webp", "png"])
+                .default_value("jpeg"),
+        )
+        .arg(
+            Arg::new("thumbnail-alpha-format")
+                .long("thumbnail-alpha-format")
+                .env("HOOYAD_THUMBNAIL_ALPHA_FORMAT")
+                .help("Thumbnail format for sources with transparency")
+                .value_parser(["webp", "png"])
+                .default_value("webp"),
+        )
+        .arg(
+            Arg::new("thumbnail-background")
+                .long("thumbnail-background")
+                .env("HOOYAD_THUMBNAIL_BACKGROUND")
+                .help("Flatten transparent sources onto this color, eg #ffffff")
+                .conflicts_with("thumbnail-alpha-format"),
+        )
+        .arg(
+            Arg::new("thumbnail-quality")
+                .long("thumbnail-quality")
+                .env("HOOYAD_THUMBNAIL_QUALITY")
+                .value_parser(value_parser!(u8).range(1..=100))
+                .default_value("85"),
+        )
+        .arg(
+            Arg::new("thumbnail-max-edge")
+                .long("thumbnail-max-edge")
+                .env("HOOYAD_THUMBNAIL_MAX_EDGE")
+                .help("Largest thumbnail long edge generated on request")
+                .value_parser(value_parser!(u32))
+                .default_value("2048"),
+        )
+        .arg(
+            Arg::new("thumbnail-cache-size")
+                .long("thumbnail-cache-size")
+                .env("HOOYAD_THUMBNAIL_CACHE_SIZE")
+                .help("MiB of thumbnails generated on request to keep")
+                .value_parser(value_parser!(u64))
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::new("audio-tags")
+                .long("audio-tags")
+                .env("HOOYAD_AUDIO_TAGS")
+                .help("Tag audio files with the artist and album they name")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("embedded-tags")
+                .long("embedded-tags")
+                .env("HOOYAD_EMBEDDED_TAGS")
+                .help("Tag images with their XMP and IPTC keywords")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("embedded-field")
+                .long("embedded-field")
+                .action(ArgAction::Append)
+                .value_name("FIELD=NAMESPACE")
+                .requires("embedded-tags")
+                .help(
+                    "Import an embedded field into a namespace; * for fields \
+                    holding tags, empty to skip the field",
+                ),
+        )
+        .arg(
+            Arg::new("watch-dir")
+                .long("watch-dir")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(PathBuf))
+                .help("Import files dropped into this directory"),
+        )
+        .arg(
+            Arg::new("watch-tag")
+                .long("watch-tag")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(Tag))
+                .help("Tag files imported from watched directories"),
+        )
+        .arg(
+            Arg::new("watch-unlink")
+                .long("watch-unlink")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("watch-archive-to")
+                .help("Delete files from watched directories once imported"),
+        )
+        .arg(
+            Arg::new("watch-archive-to")
+                .long("watch-archive-to")
+                .value_parser(value_parser!(PathBuf))
+                .help("Move files from watched directories here once imported"),
+        )
+        .arg(
+            Arg::new("workers")
+                .long("workers")
+                .env("HOOYAD_WORKERS")
+                .help("Background jobs run at once, one per CPU if unset")
+                .value_parser(value_parser!(usize).range(1..)),
+        )
         .get_matches();
 
     let filestore_path = matches
@@ -317,26 +1069,110 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .get_one::<String>("db-uri")
         .unwrap_or(&default_db_uri);
 
-    let mut should_init = false;
     // TODO Match on URI for different DB types
     if !Sqlite::database_exists(db_uri).await.unwrap_or(false) {
         Sqlite::create_database(db_uri).await?;
-        should_init = true;
     }
 
     let mut db = hooya::local::Db::new(SqlitePool::connect(db_uri).await?);
 
-    if should_init {
-        db.init_tables().await?;
+    // Tables are only created if missing so this also brings databases from
+    // older versions up to date
+    db.init_tables().await?;
+
+    let transparency = match matches.get_one::<String>("thumbnail-background") {
+        Some(hex) => Transparency::Flatten(hooya::color::parse_hex(hex)?),
+        None => Transparency::Keep(ThumbnailFormat::from_str(
+            matches.get_one::<String>("thumbnail-alpha-format").unwrap(),
+        )?),
+    };
+
+    let thumbnail_config = ThumbnailConfig {
+        sizes: matches
+            .get_many::<u32>("thumbnail-sizes")
+            .unwrap()
+            .copied()
+            .collect(),
+        format: ThumbnailFormat::from_str(
+            matches.get_one::<String>("thumbnail-format").unwrap(),
+        )?,
+        quality: *matches.get_one::<u8>("thumbnail-quality").unwrap(),
+        transparency,
+        max_on_demand_edge: *matches
+            .get_one::<u32>("thumbnail-max-edge")
+            .unwrap(),
+        cache_bytes: matches.get_one::<u64>("thumbnail-cache-size").unwrap()
+            * 1024
+            * 1024,
+    };
+
+    let embedded_tags = if matches.get_flag("embedded-tags") {
+        let mut field_map = FieldMap::default();
+        for entry in matches
+            .get_many::<String>("embedded-field")
+            .unwrap_or_default()
+        {
+            field_map.set(entry)?;
+        }
+        Some(field_map)
+    } else {
+        None
+    };
+
+    let runtime = Arc::new(
+        Runtime::new(
+            filestore_path.to_path_buf(),
+            db,
+            thumbnail_config,
+            matches.get_flag("audio-tags"),
+            embedded_tags,
+        )
+        .await?,
+    );
+
+    let workers = match matches.get_one::<usize>("workers") {
+        Some(w) => *w,
+        None => std::thread::available_parallelism()?.get(),
+    };
+    // Jobs decode and encode images synchronously, so each worker gets a
+    // thread of its own rather than stalling the async executor
+    let handle = tokio::runtime::Handle::current();
+    for _ in 0..workers {
+        let runtime = runtime.clone();
+        let handle = handle.clone();
+        tokio::task::spawn_blocking(move || handle.block_on(runtime.work()));
+    }
+
+    let watch_tags: Vec<Tag> = matches
+        .get_many::<Tag>("watch-tag")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let after_import = match matches.get_one::<PathBuf>("watch-archive-to") {
+        Some(dir) => AfterImport::Archive(dir.clone()),
+        None if matches.get_flag("watch-unlink") => AfterImport::Delete,
+        None => AfterImport::Keep,
+    };
+    for dir in matches.get_many::<PathBuf>("watch-dir").unwrap_or_default() {
+        let dir = dir.canonicalize()?;
+        after_import.check(&dir)?;
+
+        let runtime = runtime.clone();
+        let handle = handle.clone();
+        let tags = watch_tags.clone();
+        let after_import = after_import.clone();
+        // Hashing and copying files in is blocking work too
+        tokio::task::spawn_blocking(move || {
+            let result =
+                handle.block_on(runtime.watch_dir(&dir, tags, &after_import));
+            if let Err(e) = result {
+                log::error!("Stopped watching {}: {}", dir.display(), e);
+            }
+        });
     }
 
     Server::builder()
-        .add_service(ControlServer::new(IControl {
-            runtime: Runtime {
-                filestore_path: filestore_path.to_path_buf(),
-                db,
-            },
-        }))
+        .add_service(ControlServer::new(IControl { runtime }))
         .serve(matches.get_one::<String>("endpoint").unwrap().parse()?)
         .await?;
     Ok(())
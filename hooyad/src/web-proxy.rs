@@ -1,17 +1,22 @@
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    body::{Bytes, StreamBody},
+    extract::{DefaultBodyLimit, Path, Query, State},
     http::HeaderMap,
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Router,
 };
 use clap::{command, Arg};
 use dotenv::dotenv;
+use futures_util::StreamExt;
+use hooya::mimetype_extension;
 use hooya::proto::{
     control_client::ControlClient, CidInfoRequest, CidThumbnailRequest,
-    ContentAtCidRequest, Thumbnail, Tag, TagsRequest,
+    ContentAtCidPageRequest, ContentAtCidRequest, ReverseSearchRequest, Tag,
+    TagsRequest, Thumbnail,
 };
+use serde::{Deserialize, Serialize};
 use tonic::transport::Channel;
 mod config;
 
@@ -22,6 +27,28 @@ struct AState {
 
 pub const DEFAULT_HOOYA_WEB_PROXY_ENDPOINT: &str = "0.0.0.0:8532";
 
+// Largest probe image accepted by /reverse-search
+const MAX_REVERSE_SEARCH_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct ThumbnailParams {
+    // Serve the animated variant when the source has one
+    animated: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ReverseSearchParams {
+    distance: Option<u32>,
+    count: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ReverseSearchMatch {
+    cid: String,
+    distance: u32,
+    color_distance: f32,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
@@ -50,10 +77,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let app = Router::new()
         .route("/cid-content/:cid", get(cid_content))
+        .route("/cid-page/:cid/:n", get(cid_page))
         .route("/cid-thumbnail/:cid/medium", get(cid_thumbnail_medium))
         .route("/cid-thumbnail/:cid/small", get(cid_thumbnail_small))
         .route("/cid-thumbnail/:cid/:long_edge", get(cid_thumbnail))
         .route("/cid-tags/:cid", get(cid_tags))
+        .route(
+            "/reverse-search",
+            post(reverse_search)
+                .layer(DefaultBodyLimit::max(MAX_REVERSE_SEARCH_BYTES)),
+        )
         .with_state(state);
 
     axum::Server::bind(
@@ -84,7 +117,10 @@ async fn cid_content(
 
     let mut client = state.client;
     let mut chunk_stream = client
-        .content_at_cid(ContentAtCidRequest { cid: cid.clone() })
+        .content_at_cid(ContentAtCidRequest {
+            cid: cid.clone(),
+            offset: None,
+        })
         .await
         .unwrap()
         .into_inner();
@@ -132,9 +168,67 @@ async fn cid_content(
     (headers, body).into_response()
 }
 
+async fn cid_page(
+    State(state): State<AState>,
+    Path((encoded_cid, page)): Path<(String, u32)>,
+) -> impl IntoResponse {
+    let (_, cid) = match hooya::cid::decode(&encoded_cid) {
+        Ok(cid) => cid,
+        _ => {
+            return (axum::http::StatusCode::BAD_REQUEST, "Invalid CID")
+                .into_response()
+        }
+    };
+
+    let mut client = state.client;
+    let response = match client
+        .content_at_cid_page(ContentAtCidPageRequest { cid, page })
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            return (axum::http::StatusCode::NOT_FOUND, e.message().to_string())
+                .into_response()
+        }
+    };
+
+    let mimetype = response
+        .metadata()
+        .get("mimetype")
+        .and_then(|m| m.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let mut headers = HeaderMap::new();
+    headers.append(
+        axum::http::header::CACHE_CONTROL,
+        "max-age=31536000, immutable".parse().unwrap(),
+    );
+    headers.append(axum::http::header::CONTENT_TYPE, mimetype.parse().unwrap());
+
+    if let Some(save_extension) = mimetype_extension(&mimetype) {
+        headers.append(
+            axum::http::header::CONTENT_DISPOSITION,
+            format!(
+                "inline; filename=\"{}_p{}.{}\"",
+                encoded_cid, page, save_extension
+            )
+            .parse()
+            .unwrap(),
+        );
+    }
+
+    // Pages are passed along as hooyad decompresses them
+    let body =
+        StreamBody::new(response.into_inner().map(|c| c.map(|m| m.data)));
+
+    (headers, body).into_response()
+}
+
 async fn cid_thumbnail_medium(
     State(state): State<AState>,
     Path(encoded_cid): Path<String>,
+    Query(params): Query<ThumbnailParams>,
 ) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     let (_, cid) = match hooya::cid::decode(&encoded_cid) {
@@ -163,9 +257,15 @@ async fn cid_thumbnail_medium(
         }
     };
 
-    let thumbs = match ext_file {
-        hooya::proto::file::ExtFile::Image(i) => i.thumbnails,
-    };
+    let thumbs = ext_file.thumbnails().to_vec();
+    let thumbs = thumbnail_variant(thumbs, params.animated.unwrap_or(false));
+    if thumbs.is_empty() {
+        return (
+            axum::http::StatusCode::NOT_FOUND,
+            "No thumbnails for this indexed CID",
+        )
+            .into_response();
+    }
 
     let thumbnail = closest_thumbnail(&thumbs, 1280);
     let long_edge = if thumbnail.width > thumbnail.height {
@@ -180,6 +280,7 @@ async fn cid_thumbnail_medium(
         .cid_thumbnail(CidThumbnailRequest {
             source_cid: cid,
             long_edge,
+            animated: thumbnail.is_animated,
         })
         .await
         .unwrap()
@@ -219,6 +320,7 @@ async fn cid_thumbnail_medium(
 async fn cid_thumbnail_small(
     State(state): State<AState>,
     Path(encoded_cid): Path<String>,
+    Query(params): Query<ThumbnailParams>,
 ) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     let (_, cid) = match hooya::cid::decode(&encoded_cid) {
@@ -247,9 +349,15 @@ async fn cid_thumbnail_small(
         }
     };
 
-    let thumbs = match ext_file {
-        hooya::proto::file::ExtFile::Image(i) => i.thumbnails,
-    };
+    let thumbs = ext_file.thumbnails().to_vec();
+    let thumbs = thumbnail_variant(thumbs, params.animated.unwrap_or(false));
+    if thumbs.is_empty() {
+        return (
+            axum::http::StatusCode::NOT_FOUND,
+            "No thumbnails for this indexed CID",
+        )
+            .into_response();
+    }
 
     let thumbnail = closest_thumbnail(&thumbs, 640);
     let long_edge = if thumbnail.width > thumbnail.height {
@@ -264,6 +372,7 @@ async fn cid_thumbnail_small(
         .cid_thumbnail(CidThumbnailRequest {
             source_cid: cid,
             long_edge,
+            animated: thumbnail.is_animated,
         })
         .await
         .unwrap()
@@ -303,6 +412,7 @@ async fn cid_thumbnail_small(
 async fn cid_thumbnail(
     State(state): State<AState>,
     Path((encoded_cid, long_edge)): Path<(String, u32)>,
+    Query(params): Query<ThumbnailParams>,
 ) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     let (_, cid) = match hooya::cid::decode(&encoded_cid) {
@@ -331,34 +441,32 @@ async fn cid_thumbnail(
         }
     };
 
-    let thumbs = match ext_file {
-        hooya::proto::file::ExtFile::Image(i) => i.thumbnails,
-    };
-
-    let thumb_match = thumbs.iter().find(|t| {
-        (t.width == long_edge as i64 && t.height < t.width)
-            || (t.height == long_edge as i64 && t.height > t.width)
-    });
-
-    let thumb = match thumb_match {
-        Some(t) => t,
-        None => {
-            return (
-                axum::http::StatusCode::NOT_FOUND,
-                "No such sized thumbnail for this indexed CID",
-            )
-                .into_response()
-        }
-    };
+    // Sizes that weren't made at import are generated by hooyad on request
+    let animated = params.animated.unwrap_or(false)
+        && ext_file.thumbnails().iter().any(|t| t.is_animated);
 
-    let mut chunk_stream = client
+    let response = match client
         .cid_thumbnail(CidThumbnailRequest {
             source_cid: cid,
             long_edge,
+            animated,
         })
         .await
-        .unwrap()
-        .into_inner();
+    {
+        Ok(response) => response,
+        Err(e) => {
+            return (axum::http::StatusCode::NOT_FOUND, e.message().to_string())
+                .into_response()
+        }
+    };
+
+    let mimetype = response
+        .metadata()
+        .get("mimetype")
+        .and_then(|m| m.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let mut chunk_stream = response.into_inner();
 
     let mut body = vec![];
     while let Some(mut m) = chunk_stream.message().await.unwrap() {
@@ -370,14 +478,12 @@ async fn cid_thumbnail(
         axum::http::header::CACHE_CONTROL,
         "max-age=31536000, immutable".parse().unwrap(),
     );
-    headers.append(axum::http::header::CONTENT_LENGTH, thumb.size.into());
+    headers.append(axum::http::header::CONTENT_LENGTH, body.len().into());
 
-    headers.append(
-        axum::http::header::CONTENT_TYPE,
-        thumb.mimetype.parse().unwrap(),
-    );
+    headers.append(axum::http::header::CONTENT_TYPE, mimetype.parse().unwrap());
 
-    let save_extension = mimetype_extension(&thumb.mimetype).unwrap();
+    let save_extension =
+        mimetype_extension(&mimetype).unwrap_or_else(|| "bin".to_string());
     headers.append(
         axum::http::header::CONTENT_DISPOSITION,
         format!(
@@ -391,6 +497,20 @@ async fn cid_thumbnail(
     (headers, body).into_response()
 }
 
+/// Thumbnails of the requested variant. Sources without animated thumbnails
+/// fall back to their stills
+fn thumbnail_variant(
+    thumbnails: Vec<Thumbnail>,
+    animated: bool,
+) -> Vec<Thumbnail> {
+    let animated = animated && thumbnails.iter().any(|t| t.is_animated);
+
+    thumbnails
+        .into_iter()
+        .filter(|t| t.is_animated == animated)
+        .collect()
+}
+
 fn closest_thumbnail(thumbnails: &[Thumbnail], long_edge: i64) -> &Thumbnail {
     thumbnails
         .iter()
@@ -405,16 +525,6 @@ fn closest_thumbnail(thumbnails: &[Thumbnail], long_edge: i64) -> &Thumbnail {
         .unwrap()
 }
 
-fn mimetype_extension(mimetype: &str) -> Option<String> {
-    match mimetype {
-        "image/jpeg" => Some("jpeg".to_string()),
-        "image/png" => Some("png".to_string()),
-        "image/gif" => Some("gif".to_string()),
-        "video/mp4" => Some("mp4".to_string()),
-        _ => None,
-    }
-}
-
 async fn cid_tags(
     State(state): State<AState>,
     Path(encoded_cid): Path<String>,
@@ -430,9 +540,7 @@ async fn cid_tags(
     let mut client = state.client;
 
     let tags: Vec<Tag> = client
-        .tags(TagsRequest{
-            cid,
-        })
+        .tags(TagsRequest { cid })
         .await
         .unwrap()
         .into_inner()
@@ -440,3 +548,48 @@ async fn cid_tags(
 
     axum::Json(tags).into_response()
 }
+
+async fn reverse_search(
+    State(state): State<AState>,
+    Query(params): Query<ReverseSearchParams>,
+    body: Bytes,
+) -> impl IntoResponse {
+    let max_distance = params.distance.unwrap_or(10);
+    let count = params.count.unwrap_or(20);
+
+    let chunks: Vec<ReverseSearchRequest> = body
+        .chunks(1024 * 1024)
+        .map(|c| ReverseSearchRequest {
+            data: c.to_vec(),
+            max_distance,
+            count,
+        })
+        .collect();
+
+    let mut client = state.client;
+
+    let results = match client
+        .reverse_search(futures_util::stream::iter(chunks))
+        .await
+    {
+        Ok(r) => r.into_inner().result,
+        Err(e) => {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                e.message().to_string(),
+            )
+                .into_response()
+        }
+    };
+
+    let matches: Vec<ReverseSearchMatch> = results
+        .into_iter()
+        .map(|r| ReverseSearchMatch {
+            cid: hooya::cid::encode(r.cid),
+            distance: r.distance,
+            color_distance: r.color_distance,
+        })
+        .collect();
+
+    axum::Json(matches).into_response()
+}
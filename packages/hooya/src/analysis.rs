@@ -0,0 +1,54 @@
+use anyhow::Result;
+use std::fmt;
+use std::str::FromStr;
+
+/// How far extracting the type-specific details of a stored file got
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnalysisStatus {
+    /// Waiting on a job
+    Pending,
+    Analyzed,
+    /// Stored, but of a type or format hooyad has no decoder for
+    Unsupported,
+    /// Decoding went wrong; worth retrying after a fix
+    Failed,
+}
+
+/// Error for files that can be stored but not analyzed, as opposed to ones
+/// that failed to analyze
+#[derive(Debug)]
+pub struct Unsupported(pub String);
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+impl FromStr for AnalysisStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(AnalysisStatus::Pending),
+            "analyzed" => Ok(AnalysisStatus::Analyzed),
+            "unsupported" => Ok(AnalysisStatus::Unsupported),
+            "failed" => Ok(AnalysisStatus::Failed),
+            _ => Err(anyhow::anyhow!("Unknown analysis status \"{}\"", s)),
+        }
+    }
+}
+
+impl ToString for AnalysisStatus {
+    fn to_string(&self) -> String {
+        match self {
+            AnalysisStatus::Pending => "pending",
+            AnalysisStatus::Analyzed => "analyzed",
+            AnalysisStatus::Unsupported => "unsupported",
+            AnalysisStatus::Failed => "failed",
+        }
+        .to_string()
+    }
+}
@@ -0,0 +1,242 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+use anyhow::Result;
+use image::{DynamicImage, ImageFormat};
+use zip::ZipArchive;
+
+use crate::ChunkedReader;
+
+// Archives with more entries than this are not treated as page sequences
+const MAX_ENTRIES: usize = 10_000;
+
+// Pages that decompress to more than this are left out, so a small archive
+// can't expand into an unbounded amount of memory
+const MAX_PAGE_BYTES: u64 = 64 * 1024 * 1024;
+
+// Dimensions are read from this much of the start of a page, which holds
+// the header of any reasonable image
+const HEADER_BYTES: u64 = 256 * 1024;
+
+/// An image inside a comic archive
+pub struct Page {
+    // Path of the entry within the archive
+    pub name: String,
+    pub mimetype: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// List the image pages of a ZIP/CBZ archive in reading order. Gives None
+/// for archives with no images in them
+pub fn read_pages(path: &Path) -> Result<Option<Vec<Page>>> {
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+    if archive.len() > MAX_ENTRIES {
+        return Ok(None);
+    }
+
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|n| page_format(n).is_some() && !is_junk(n))
+        .map(|n| n.to_string())
+        .collect();
+    names.sort_by(|a, b| natural_cmp(a, b));
+
+    let mut pages = vec![];
+    for name in names {
+        let (format, mimetype) = page_format(&name).unwrap();
+        let entry = archive.by_name(&name)?;
+        if entry.size() > MAX_PAGE_BYTES {
+            continue;
+        }
+
+        // Pages that aren't really images are left out rather than failing
+        // the whole archive
+        let (width, height) = match page_dimensions(entry, format)? {
+            Some(d) => d,
+            None => continue,
+        };
+
+        pages.push(Page {
+            name,
+            mimetype: mimetype.to_string(),
+            width,
+            height,
+        });
+    }
+
+    if pages.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(pages))
+}
+
+/// Decode a single page of an archive
+pub fn read_page_image(path: &Path, name: &str) -> Result<DynamicImage> {
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+    let entry = archive.by_name(name)?;
+    if entry.size() > MAX_PAGE_BYTES {
+        return Err(anyhow::anyhow!("Page {} is too large", name));
+    }
+
+    // The declared size may lie, so reading is capped as well
+    let mut buf = vec![];
+    entry.take(MAX_PAGE_BYTES + 1).read_to_end(&mut buf)?;
+    if buf.len() as u64 > MAX_PAGE_BYTES {
+        return Err(anyhow::anyhow!("Page {} is too large", name));
+    }
+
+    Ok(image::load_from_memory(&buf)?)
+}
+
+/// Width and height of a page from its header, reading further only for
+/// images whose header is not near the start. None for pages that are not
+/// really images or are too large
+fn page_dimensions(
+    entry: impl Read,
+    format: ImageFormat,
+) -> Result<Option<(u32, u32)>> {
+    let mut entry = entry.take(MAX_PAGE_BYTES + 1);
+    let mut buf = vec![];
+    (&mut entry).take(HEADER_BYTES).read_to_end(&mut buf)?;
+
+    let dimensions = |buf: &[u8]| {
+        image::io::Reader::with_format(Cursor::new(buf), format)
+            .into_dimensions()
+            .ok()
+    };
+    if let Some(d) = dimensions(&buf) {
+        return Ok(Some(d));
+    }
+
+    entry.read_to_end(&mut buf)?;
+    if buf.len() as u64 > MAX_PAGE_BYTES {
+        return Ok(None);
+    }
+    Ok(dimensions(&buf))
+}
+
+/// Read a single page of an archive in chunks, decompressing only that
+/// entry, until `send` returns false
+pub fn stream_page(
+    path: &Path,
+    name: &str,
+    mut send: impl FnMut(io::Result<Vec<u8>>) -> bool,
+) -> Result<()> {
+    let mut archive = ZipArchive::new(fs::File::open(path)?)?;
+    let entry = archive.by_name(name)?;
+
+    for chunk in ChunkedReader::new(entry) {
+        if !send(chunk) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn page_format(name: &str) -> Option<(ImageFormat, &'static str)> {
+    let ext = Path::new(name).extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "jpg" | "jpeg" => Some((ImageFormat::Jpeg, "image/jpeg")),
+        "png" => Some((ImageFormat::Png, "image/png")),
+        "gif" => Some((ImageFormat::Gif, "image/gif")),
+        "webp" => Some((ImageFormat::WebP, "image/webp")),
+        "bmp" => Some((ImageFormat::Bmp, "image/bmp")),
+        _ => None,
+    }
+}
+
+/// Resource forks and hidden files some archivers add alongside the pages
+fn is_junk(name: &str) -> bool {
+    name.starts_with("__MACOSX/")
+        || name.split('/').any(|part| part.starts_with('.'))
+}
+
+/// Compare names with runs of digits ordered by value, so page2 sorts
+/// before page10
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_digits(&mut a);
+                let y = take_digits(&mut b);
+
+                // Compare by value without parsing, which could overflow
+                let x_trimmed = x.trim_start_matches('0');
+                let y_trimmed = y.trim_start_matches('0');
+                let ord = x_trimmed
+                    .len()
+                    .cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        digits.push(c);
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "page10.jpg",
+            "page2.jpg",
+            "Page1.jpg",
+            "page1b.jpg",
+            "cover.jpg",
+            "page99999999999999999999999.jpg",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            vec![
+                "cover.jpg",
+                "Page1.jpg",
+                "page1b.jpg",
+                "page2.jpg",
+                "page10.jpg",
+                "page99999999999999999999999.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn leading_zeros_compare_by_value() {
+        assert_eq!(natural_cmp("p007", "p7"), Ordering::Equal);
+        assert_eq!(natural_cmp("p009", "p10"), Ordering::Less);
+        assert_eq!(natural_cmp("p1", "p1a"), Ordering::Less);
+    }
+}
@@ -0,0 +1,62 @@
+use std::borrow::Cow;
+use std::path::Path;
+
+use anyhow::Result;
+use lofty::{Accessor, AudioFile, PictureType, TaggedFileExt};
+
+/// Stream details and tags of an audio file
+pub struct AudioInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    // Seconds
+    pub duration: f64,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    // Embedded front cover, or whichever picture comes first, still encoded
+    pub cover: Option<Vec<u8>>,
+}
+
+/// Read the ID3, Vorbis comment, FLAC or MP4 metadata of an audio file.
+/// Formats without tag support give None
+pub fn read_info(path: &Path, mimetype: &str) -> Result<Option<AudioInfo>> {
+    match mimetype {
+        "audio/mpeg" | "audio/x-flac" | "audio/ogg" | "audio/opus"
+        | "audio/m4a" | "audio/x-wav" | "audio/x-aiff" | "audio/x-ape" => {}
+        _ => return Ok(None),
+    }
+
+    let tagged_file = lofty::read_from_path(path)?;
+    let properties = tagged_file.properties();
+
+    let mut info = AudioInfo {
+        title: None,
+        artist: None,
+        album: None,
+        duration: properties.duration().as_secs_f64(),
+        sample_rate: properties.sample_rate(),
+        channels: properties.channels(),
+        cover: None,
+    };
+
+    let tag = match tagged_file.primary_tag().or(tagged_file.first_tag()) {
+        Some(tag) => tag,
+        None => return Ok(Some(info)),
+    };
+
+    let text = |s: Option<Cow<str>>| {
+        s.map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+    };
+    info.title = text(tag.title());
+    info.artist = text(tag.artist());
+    info.album = text(tag.album());
+
+    let pictures = tag.pictures();
+    info.cover = pictures
+        .iter()
+        .find(|p| p.pic_type() == PictureType::CoverFront)
+        .or(pictures.first())
+        .map(|p| p.data().to_vec());
+
+    Ok(Some(info))
+}
@@ -0,0 +1,187 @@
+/// Burkhard-Keller tree over 64-bit perceptual hashes using Hamming distance,
+/// for finding every value whose hash is within some distance of a probe
+/// without comparing against all of them
+pub struct BkTree<T> {
+    root: Option<Node<T>>,
+    len: usize,
+}
+
+struct Node<T> {
+    hash: u64,
+    values: Vec<T>,
+    // Keyed by distance from this node's hash
+    children: Vec<(u32, Node<T>)>,
+}
+
+pub fn hamming_distance(x: u64, y: u64) -> u32 {
+    (x ^ y).count_ones()
+}
+
+impl<T> Default for BkTree<T> {
+    fn default() -> Self {
+        BkTree { root: None, len: 0 }
+    }
+}
+
+impl<T: PartialEq> BkTree<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert a value under a hash. Inserting a value already stored under
+    /// the same hash is a no-op
+    pub fn insert(&mut self, hash: u64, value: T) {
+        if self.root.is_none() {
+            self.root = Some(Node::new(hash, value));
+            self.len += 1;
+            return;
+        }
+
+        let mut node = self.root.as_mut().unwrap();
+
+        loop {
+            let distance = hamming_distance(node.hash, hash);
+            if distance == 0 {
+                if !node.values.contains(&value) {
+                    node.values.push(value);
+                    self.len += 1;
+                }
+                return;
+            }
+
+            match node.children.iter().position(|(d, _)| *d == distance) {
+                Some(i) => node = &mut node.children[i].1,
+                None => {
+                    node.children.push((distance, Node::new(hash, value)));
+                    self.len += 1;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Remove a value stored under a hash, returning whether it was there.
+    /// Emptied nodes stay in place to route lookups to their children
+    pub fn remove(&mut self, hash: u64, value: &T) -> bool {
+        let mut node = match self.root.as_mut() {
+            Some(n) => n,
+            None => return false,
+        };
+
+        loop {
+            let distance = hamming_distance(node.hash, hash);
+            if distance == 0 {
+                let before = node.values.len();
+                node.values.retain(|v| v != value);
+                let removed = node.values.len() != before;
+                if removed {
+                    self.len -= 1;
+                }
+                return removed;
+            }
+
+            match node.children.iter().position(|(d, _)| *d == distance) {
+                Some(i) => node = &mut node.children[i].1,
+                None => return false,
+            }
+        }
+    }
+
+    /// Every value within `max_distance` of `hash` along with its distance,
+    /// closest first
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(&T, u32)> {
+        let mut found = vec![];
+        let mut to_visit: Vec<&Node<T>> = self.root.iter().collect();
+
+        while let Some(node) = to_visit.pop() {
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                found.extend(node.values.iter().map(|v| (v, distance)));
+            }
+
+            // Triangle inequality bounds which subtrees can hold matches
+            let low = distance.saturating_sub(max_distance);
+            let high = distance + max_distance;
+            to_visit.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| *d >= low && *d <= high)
+                    .map(|(_, n)| n),
+            );
+        }
+
+        found.sort_by_key(|(_, d)| *d);
+        found
+    }
+
+    /// Every stored value along with its hash
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &T)> {
+        let mut to_visit: Vec<&Node<T>> = self.root.iter().collect();
+        let mut entries = vec![];
+
+        while let Some(node) = to_visit.pop() {
+            entries.extend(node.values.iter().map(|v| (node.hash, v)));
+            to_visit.extend(node.children.iter().map(|(_, n)| n));
+        }
+
+        entries.into_iter()
+    }
+}
+
+impl<T> Node<T> {
+    fn new(hash: u64, value: T) -> Self {
+        Node {
+            hash,
+            values: vec![value],
+            children: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_within_distance() {
+        let mut tree = BkTree::default();
+        tree.insert(0b0000, "a");
+        tree.insert(0b0001, "b");
+        tree.insert(0b0011, "c");
+        tree.insert(0b0111, "d");
+        tree.insert(u64::MAX, "e");
+
+        let found: Vec<_> = tree.find(0b0000, 2);
+        assert_eq!(found, vec![(&"a", 0), (&"b", 1), (&"c", 2)]);
+        assert_eq!(tree.find(u64::MAX, 0), vec![(&"e", 0)]);
+        assert!(tree.find(0b1111_0000, 1).is_empty());
+    }
+
+    #[test]
+    fn same_hash_holds_several_values() {
+        let mut tree = BkTree::default();
+        tree.insert(42, "a");
+        tree.insert(42, "b");
+        tree.insert(42, "a");
+
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.find(42, 0).len(), 2);
+    }
+
+    #[test]
+    fn remove_keeps_children_reachable() {
+        let mut tree = BkTree::default();
+        tree.insert(0b0000, "root");
+        tree.insert(0b0001, "child");
+
+        assert!(tree.remove(0b0000, &"root"));
+        assert!(!tree.remove(0b0000, &"root"));
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.find(0b0001, 0), vec![(&"child", 0)]);
+        assert!(tree.find(0b0000, 0).is_empty());
+    }
+}
@@ -12,6 +12,13 @@ pub fn wrap_digest(d: Digest) -> Result<Vec<u8>, cid::multihash::Error> {
     Ok(Cid::new_v1(CURR_CODEC, m_hash).into())
 }
 
+/// CID of a file whose SHA2-256 digest is already known, eg from another
+/// program's database
+pub fn from_sha256(digest: &[u8]) -> Result<Vec<u8>, cid::multihash::Error> {
+    let m_hash = Multihash::wrap(CURR_MULTIHASH_FORMAT, digest)?;
+    Ok(Cid::new_v1(CURR_CODEC, m_hash).into())
+}
+
 pub fn encode<T>(t: T) -> String
 where
     T: AsRef<[u8]>,
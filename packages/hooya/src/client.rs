@@ -1,107 +1,657 @@
 use anyhow::Result;
-use futures_util::future::BoxFuture;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::{fs::File, path::Path};
 
+use futures_util::StreamExt;
+use tonic::metadata::MetadataValue;
 use tonic::transport::Channel;
 
-use crate::proto::{control_client::ControlClient, FileChunk};
+use crate::metadata::{self, FieldMap};
+use crate::proto::{
+    control_client::ControlClient, ContentAtCidRequest, FileChunk,
+    IndexedCidsRequest,
+};
 
+/// Request metadata carrying the name of a file being uploaded
+pub const FILE_NAME_KEY: &str = "file-name-bin";
+
+// CIDs asked about per request when checking a directory's status
+const STATUS_BATCH: usize = 500;
+
+/// What to do with symbolic links met while walking a directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    #[default]
+    Skip,
+    /// Add what links point to. Directories reached twice are only walked
+    /// once, so link cycles end
+    Follow,
+}
+
+/// Which files of a directory tree are added
+#[derive(Clone, Debug, Default)]
+pub struct DirFilter {
+    // Matched against paths relative to the directory being added; when
+    // set, only matching files are added
+    pub include: Option<GlobSet>,
+    // Matched likewise, against directories too
+    pub exclude: Option<GlobSet>,
+    // Whether dotfiles and dot-directories are added
+    pub hidden: bool,
+    pub symlinks: SymlinkPolicy,
+    // Levels of subdirectories walked below the directory being added
+    pub max_depth: Option<usize>,
+}
+
+/// How `stream_dir_to_remote_filestore` adds files
+#[derive(Clone)]
+pub struct AddOptions {
+    pub unlink: bool,
+    // Whether to keep going after a file fails to upload
+    pub cont_inue: bool,
+    pub init_tags: Vec<crate::proto::Tag>,
+    // Field map for reading sidecar metadata, or None to ignore sidecars
+    pub sidecars: Option<FieldMap>,
+    // Uploads in flight at once
+    pub concurrency: usize,
+    pub filter: DirFilter,
+}
+
+/// Counters an add in progress updates as it goes
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub files_total: AtomicU64,
+    pub files_done: AtomicU64,
+    pub bytes_total: AtomicU64,
+    pub bytes_done: AtomicU64,
+}
+
+/// What became of every path met while adding a directory
+#[derive(Debug, Default, Serialize)]
+pub struct AddReport {
+    pub added: Vec<ReportEntry>,
+    // Already in the filestore; tagged, but not stored again
+    pub duplicate: Vec<ReportEntry>,
+    pub skipped: Vec<ReportEntry>,
+    pub failed: Vec<ReportEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportEntry {
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    // Why the path was skipped or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AddReport {
+    /// Add the entries of another report to this one
+    pub fn merge(&mut self, other: AddReport) {
+        self.added.extend(other.added);
+        self.duplicate.extend(other.duplicate);
+        self.skipped.extend(other.skipped);
+        self.failed.extend(other.failed);
+    }
+}
+
+/// How the files of a local directory compare with the filestore
+#[derive(Debug, Default, Serialize)]
+pub struct DirStatus {
+    // Not in the filestore
+    pub new: Vec<ReportEntry>,
+    pub stored: Vec<ReportEntry>,
+    // Stored, but without some of the tags adding them would give
+    pub tags_differ: Vec<TagDifference>,
+    pub skipped: Vec<ReportEntry>,
+    pub failed: Vec<ReportEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagDifference {
+    pub path: PathBuf,
+    pub cid: String,
+    pub missing: Vec<String>,
+}
+
+/// A file streamed to the filestore
+pub struct Uploaded {
+    pub cid: Vec<u8>,
+    // Whether the filestore had it already
+    pub duplicate: bool,
+}
+
+/// Compile glob patterns into a set, or None when there are none
+pub fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for p in patterns {
+        builder.add(Glob::new(p)?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Upload a file with the given tags. When a field map is given, sidecar
+/// metadata files next to it are read for more tags
 pub async fn stream_file_to_remote_filestore(
-    mut client: ControlClient<Channel>,
+    client: ControlClient<Channel>,
     local_file: &Path,
     unlink: bool,
-    cont_inue: bool,
     init_tags: Vec<crate::proto::Tag>,
+    sidecars: Option<&FieldMap>,
 ) -> Result<()> {
-    {
-        let fh = File::open(local_file)?;
-
-        if fh.metadata()?.len() == 0 {
-            println!(
-                "Not streaming empty file {}",
-                local_file.file_name().unwrap().to_str().unwrap()
-            );
-            return Ok(());
-        }
-
-        let chunks = crate::ChunkedReader::new(fh)
-            .map(|c| FileChunk { data: c.unwrap() });
-        let resp = client
-            .stream_to_filestore(futures_util::stream::iter(chunks))
-            .await
-            .map_err(|e| {
-                anyhow::format_err!(
-                    "Error adding {}: {}",
-                    local_file.to_string_lossy(),
-                    e
-                )
-            });
-
-        let resp = match resp {
-            Ok(r) => r,
-            Err(e) => {
-                if cont_inue {
-                    eprintln!("{}", e);
-                    return Ok(());
-                } else {
-                    return Err(e);
+    if File::open(local_file)?.metadata()?.len() == 0 {
+        println!(
+            "Not streaming empty file {}",
+            local_file.file_name().unwrap().to_str().unwrap()
+        );
+        return Ok(());
+    }
+
+    // Read before uploading, as unlinking may remove sidecars other files
+    // share
+    let tags = file_tags(local_file, &init_tags, sidecars)?;
+    let uploaded = upload_file(client, local_file, tags, None).await?;
+
+    println!(
+        "added {} {}",
+        crate::cid::encode(uploaded.cid),
+        Path::new(local_file).file_name().unwrap().to_str().unwrap()
+    );
+
+    if unlink {
+        remove_with_sidecars(local_file, sidecars.is_some())?;
+    }
+
+    Ok(())
+}
+
+/// Upload every file of a directory tree that passes the filter, several at
+/// a time, and report what became of each
+pub async fn stream_dir_to_remote_filestore(
+    client: ControlClient<Channel>,
+    local_dir: &Path,
+    options: &AddOptions,
+    progress: Arc<Progress>,
+) -> Result<AddReport> {
+    let mut report = AddReport::default();
+    let files =
+        list_dir(local_dir, options, &mut report.skipped, &mut report.failed)?;
+    if let Some(f) = report.failed.first().filter(|_| !options.cont_inue) {
+        return Err(anyhow::anyhow!(
+            "{}: {}",
+            f.path.display(),
+            f.reason.as_deref().unwrap_or_default()
+        ));
+    }
+
+    progress
+        .files_total
+        .fetch_add(files.len() as u64, Ordering::Relaxed);
+    progress
+        .bytes_total
+        .fetch_add(files.iter().map(|f| f.size).sum(), Ordering::Relaxed);
+
+    let mut uploads = futures_util::stream::iter(files)
+        .map(|f| {
+            let client = client.clone();
+            let progress = progress.clone();
+            async move {
+                let result =
+                    upload_file(client, &f.path, f.tags, Some(progress)).await;
+                (f.path, result)
+            }
+        })
+        .buffer_unordered(options.concurrency.max(1));
+
+    while let Some((path, result)) = uploads.next().await {
+        progress.files_done.fetch_add(1, Ordering::Relaxed);
+
+        let uploaded = match result {
+            Ok(u) => u,
+            Err(e) if options.cont_inue => {
+                report.failed.push(ReportEntry {
+                    path,
+                    cid: None,
+                    reason: Some(e.to_string()),
+                });
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        if options.unlink {
+            match remove_with_sidecars(&path, options.sidecars.is_some()) {
+                Ok(()) => {}
+                Err(e) if options.cont_inue => {
+                    report.failed.push(ReportEntry {
+                        path,
+                        cid: Some(crate::cid::encode(uploaded.cid)),
+                        reason: Some(format!("Uploaded, but {}", e)),
+                    });
+                    continue;
                 }
+                Err(e) => return Err(e),
             }
+        }
+
+        let entry = ReportEntry {
+            path,
+            cid: Some(crate::cid::encode(uploaded.cid)),
+            reason: None,
         };
+        if uploaded.duplicate {
+            report.duplicate.push(entry);
+        } else {
+            report.added.push(entry);
+        }
+    }
 
-        let cid = resp.into_inner().cid;
-        client
-            .tag_cid(crate::proto::TagCidRequest {
-                cid: cid.clone(),
-                tags: init_tags,
+    Ok(report)
+}
+
+/// Which files of a local directory are already in the filestore, and which
+/// of those lack tags that adding them would give. Files are hashed
+/// locally, so nothing is uploaded
+pub async fn dir_status(
+    client: ControlClient<Channel>,
+    local_dir: &Path,
+    options: &AddOptions,
+) -> Result<DirStatus> {
+    let mut status = DirStatus::default();
+    let files =
+        list_dir(local_dir, options, &mut status.skipped, &mut status.failed)?;
+
+    // Hashing is blocking work, so each file gets a thread of its own
+    let mut hashed = vec![];
+    let mut hashes = futures_util::stream::iter(files)
+        .map(|f| {
+            tokio::task::spawn_blocking(move || {
+                let result = hash_file(&f.path);
+                (f, result)
             })
-            .await?;
+        })
+        .buffer_unordered(options.concurrency.max(1));
+    while let Some(joined) = hashes.next().await {
+        match joined? {
+            (f, Ok(cid)) => hashed.push((f.path, cid, f.tags)),
+            (f, Err(e)) => status.failed.push(ReportEntry {
+                path: f.path,
+                cid: None,
+                reason: Some(e.to_string()),
+            }),
+        }
+    }
+    hashed.sort_by(|a, b| a.0.cmp(&b.0));
 
-        println!(
-            "added {} {}",
-            crate::cid::encode(cid),
-            Path::new(local_file).file_name().unwrap().to_str().unwrap()
-        );
+    for batch in hashed.chunks(STATUS_BATCH) {
+        // Stored tags come back with the CIDs, when any are to be compared
+        let reply = client
+            .clone()
+            .indexed_cids(IndexedCidsRequest {
+                cid: batch.iter().map(|(_, cid, _)| cid.clone()).collect(),
+                with_tags: batch.iter().any(|(_, _, tags)| !tags.is_empty()),
+            })
+            .await?
+            .into_inner();
+        let mut stored_tags: HashMap<Vec<u8>, Vec<crate::proto::Tag>> = reply
+            .cid
+            .iter()
+            .cloned()
+            .zip(reply.tags.into_iter().map(|t| t.tags))
+            .collect();
+        let indexed: HashSet<Vec<u8>> = reply.cid.into_iter().collect();
+
+        for (path, cid, expected) in batch {
+            let entry = ReportEntry {
+                path: path.clone(),
+                cid: Some(crate::cid::encode(cid)),
+                reason: None,
+            };
+            if !indexed.contains(cid) {
+                status.new.push(entry);
+                continue;
+            }
+
+            if !expected.is_empty() {
+                let stored = stored_tags.remove(cid).unwrap_or_default();
+                let missing: Vec<String> = expected
+                    .iter()
+                    .filter(|t| !stored.contains(t))
+                    .map(|t| t.to_string())
+                    .collect();
+                if !missing.is_empty() {
+                    status.tags_differ.push(TagDifference {
+                        path: path.clone(),
+                        cid: crate::cid::encode(cid),
+                        missing,
+                    });
+                }
+            }
+            status.stored.push(entry);
+        }
     }
 
-    if unlink {
-        std::fs::remove_file(local_file)?;
+    Ok(status)
+}
+
+/// CID of a local file, without uploading it
+pub fn hash_file(local_file: &Path) -> Result<Vec<u8>> {
+    let mut sha_context = crate::cid::new_digest_context();
+    for c in crate::ChunkedReader::new(File::open(local_file)?) {
+        sha_context.update(&c?);
     }
+    Ok(crate::cid::wrap_digest(sha_context.finish())?)
+}
 
-    Ok(())
+/// Tags adding a file gives it: the ones asked for and, when a field map is
+/// given, those of its sidecars
+pub fn file_tags(
+    local_file: &Path,
+    init_tags: &[crate::proto::Tag],
+    sidecars: Option<&FieldMap>,
+) -> Result<Vec<crate::proto::Tag>> {
+    let mut tags = init_tags.to_vec();
+    if let Some(field_map) = sidecars {
+        for sidecar in metadata::sidecar_paths(local_file) {
+            let fields = metadata::read_sidecar(&sidecar).map_err(|e| {
+                anyhow::anyhow!("Error reading {}: {}", sidecar.display(), e)
+            })?;
+            for tag in field_map.tags(&fields) {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+    }
+    Ok(tags)
 }
 
-pub fn stream_dir_to_remote_filestore(
-    client: ControlClient<Channel>,
+/// A file adding a directory would upload
+struct DirFile {
+    path: PathBuf,
+    size: u64,
+    tags: Vec<crate::proto::Tag>,
+}
+
+/// The files under `local_dir` that adding it would upload, with the tags
+/// each would get. Sidecars are all read up front, before any upload can
+/// unlink one that several files share; files with unreadable sidecars are
+/// reported as failed
+fn list_dir(
     local_dir: &Path,
-    unlink: bool,
-    cont_inue: bool,
-    init_tags: Vec<crate::proto::Tag>,
-) -> BoxFuture<Result<()>> {
-    Box::pin(async move {
-        for entry in std::fs::read_dir(local_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                stream_dir_to_remote_filestore(
-                    client.clone(),
-                    &path,
-                    unlink,
-                    cont_inue,
-                    init_tags.clone(),
-                )
-                .await?;
-            } else {
-                stream_file_to_remote_filestore(
-                    client.clone(),
-                    &path,
-                    unlink,
-                    cont_inue,
-                    init_tags.clone(),
-                )
-                .await?;
+    options: &AddOptions,
+    skipped: &mut Vec<ReportEntry>,
+    failed: &mut Vec<ReportEntry>,
+) -> Result<Vec<DirFile>> {
+    let mut paths = vec![];
+    let mut visited = HashSet::new();
+    visited.insert(local_dir.canonicalize()?);
+    walk(
+        local_dir,
+        local_dir,
+        0,
+        options,
+        &mut visited,
+        &mut paths,
+        skipped,
+    )?;
+
+    let mut files = vec![];
+    for (path, size) in paths {
+        match file_tags(&path, &options.init_tags, options.sidecars.as_ref()) {
+            Ok(tags) => files.push(DirFile { path, size, tags }),
+            Err(e) => failed.push(ReportEntry {
+                path,
+                cid: None,
+                reason: Some(e.to_string()),
+            }),
+        }
+    }
+    Ok(files)
+}
+
+/// Collect the files under `dir` to upload, with their sizes, noting what is
+/// left out and why
+fn walk(
+    root: &Path,
+    dir: &Path,
+    depth: usize,
+    options: &AddOptions,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<(PathBuf, u64)>,
+    skipped: &mut Vec<ReportEntry>,
+) -> Result<()> {
+    let filter = &options.filter;
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    // Sidecars are uploaded as tags of the file they describe, not as files
+    // of their own
+    let sidecar_paths: HashSet<_> = match options.sidecars {
+        Some(_) => paths
+            .iter()
+            .filter(|p| !p.is_dir())
+            .flat_map(|p| metadata::sidecar_paths(p))
+            .collect(),
+        None => HashSet::new(),
+    };
+
+    for path in paths {
+        if sidecar_paths.contains(&path) {
+            continue;
+        }
+
+        let skip = |reason: &str, skipped: &mut Vec<ReportEntry>| {
+            skipped.push(ReportEntry {
+                path: path.clone(),
+                cid: None,
+                reason: Some(reason.to_string()),
+            })
+        };
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |n| n.starts_with('.'));
+        if hidden && !filter.hidden {
+            skip("hidden", skipped);
+            continue;
+        }
+        if filter
+            .exclude
+            .as_ref()
+            .map_or(false, |g| g.is_match(relative))
+        {
+            skip("excluded", skipped);
+            continue;
+        }
+
+        let mut metadata = std::fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            if filter.symlinks == SymlinkPolicy::Skip {
+                skip("symlink", skipped);
+                continue;
             }
+            metadata = match std::fs::metadata(&path) {
+                Ok(m) => m,
+                Err(_) => {
+                    skip("broken symlink", skipped);
+                    continue;
+                }
+            };
+        }
+
+        if metadata.is_dir() {
+            if filter.max_depth.map_or(false, |max| depth >= max) {
+                skip("deeper than max depth", skipped);
+                continue;
+            }
+            if !visited.insert(path.canonicalize()?) {
+                skip("already walked", skipped);
+                continue;
+            }
+            walk(root, &path, depth + 1, options, visited, files, skipped)?;
+            continue;
         }
-        Ok(())
+
+        if filter
+            .include
+            .as_ref()
+            .map_or(false, |g| !g.is_match(relative))
+        {
+            skip("not included", skipped);
+            continue;
+        }
+        if metadata.len() == 0 {
+            skip("empty", skipped);
+            continue;
+        }
+
+        files.push((path, metadata.len()));
+    }
+
+    Ok(())
+}
+
+/// Stream a file to the filestore and tag it, counting bytes sent toward
+/// `progress`
+pub async fn upload_file(
+    mut client: ControlClient<Channel>,
+    local_file: &Path,
+    tags: Vec<crate::proto::Tag>,
+    progress: Option<Arc<Progress>>,
+) -> Result<Uploaded> {
+    let fh = File::open(local_file)?;
+    let chunks = crate::ChunkedReader::new(fh).map(move |c| {
+        let data = c.unwrap();
+        if let Some(progress) = &progress {
+            progress
+                .bytes_done
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        FileChunk { data }
+    });
+    let mut request = tonic::Request::new(futures_util::stream::iter(chunks));
+    if let Some(name) = local_file.file_name() {
+        request.metadata_mut().insert_bin(
+            FILE_NAME_KEY,
+            MetadataValue::from_bytes(name.to_string_lossy().as_bytes()),
+        );
+    }
+    let reply = client
+        .stream_to_filestore(request)
+        .await
+        .map_err(|e| {
+            anyhow::format_err!(
+                "Error adding {}: {}",
+                local_file.to_string_lossy(),
+                e
+            )
+        })?
+        .into_inner();
+
+    client
+        .tag_cid(crate::proto::TagCidRequest {
+            cid: reply.cid.clone(),
+            tags,
+        })
+        .await?;
+
+    Ok(Uploaded {
+        cid: reply.cid,
+        duplicate: reply.duplicate,
     })
 }
+
+fn remove_with_sidecars(local_file: &Path, sidecars: bool) -> Result<()> {
+    if sidecars {
+        for sidecar in metadata::sidecar_paths(local_file) {
+            match std::fs::remove_file(sidecar) {
+                // Shared with a file removed before this one
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                r => r?,
+            }
+        }
+    }
+    std::fs::remove_file(local_file)?;
+    Ok(())
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            _ => Err(anyhow::anyhow!("Unknown symlink policy \"{}\"", s)),
+        }
+    }
+}
+
+/// Download a file to `dest`, checking that its bytes hash to the CID before
+/// moving it into place. A download cut short is kept next to `dest` and
+/// picked up where it left off next time
+pub async fn download_cid_to_file(
+    mut client: ControlClient<Channel>,
+    cid: Vec<u8>,
+    dest: &Path,
+) -> Result<()> {
+    let mut part_name = dest.as_os_str().to_os_string();
+    part_name.push(".part");
+    let part_path = PathBuf::from(part_name);
+
+    let mut part = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part_path)?;
+
+    // Bytes already on disk count toward the hash but aren't fetched again
+    let mut sha_context = crate::cid::new_digest_context();
+    let mut offset = 0;
+    for c in crate::ChunkedReader::new(File::open(&part_path)?) {
+        let c = c?;
+        offset += c.len() as u64;
+        sha_context.update(&c);
+    }
+
+    let mut chunk_stream = client
+        .content_at_cid(ContentAtCidRequest {
+            cid: cid.clone(),
+            offset: Some(offset),
+        })
+        .await?
+        .into_inner();
+
+    while let Some(m) = chunk_stream.message().await? {
+        sha_context.update(&m.data);
+        part.write_all(&m.data)?;
+    }
+
+    if crate::cid::wrap_digest(sha_context.finish())? != cid {
+        std::fs::remove_file(&part_path)?;
+        return Err(anyhow::anyhow!(
+            "Downloaded {} does not match its CID",
+            crate::cid::encode(cid)
+        ));
+    }
+
+    std::fs::rename(&part_path, dest)?;
+    Ok(())
+}
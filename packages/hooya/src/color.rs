@@ -0,0 +1,233 @@
+use anyhow::Result;
+
+pub type Rgb = [u8; 3];
+
+/// CIE L*a*b* color under the D65 illuminant
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Lab {
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+}
+
+/// One color of an image palette along with the share of the image it covers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Swatch {
+    pub rgb: Rgb,
+    pub lab: Lab,
+    pub weight: f32,
+}
+
+// Iterations are cheap on a downscaled image and palettes settle quickly
+const KMEANS_ITERATIONS: usize = 10;
+
+impl From<Rgb> for Lab {
+    fn from(rgb: Rgb) -> Self {
+        fn linearize(c: u8) -> f32 {
+            let c = f32::from(c) / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+
+        fn f(t: f32) -> f32 {
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        }
+
+        let (r, g, b) =
+            (linearize(rgb[0]), linearize(rgb[1]), linearize(rgb[2]));
+
+        // sRGB -> XYZ, normalized by the D65 white point
+        let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+}
+
+impl Lab {
+    /// CIE76 color difference; ~2.3 is a just-noticeable difference
+    pub fn delta_e(&self, other: &Lab) -> f32 {
+        ((self.l - other.l).powi(2)
+            + (self.a - other.a).powi(2)
+            + (self.b - other.b).powi(2))
+        .sqrt()
+    }
+}
+
+/// Parse a color written as `#aabbcc` (the leading `#` is optional)
+pub fn parse_hex(s: &str) -> Result<Rgb> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(anyhow::anyhow!("Malformed color \"{}\"", s));
+    }
+
+    let mut rgb = [0; 3];
+    for (i, c) in rgb.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+
+    Ok(rgb)
+}
+
+/// Cluster pixels into at most `count` swatches with k-means in Lab space,
+/// ordered from most to least dominant
+pub fn palette(pixels: &[Rgb], count: usize) -> Vec<Swatch> {
+    if pixels.is_empty() || count == 0 {
+        return vec![];
+    }
+
+    let labs: Vec<Lab> = pixels.iter().map(|p| Lab::from(*p)).collect();
+
+    // Deterministic seeding: spread initial centers across the lightness
+    // range so reimporting the same file yields the same palette
+    let mut by_lightness: Vec<usize> = (0..labs.len()).collect();
+    by_lightness.sort_by(|x, y| labs[*x].l.total_cmp(&labs[*y].l));
+    let count = count.min(labs.len());
+    let mut centers: Vec<Lab> = (0..count)
+        .map(|i| labs[by_lightness[(2 * i + 1) * labs.len() / (2 * count)]])
+        .collect();
+
+    let mut assignments = vec![0; labs.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        for (lab, assignment) in labs.iter().zip(assignments.iter_mut()) {
+            *assignment = nearest(&centers, lab);
+        }
+
+        let mut sums = vec![(0.0, 0.0, 0.0, 0usize); count];
+        for (lab, assignment) in labs.iter().zip(&assignments) {
+            let s = &mut sums[*assignment];
+            s.0 += lab.l;
+            s.1 += lab.a;
+            s.2 += lab.b;
+            s.3 += 1;
+        }
+
+        for (center, s) in centers.iter_mut().zip(&sums) {
+            // Empty clusters keep their old center
+            if s.3 > 0 {
+                let n = s.3 as f32;
+                *center = Lab {
+                    l: s.0 / n,
+                    a: s.1 / n,
+                    b: s.2 / n,
+                };
+            }
+        }
+    }
+
+    // Report the mean sRGB of each cluster's members rather than converting
+    // the Lab center back
+    let mut rgb_sums = vec![([0u64; 3], 0u64); count];
+    for (pixel, assignment) in pixels.iter().zip(&assignments) {
+        let s = &mut rgb_sums[*assignment];
+        for (acc, c) in s.0.iter_mut().zip(pixel) {
+            *acc += u64::from(*c);
+        }
+        s.1 += 1;
+    }
+
+    let mut swatches: Vec<Swatch> = centers
+        .iter()
+        .zip(rgb_sums)
+        .filter(|(_, (_, n))| *n > 0)
+        .map(|(lab, (sum, n))| Swatch {
+            rgb: [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8],
+            lab: *lab,
+            weight: n as f32 / pixels.len() as f32,
+        })
+        .collect();
+    swatches.sort_by(|x, y| y.weight.total_cmp(&x.weight));
+
+    swatches
+}
+
+/// How far apart two palettes are: the mean ΔE from each swatch of `probe` to
+/// the closest color of `other`, weighted by how much of the image the swatch
+/// covers
+pub fn palette_distance(probe: &[Swatch], other: &[Lab]) -> f32 {
+    if other.is_empty() {
+        return f32::INFINITY;
+    }
+
+    let total_weight: f32 = probe.iter().map(|s| s.weight).sum();
+    if total_weight == 0.0 {
+        return f32::INFINITY;
+    }
+
+    probe
+        .iter()
+        .map(|s| s.weight * s.lab.delta_e(&other[nearest(other, &s.lab)]))
+        .sum::<f32>()
+        / total_weight
+}
+
+fn nearest(centers: &[Lab], lab: &Lab) -> usize {
+    centers
+        .iter()
+        .enumerate()
+        .min_by(|(_, x), (_, y)| x.delta_e(lab).total_cmp(&y.delta_e(lab)))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(lab: Lab, l: f32, a: f32, b: f32) {
+        let expected = Lab { l, a, b };
+        assert!(lab.delta_e(&expected) < 0.5, "{:?} != {:?}", lab, expected);
+    }
+
+    #[test]
+    fn lab_of_srgb() {
+        assert_close(Lab::from([0, 0, 0]), 0.0, 0.0, 0.0);
+        assert_close(Lab::from([255, 255, 255]), 100.0, 0.0, 0.0);
+        assert_close(Lab::from([255, 0, 0]), 53.24, 80.09, 67.2);
+        assert_close(Lab::from([0, 0, 255]), 32.3, 79.19, -107.86);
+    }
+
+    #[test]
+    fn cie76_distance() {
+        let black = Lab::from([0, 0, 0]);
+        let white = Lab::from([255, 255, 255]);
+
+        assert!((black.delta_e(&white) - 100.0).abs() < 0.5);
+        assert_eq!(black.delta_e(&white), white.delta_e(&black));
+        assert_eq!(white.delta_e(&white), 0.0);
+
+        let a = Lab {
+            l: 50.0,
+            a: 3.0,
+            b: 0.0,
+        };
+        let b = Lab {
+            l: 50.0,
+            a: 0.0,
+            b: 4.0,
+        };
+        assert_eq!(a.delta_e(&b), 5.0);
+    }
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(parse_hex("#aabbcc").unwrap(), [0xaa, 0xbb, 0xcc]);
+        assert_eq!(parse_hex("00ff10").unwrap(), [0, 0xff, 0x10]);
+        assert!(parse_hex("#abc").is_err());
+        assert!(parse_hex("#gggggg").is_err());
+    }
+}
@@ -1,54 +1,378 @@
-use std::io::{BufReader, Read, Seek};
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use anyhow::Result;
 use exif::Exif;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::{WebPDecoder, WebPEncoder, WebPQuality};
+use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
-use image::{DynamicImage, ImageFormat};
+use image::{
+    AnimationDecoder, ColorType, Delay, DynamicImage, Frame, ImageEncoder,
+    ImageError, ImageFormat, Rgb, RgbImage, RgbaImage,
+};
 
+use crate::analysis::Unsupported;
+use crate::color::{self, Swatch};
+
+// Palettes are computed from a copy no larger than this on either edge
+const PALETTE_SAMPLE_EDGE: u32 = 64;
+
+// Placeholders only hold a few components so a tiny copy is plenty
+const BLURHASH_SAMPLE_EDGE: u32 = 32;
+
+// Stop decoding animations after this many frames to bound decoding time;
+// anything longer is thumbnailed from its beginning only
+const MAX_SOURCE_FRAMES: usize = 1000;
+
+// Animated thumbnails keep at most this many frames, merging the delays of
+// frames that are dropped so playback runs at the same overall speed. No
+// more than this are held in memory while decoding
+pub const MAX_ANIMATED_THUMB_FRAMES: usize = 60;
+
+/// Encoding of still thumbnails
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    #[default]
+    Jpeg,
+    WebP,
+    Png,
+}
+
+/// How still thumbnails of sources with transparent pixels are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transparency {
+    /// Keep the alpha channel, writing this format instead when the
+    /// configured one has none
+    Keep(ThumbnailFormat),
+    /// Composite onto a solid color and write the configured format
+    Flatten(color::Rgb),
+}
+
+/// A still thumbnail as it was written
+pub struct StillThumbnail {
+    pub height: u32,
+    pub width: u32,
+    pub format: ThumbnailFormat,
+    pub has_alpha: bool,
+}
+
+/// Capture details worth keeping from an image's EXIF data
+#[derive(Debug, Default)]
+pub struct ExifInfo {
+    // YYYY-MM-DD HH:MM:SS, in whatever timezone the camera was set to
+    pub taken: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    // As written by photographers, eg 1/250
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    // Millimeters
+    pub focal_length: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl ThumbnailFormat {
+    pub fn mimetype(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::WebP => "image/webp",
+            ThumbnailFormat::Png => "image/png",
+        }
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        !matches!(self, ThumbnailFormat::Jpeg)
+    }
+}
+
+impl Default for Transparency {
+    fn default() -> Self {
+        Transparency::Keep(ThumbnailFormat::WebP)
+    }
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jpeg" | "jpg" => Ok(ThumbnailFormat::Jpeg),
+            "webp" => Ok(ThumbnailFormat::WebP),
+            "png" => Ok(ThumbnailFormat::Png),
+            _ => Err(anyhow::anyhow!("Unknown thumbnail format \"{}\"", s)),
+        }
+    }
+}
+
+/// Write a still thumbnail. Quality (1-100) applies to the lossy formats.
+/// Sources with transparent pixels are handled as `transparency` says, so
+/// the format written may not be the one asked for
 pub fn thumbnail(
     in_image: &DynamicImage,
-    exif_data: Option<&Exif>,
     out_file: &PathBuf,
     long_edge: u32,
+    format: ThumbnailFormat,
+    quality: u8,
+    transparency: Transparency,
+) -> Result<StillThumbnail> {
+    let mut thumb = in_image.thumbnail(long_edge, long_edge);
+    let (width, height) = (thumb.width(), thumb.height());
+
+    let mut format = format;
+    let mut has_alpha = has_transparency(&thumb);
+    if has_alpha {
+        match transparency {
+            Transparency::Keep(alpha_format) if !format.has_alpha() => {
+                format = alpha_format
+            }
+            Transparency::Keep(_) => {}
+            Transparency::Flatten(background) => {
+                thumb = flatten(&thumb, background);
+                has_alpha = false;
+            }
+        }
+    }
+
+    let writer = BufWriter::new(File::create(out_file)?);
+
+    match format {
+        ThumbnailFormat::Jpeg => JpegEncoder::new_with_quality(writer, quality)
+            .encode_image(&thumb.into_rgb8())?,
+        ThumbnailFormat::WebP => {
+            WebPEncoder::new_with_quality(writer, WebPQuality::lossy(quality))
+                .encode(&thumb.into_rgba8(), width, height, ColorType::Rgba8)?
+        }
+        ThumbnailFormat::Png => PngEncoder::new(writer).write_image(
+            &thumb.into_rgba8(),
+            width,
+            height,
+            ColorType::Rgba8,
+        )?,
+    }
+
+    Ok(StillThumbnail {
+        height,
+        width,
+        format,
+        has_alpha,
+    })
+}
+
+/// BlurHash of an image, a ~30 character string clients can decode into a
+/// blurred preview while the thumbnail loads
+pub fn blurhash(img: &DynamicImage) -> Result<String> {
+    let sample = img
+        .thumbnail(BLURHASH_SAMPLE_EDGE, BLURHASH_SAMPLE_EDGE)
+        .into_rgba8();
+    let (width, height) = sample.dimensions();
+
+    // More components along the longer edge
+    let (components_x, components_y) =
+        if width >= height { (4, 3) } else { (3, 4) };
+
+    blurhash::encode(components_x, components_y, width, height, &sample)
+        .map_err(|e| anyhow::anyhow!("Could not compute BlurHash: {}", e))
+}
+
+/// Whether any pixel of an image is at all see-through
+pub fn has_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.to_rgba8().pixels().any(|p| p[3] < u8::MAX)
+}
+
+/// Composite an image onto a solid background, dropping its alpha channel
+fn flatten(img: &DynamicImage, background: color::Rgb) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let flat = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let alpha = u32::from(p[3]);
+        let blend = |fg: u8, bg: u8| {
+            ((u32::from(fg) * alpha + u32::from(bg) * (255 - alpha) + 127)
+                / 255) as u8
+        };
+
+        Rgb([
+            blend(p[0], background[0]),
+            blend(p[1], background[1]),
+            blend(p[2], background[2]),
+        ])
+    });
+
+    DynamicImage::ImageRgb8(flat)
+}
+
+/// Write an animated GIF thumbnail from the frames of an animation
+pub fn animated_thumbnail(
+    frames: &[Frame],
+    out_file: &Path,
+    long_edge: u32,
 ) -> Result<(u32, u32)> {
-    let thumb = in_image.thumbnail(long_edge, long_edge);
-    let transformed = match exif_data {
-        Some(exif_data) => match exif_data
-            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
-        {
-            Some(orientation) => match orientation.value.get_uint(0) {
-                // There are more but these are most common
-                Some(1) => thumb,
-                Some(3) => thumb.rotate180(),
-                Some(6) => thumb.rotate90(),
-                Some(8) => thumb.rotate270(),
-                _ => thumb,
-            },
-            None => thumb,
-        },
-        None => thumb,
-    }
-    .into_rgba8();
-
-    transformed.save_with_format(out_file, ImageFormat::Jpeg)?;
-
-    Ok((transformed.height(), transformed.width()))
-}
-
-pub fn read(
-    local_file: &PathBuf,
+    let step = (frames.len() + MAX_ANIMATED_THUMB_FRAMES - 1)
+        / MAX_ANIMATED_THUMB_FRAMES;
+
+    let mut sampled = Vec::with_capacity(MAX_ANIMATED_THUMB_FRAMES);
+    for chunk in frames.chunks(step.max(1)) {
+        let delay_ms: f64 = chunk
+            .iter()
+            .map(|f| {
+                let (numer, denom) = f.delay().numer_denom_ms();
+                f64::from(numer) / f64::from(denom)
+            })
+            .sum();
+
+        let thumb = DynamicImage::ImageRgba8(chunk[0].buffer().clone())
+            .thumbnail(long_edge, long_edge)
+            .into_rgba8();
+
+        sampled.push(Frame::from_parts(
+            thumb,
+            0,
+            0,
+            Delay::from_numer_denom_ms(delay_ms.round() as u32, 1),
+        ));
+    }
+
+    let (width, height) = match sampled.first() {
+        Some(f) => f.buffer().dimensions(),
+        None => return Err(anyhow::anyhow!("Animation has no frames")),
+    };
+
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(out_file)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(sampled)?;
+
+    Ok((height, width))
+}
+
+/// Frames of an animated GIF, APNG or WebP, each composited onto the full
+/// canvas and shrunk to fit within `long_edge`. Long animations are sampled
+/// down to `MAX_ANIMATED_THUMB_FRAMES` as they are decoded, each kept frame
+/// taking on the delays of the frames dropped after it. Still images
+/// (including single-frame animations) give None
+pub fn read_frames<R: BufRead + Seek>(
+    mut b_reader: R,
     mimetype: &str,
-) -> Result<(DynamicImage, Option<exif::Exif>)> {
-    let fh = std::fs::File::open(local_file)?;
-    let format = ImageFormat::from_mime_type(mimetype).unwrap();
+    long_edge: u32,
+) -> Result<Option<Vec<Frame>>> {
+    let frames = match mimetype {
+        "image/gif" => GifDecoder::new(b_reader.by_ref())?.into_frames(),
+        "image/png" | "image/apng" => {
+            let decoder = PngDecoder::new(b_reader.by_ref())?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        "image/webp" => {
+            let decoder = WebPDecoder::new(b_reader.by_ref())?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    // Every `stride`th source frame is kept. When too many are kept, every
+    // other one is dropped and the stride doubles, so memory stays bounded
+    // however long the animation
+    let mut kept: Vec<(RgbaImage, f64)> = vec![];
+    let mut stride = 1;
+    for (i, f) in frames.take(MAX_SOURCE_FRAMES).enumerate() {
+        let f = f?;
+        let (numer, denom) = f.delay().numer_denom_ms();
+        let delay_ms = f64::from(numer) / f64::from(denom);
+
+        if i % stride != 0 {
+            if let Some((_, d)) = kept.last_mut() {
+                *d += delay_ms;
+            }
+            continue;
+        }
+
+        let (width, height) = f.buffer().dimensions();
+        let buffer = if width <= long_edge && height <= long_edge {
+            f.into_buffer()
+        } else {
+            DynamicImage::ImageRgba8(f.into_buffer())
+                .thumbnail(long_edge, long_edge)
+                .into_rgba8()
+        };
+        kept.push((buffer, delay_ms));
+
+        if kept.len() > MAX_ANIMATED_THUMB_FRAMES {
+            kept = halve(kept);
+            stride *= 2;
+        }
+    }
 
-    let mut b_reader = BufReader::new(fh);
+    let frames: Vec<Frame> = kept
+        .into_iter()
+        .map(|(buffer, delay_ms)| {
+            Frame::from_parts(
+                buffer,
+                0,
+                0,
+                Delay::from_numer_denom_ms(delay_ms.round() as u32, 1),
+            )
+        })
+        .collect();
 
-    let decoded_data = {
-        let mut reader = ImageReader::new(b_reader.by_ref());
-        reader.set_format(format);
-        reader.decode()?
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    Ok(Some(frames))
+}
+
+/// Keep every other frame, adding the delay of each dropped one to the frame
+/// before it
+fn halve(frames: Vec<(RgbaImage, f64)>) -> Vec<(RgbaImage, f64)> {
+    let mut halved: Vec<(RgbaImage, f64)> =
+        Vec::with_capacity(frames.len() / 2 + 1);
+    for (i, (buffer, delay_ms)) in frames.into_iter().enumerate() {
+        match halved.last_mut() {
+            Some((_, d)) if i % 2 == 1 => *d += delay_ms,
+            _ => halved.push((buffer, delay_ms)),
+        }
+    }
+    halved
+}
+
+/// Decode an image and its EXIF data. The image is turned upright according
+/// to its EXIF orientation
+pub fn read<R: BufRead + Seek>(
+    mut b_reader: R,
+    mimetype: &str,
+) -> Result<(DynamicImage, Option<exif::Exif>)> {
+    let decoded_data = match mimetype {
+        #[cfg(feature = "heif")]
+        "image/heif" | "image/heic" => decode_heif(b_reader.by_ref())?,
+        #[cfg(feature = "jxl")]
+        "image/jxl" => decode_jxl(b_reader.by_ref())?,
+        _ => {
+            let format = source_format(mimetype).ok_or_else(|| {
+                Unsupported(format!("No decoder for {}", mimetype))
+            })?;
+
+            let mut reader = ImageReader::new(b_reader.by_ref());
+            reader.set_format(format);
+            match reader.decode() {
+                Ok(d) => d,
+                // Formats the image crate knows of but was built without
+                Err(ImageError::Unsupported(e)) => {
+                    return Err(Unsupported(e.to_string()).into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
     };
 
     let exif_data = {
@@ -58,5 +382,269 @@ pub fn read(
             .ok()
     };
 
+    let decoded_data = match exif_data.as_ref() {
+        Some(e) => orient(decoded_data, orientation(e)),
+        None => decoded_data,
+    };
+
     Ok((decoded_data, exif_data))
 }
+
+/// Format to decode a source mimetype as, as far as the image crate is
+/// concerned. Some formats go by more than one mimetype
+fn source_format(mimetype: &str) -> Option<ImageFormat> {
+    match mimetype {
+        "image/vnd.microsoft.icon" | "image/x-icon" => Some(ImageFormat::Ico),
+        "image/apng" => Some(ImageFormat::Png),
+        "image/x-portable-anymap" => Some(ImageFormat::Pnm),
+        _ => ImageFormat::from_mime_type(mimetype),
+    }
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif<R: Read>(mut reader: R) -> Result<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let mut buf = vec![];
+    reader.read_to_end(&mut buf)?;
+
+    let ctx = HeifContext::read_from_bytes(&buf)?;
+    let handle = ctx.primary_image_handle()?;
+    let decoded = LibHeif::new().decode(
+        &handle,
+        ColorSpace::Rgb(RgbChroma::Rgba),
+        None,
+    )?;
+
+    let planes = decoded.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| anyhow::anyhow!("HEIF image has no RGBA plane"))?;
+
+    // Rows may be padded past their width
+    let row_len = plane.width as usize * 4;
+    let data = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+
+    let img = image::RgbaImage::from_raw(plane.width, plane.height, data)
+        .ok_or_else(|| anyhow::anyhow!("Malformed HEIF image"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+#[cfg(feature = "jxl")]
+fn decode_jxl<R: Read>(reader: R) -> Result<DynamicImage> {
+    let jxl = jxl_oxide::JxlImage::from_reader(reader)?;
+    let render = jxl.render_frame(0)?;
+    let fb = render.image();
+
+    let (width, height, channels) = (fb.width(), fb.height(), fb.channels());
+    let to_u8 = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    // Samples are interleaved floats; grayscale and RGB get an opaque alpha
+    let data = fb
+        .buf()
+        .chunks(channels)
+        .flat_map(|px| match px.len() {
+            1 => [px[0], px[0], px[0], 1.0],
+            2 => [px[0], px[0], px[0], px[1]],
+            3 => [px[0], px[1], px[2], 1.0],
+            _ => [px[0], px[1], px[2], px[3]],
+        })
+        .map(to_u8)
+        .collect();
+
+    let img = image::RgbaImage::from_raw(width as u32, height as u32, data)
+        .ok_or_else(|| anyhow::anyhow!("Malformed JPEG XL image"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+/// EXIF orientation, 1 (upright) through 8
+pub fn orientation(exif_data: &Exif) -> u32 {
+    exif_data
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .filter(|o| (1..=8).contains(o))
+        .unwrap_or(1)
+}
+
+/// Undo the transform described by an EXIF orientation
+pub fn orient(in_image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => in_image.fliph(),
+        3 => in_image.rotate180(),
+        4 => in_image.flipv(),
+        // Transpose
+        5 => in_image.rotate90().fliph(),
+        6 => in_image.rotate90(),
+        // Transverse
+        7 => in_image.rotate270().fliph(),
+        8 => in_image.rotate270(),
+        _ => in_image,
+    }
+}
+
+pub fn exif_info(exif_data: &Exif) -> ExifInfo {
+    let field = |tag| exif_data.get_field(tag, exif::In::PRIMARY);
+
+    let text = |tag| match field(tag).map(|f| &f.value) {
+        Some(exif::Value::Ascii(v)) => v
+            .first()
+            .map(|s| {
+                String::from_utf8_lossy(s)
+                    .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                    .to_string()
+            })
+            .filter(|s| !s.is_empty()),
+        _ => None,
+    };
+
+    let rational = |tag| match field(tag).map(|f| &f.value) {
+        Some(exif::Value::Rational(v)) => {
+            v.first().map(|r| r.to_f64()).filter(|r| r.is_finite())
+        }
+        _ => None,
+    };
+
+    // Degrees, minutes and seconds, negated for the southern and western
+    // hemispheres
+    let coordinate = |tag, ref_tag, negative_ref| match field(tag)
+        .map(|f| &f.value)
+    {
+        Some(exif::Value::Rational(v)) if v.len() == 3 => {
+            let degrees =
+                v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0;
+            if !degrees.is_finite() {
+                return None;
+            }
+
+            match text(ref_tag) {
+                Some(r) if r == negative_ref => Some(-degrees),
+                _ => Some(degrees),
+            }
+        }
+        _ => None,
+    };
+
+    let taken = match field(exif::Tag::DateTimeOriginal).map(|f| &f.value) {
+        Some(exif::Value::Ascii(v)) => v
+            .first()
+            .and_then(|s| exif::DateTime::from_ascii(s).ok())
+            .map(|d| {
+                format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    d.year, d.month, d.day, d.hour, d.minute, d.second
+                )
+            }),
+        _ => None,
+    };
+
+    ExifInfo {
+        taken,
+        camera_make: text(exif::Tag::Make),
+        camera_model: text(exif::Tag::Model),
+        lens: text(exif::Tag::LensModel),
+        exposure_time: field(exif::Tag::ExposureTime)
+            .map(|f| f.display_value().to_string()),
+        f_number: rational(exif::Tag::FNumber),
+        iso: field(exif::Tag::PhotographicSensitivity)
+            .and_then(|f| f.value.get_uint(0)),
+        focal_length: rational(exif::Tag::FocalLength),
+        latitude: coordinate(
+            exif::Tag::GPSLatitude,
+            exif::Tag::GPSLatitudeRef,
+            "S",
+        ),
+        longitude: coordinate(
+            exif::Tag::GPSLongitude,
+            exif::Tag::GPSLongitudeRef,
+            "W",
+        ),
+    }
+}
+
+/// Dominant colors of an image, most dominant first. Mostly transparent
+/// pixels are not counted
+pub fn palette(in_image: &DynamicImage, count: usize) -> Vec<Swatch> {
+    let pixels: Vec<color::Rgb> = in_image
+        .thumbnail(PALETTE_SAMPLE_EDGE, PALETTE_SAMPLE_EDGE)
+        .into_rgba8()
+        .pixels()
+        .filter(|p| p[3] >= 128)
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+
+    color::palette(&pixels, count)
+}
+
+/// Difference hash: one bit per horizontally adjacent pair of pixels in a
+/// 9x8 grayscale copy, set when brightness increases left to right
+pub fn dhash(in_image: &DynamicImage) -> u64 {
+    let small = in_image
+        .resize_exact(9, 8, FilterType::Triangle)
+        .into_luma8();
+
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash
+}
+
+/// DCT-based perceptual hash: one bit per low-frequency coefficient of a
+/// 32x32 grayscale copy, set when above the median coefficient. Robust to
+/// rescaling, recompression and small color adjustments
+pub fn phash(in_image: &DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    const LOW_FREQ: usize = 8;
+
+    let small = in_image
+        .resize_exact(SIZE as u32, SIZE as u32, FilterType::Triangle)
+        .into_luma8();
+    let pixels: Vec<f64> = small.pixels().map(|p| f64::from(p[0])).collect();
+
+    let cosines: Vec<f64> = (0..LOW_FREQ * SIZE)
+        .map(|i| {
+            let (u, x) = (i / SIZE, i % SIZE);
+            (((2 * x + 1) * u) as f64 * std::f64::consts::PI
+                / (2 * SIZE) as f64)
+                .cos()
+        })
+        .collect();
+
+    // Only the top-left 8x8 block of the DCT-II is needed
+    let mut coefficients = Vec::with_capacity(LOW_FREQ * LOW_FREQ);
+    for v in 0..LOW_FREQ {
+        for u in 0..LOW_FREQ {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    sum += pixels[y * SIZE + x]
+                        * cosines[u * SIZE + x]
+                        * cosines[v * SIZE + y];
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    // The DC term only reflects average brightness so leave it out of the
+    // median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|x, y| x.total_cmp(y));
+    let median = sorted[sorted.len() / 2];
+
+    coefficients
+        .iter()
+        .fold(0, |hash, c| (hash << 1) | u64::from(*c > median))
+}
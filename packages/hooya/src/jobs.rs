@@ -0,0 +1,81 @@
+use anyhow::Result;
+use std::str::FromStr;
+
+/// Deferred work on a single file, run by hooyad's worker pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    /// Decode a freshly stored file to fill in its type-specific details
+    /// (dimensions, palette, hashes, thumbnails, ...)
+    Analyze,
+    /// Index and analyze a stored file again from scratch, as part of a
+    /// reimport run
+    Reimport,
+    /// Remake a file's thumbnails if they don't match the configured sizes
+    /// and format
+    RegenerateThumbnails,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    /// Whether the job will not change state again without being retried
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed)
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "analyze" => Ok(JobKind::Analyze),
+            "reimport" => Ok(JobKind::Reimport),
+            "regenerate_thumbnails" => Ok(JobKind::RegenerateThumbnails),
+            _ => Err(anyhow::anyhow!("Unknown job kind \"{}\"", s)),
+        }
+    }
+}
+
+impl ToString for JobKind {
+    fn to_string(&self) -> String {
+        match self {
+            JobKind::Analyze => "analyze",
+            JobKind::Reimport => "reimport",
+            JobKind::RegenerateThumbnails => "regenerate_thumbnails",
+        }
+        .to_string()
+    }
+}
+
+impl FromStr for JobState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(JobState::Pending),
+            "running" => Ok(JobState::Running),
+            "done" => Ok(JobState::Done),
+            "failed" => Ok(JobState::Failed),
+            _ => Err(anyhow::anyhow!("Unknown job state \"{}\"", s)),
+        }
+    }
+}
+
+impl ToString for JobState {
+    fn to_string(&self) -> String {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+        .to_string()
+    }
+}
@@ -6,11 +6,33 @@ mod chunked_reader;
 
 pub use chunked_reader::*;
 
+pub mod analysis;
+pub mod archive;
+pub mod audio;
+pub mod bktree;
 pub mod cid;
 pub mod client;
+pub mod color;
 pub mod image;
+pub mod jobs;
 pub mod local;
+pub mod metadata;
+pub mod query;
 pub mod runtime;
+pub mod thumb_cache;
+pub mod video;
+pub mod watch;
+
+impl proto::file::ExtFile {
+    pub fn thumbnails(&self) -> &[proto::Thumbnail] {
+        match self {
+            proto::file::ExtFile::Image(i) => &i.thumbnails,
+            proto::file::ExtFile::Video(v) => &v.thumbnails,
+            proto::file::ExtFile::Audio(a) => &a.thumbnails,
+            proto::file::ExtFile::Document(d) => &d.thumbnails,
+        }
+    }
+}
 
 impl From<&str> for proto::Tag {
     fn from(tag_str: &str) -> Self {
@@ -29,3 +51,53 @@ impl ToString for proto::Tag {
     }
 }
 
+/// File extension to save a file of the given mimetype under
+pub fn mimetype_extension(mimetype: &str) -> Option<String> {
+    let ext = match mimetype {
+        "image/jpeg" => "jpeg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        "image/tiff" => "tiff",
+        "image/avif" => "avif",
+        "image/heif" => "heif",
+        "image/jxl" => "jxl",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "video/quicktime" => "mov",
+        "audio/mpeg" => "mp3",
+        "audio/x-flac" => "flac",
+        "audio/ogg" => "ogg",
+        "audio/opus" => "opus",
+        "audio/m4a" => "m4a",
+        "audio/x-wav" => "wav",
+        "application/zip" => "zip",
+        "application/pdf" => "pdf",
+        _ => return None,
+    };
+    Some(ext.to_string())
+}
+
+/// Size in bytes with a binary unit, eg 1.50MiB
+pub fn human_readable_size(size: i64) -> String {
+    const SIZE_TRANSLATION: [(i64, &str); 4] =
+        [(4, "TiB"), (3, "GiB"), (2, "MiB"), (1, "KiB")];
+
+    for (power, label) in SIZE_TRANSLATION {
+        let dividend: i64 = 1 << (10 * power);
+        let div_res = size as f64 / dividend as f64;
+        if div_res.floor() >= 1.0 {
+            return format!("{:.2}{}", div_res, label);
+        }
+    }
+
+    format!("{}B", size)
+}
+
+/// Seconds as minutes and seconds, eg 3:07
+pub fn duration_label(duration: f32) -> String {
+    let secs = duration.round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
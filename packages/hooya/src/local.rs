@@ -2,8 +2,13 @@ use anyhow::Result;
 use sqlx::{
     sqlite::SqliteRow, Executor, QueryBuilder, Row, Sqlite, SqlitePool,
 };
+use std::collections::HashMap;
 
 use crate::proto::Tag;
+use crate::query::{Query, Sort, Term};
+
+// Most parameters a statement may bind, in SQLite before 3.32
+const SQLITE_MAX_VARIABLES: usize = 999;
 
 pub struct TagRow {
     pub id: i32,
@@ -32,6 +37,52 @@ pub struct ImageRow {
     pub ratio: f64,
     pub primary_color: Vec<u8>,
     pub colors: Vec<u8>,
+    pub blurhash: Option<String>,
+}
+
+pub struct ImageExifRow {
+    pub cid: Vec<u8>,
+    pub taken: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+pub struct VideoRow {
+    pub cid: Vec<u8>,
+    pub height: u32,
+    pub width: u32,
+    pub ratio: f64,
+    pub duration: f64,
+    pub video_codec: String,
+    pub audio_codec: Option<String>,
+    pub frame_rate: f64,
+}
+
+pub struct AudioRow {
+    pub cid: Vec<u8>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: f64,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+}
+
+pub struct PageRow {
+    pub cid: Vec<u8>,
+    // 0-based, in reading order
+    pub page: u32,
+    pub name: String,
+    pub mimetype: String,
+    pub width: u32,
+    pub height: u32,
 }
 
 pub struct ThumbnailRow {
@@ -43,6 +94,57 @@ pub struct ThumbnailRow {
     pub width: i64,
     pub ratio: f64,
     pub is_animated: bool,
+    pub has_alpha: bool,
+}
+
+pub struct ImageColorRow {
+    pub cid: Vec<u8>,
+    pub rank: u32,
+    pub l: f32,
+    pub a: f32,
+    pub b: f32,
+    pub weight: f32,
+}
+
+pub struct ImageHashRow {
+    pub cid: Vec<u8>,
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+pub struct SavedSearchRow {
+    pub id: i64,
+    pub name: String,
+    pub query: String,
+    pub sort: String,
+    pub owner: String,
+    pub last_viewed: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct JobRow {
+    pub id: i64,
+    pub kind: String,
+    pub cid: Vec<u8>,
+    pub state: String,
+    pub error: Option<String>,
+    pub attempts: u32,
+    pub created: String,
+    pub updated: String,
+}
+
+pub struct ReimportRunRow {
+    pub id: i64,
+    pub query: String,
+    pub started: String,
+    pub finished: Option<String>,
+}
+
+pub struct AnalysisRow {
+    pub cid: Vec<u8>,
+    pub status: String,
+    pub reason: Option<String>,
+    pub updated: String,
 }
 
 pub struct Db {
@@ -101,6 +203,74 @@ impl Db {
             Ratio REAL NOT NULL,
             PrimaryColor BINARY(3) DEFAULT NULL,
             Colors VARBINARY DEFAULT NULL,
+            Blurhash TEXT DEFAULT NULL,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+        self.add_missing_column("Images", "Blurhash", "TEXT DEFAULT NULL")
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS ImageExif (
+            Cid VARBINARY NOT NULL PRIMARY KEY,
+            Taken DATETIME DEFAULT NULL,
+            CameraMake TEXT DEFAULT NULL,
+            CameraModel TEXT DEFAULT NULL,
+            Lens TEXT DEFAULT NULL,
+            ExposureTime TEXT DEFAULT NULL,
+            FNumber REAL DEFAULT NULL,
+            Iso INTEGER UNSIGNED DEFAULT NULL,
+            FocalLength REAL DEFAULT NULL,
+            Latitude REAL DEFAULT NULL,
+            Longitude REAL DEFAULT NULL,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS Videos (
+            Cid VARBINARY NOT NULL PRIMARY KEY,
+            Height INTEGER UNSIGNED NOT NULL,
+            Width INTEGER UNSIGNED NOT NULL,
+            Ratio REAL NOT NULL,
+            Duration REAL NOT NULL,
+            VideoCodec TEXT NOT NULL,
+            AudioCodec TEXT DEFAULT NULL,
+            FrameRate REAL NOT NULL,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS Audio (
+            Cid VARBINARY NOT NULL PRIMARY KEY,
+            Title TEXT DEFAULT NULL,
+            Artist TEXT DEFAULT NULL,
+            Album TEXT DEFAULT NULL,
+            Duration REAL NOT NULL,
+            SampleRate INTEGER UNSIGNED DEFAULT NULL,
+            Channels INTEGER UNSIGNED DEFAULT NULL,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS ArchivePages (
+            Cid VARBINARY NOT NULL,
+            Page INTEGER UNSIGNED NOT NULL,
+            Name TEXT NOT NULL,
+            Mimetype TEXT NOT NULL,
+            Width INTEGER UNSIGNED NOT NULL,
+            Height INTEGER UNSIGNED NOT NULL,
+            PRIMARY KEY (Cid, Page),
             FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
             )
             .await?;
@@ -117,9 +287,141 @@ impl Db {
             Width INTEGER UNSIGNED NOT NULL,
             Ratio REAL NOT NULL,
             IsAnimated BOOLEAN DEFAULT FALSE NOT NULL,
+            HasAlpha BOOLEAN DEFAULT FALSE NOT NULL,
             FOREIGN KEY (SourceCid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
             )
             .await?;
+        self.add_missing_column(
+            "Thumbnails",
+            "HasAlpha",
+            "BOOLEAN DEFAULT FALSE NOT NULL",
+        )
+        .await?;
+
+        // Palette colors in Lab so color distance can be computed in queries
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS ImageColors (
+            Cid VARBINARY NOT NULL,
+            Rank INTEGER UNSIGNED NOT NULL,
+            L REAL NOT NULL,
+            A REAL NOT NULL,
+            B REAL NOT NULL,
+            Weight REAL NOT NULL,
+            UNIQUE(Cid, Rank),
+            FOREIGN KEY (Cid) REFERENCES Images(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        // Perceptual hashes are stored bit-for-bit as signed integers
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS ImageHashes (
+            Cid VARBINARY NOT NULL PRIMARY KEY,
+            DHash INTEGER NOT NULL,
+            PHash INTEGER NOT NULL,
+            FOREIGN KEY (Cid) REFERENCES Images(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS SavedSearches (
+            Id INTEGER PRIMARY KEY AUTOINCREMENT,
+            Name TEXT NOT NULL,
+            Query TEXT NOT NULL,
+            Sort TEXT NOT NULL,
+            Owner TEXT NOT NULL,
+            Created DATETIME DEFAULT CURRENT_TIMESTAMP,
+            LastViewed DATETIME DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE(Owner, Name))"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS Analysis (
+            Cid VARBINARY NOT NULL PRIMARY KEY,
+            Status TEXT NOT NULL,
+            Reason TEXT DEFAULT NULL,
+            Updated DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS Jobs (
+            Id INTEGER PRIMARY KEY AUTOINCREMENT,
+            Kind TEXT NOT NULL,
+            Cid VARBINARY NOT NULL,
+            State TEXT NOT NULL DEFAULT 'pending',
+            Error TEXT DEFAULT NULL,
+            Attempts INTEGER UNSIGNED NOT NULL DEFAULT 0,
+            Created DATETIME DEFAULT CURRENT_TIMESTAMP,
+            Updated DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                "CREATE INDEX IF NOT EXISTS JobsByState ON Jobs (State, Id)",
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS ReimportRuns (
+            Id INTEGER PRIMARY KEY AUTOINCREMENT,
+            Query TEXT NOT NULL,
+            Started DATETIME DEFAULT CURRENT_TIMESTAMP,
+            Finished DATETIME DEFAULT NULL)"#,
+            )
+            .await?;
+
+        // Files a run has reprocessed, so an interrupted run can pick up
+        // where it stopped
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS ReimportedFiles (
+            RunId INTEGER NOT NULL,
+            Cid VARBINARY NOT NULL,
+            PRIMARY KEY (RunId, Cid),
+            FOREIGN KEY (RunId) REFERENCES ReimportRuns(Id) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS WatchedFiles (
+            Path TEXT PRIMARY KEY NOT NULL,
+            Size INTEGER NOT NULL,
+            Modified INTEGER NOT NULL,
+            Cid VARBINARY NOT NULL,
+            FOREIGN KEY (Cid) REFERENCES Files(Cid) ON DELETE CASCADE)"#,
+            )
+            .await?;
+
+        // Names files were uploaded under. A file is stored before its Files
+        // row is written, so names aren't tied to one
+        self.executor
+            .execute(
+                r#"
+            CREATE TABLE IF NOT EXISTS FileNames (
+            Cid VARBINARY NOT NULL,
+            Name TEXT NOT NULL,
+            UNIQUE(Cid, Name))"#,
+            )
+            .await?;
 
         Ok(())
     }
@@ -196,11 +498,43 @@ impl Db {
         Ok(())
     }
 
+    /// Bring a table created by an older version up to date, as CREATE
+    /// TABLE IF NOT EXISTS leaves existing tables untouched
+    async fn add_missing_column(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<()> {
+        let exists: i64 = sqlx::query(
+            "SELECT COUNT(*) AS Present FROM pragma_table_info(?) WHERE name=?",
+        )
+        .bind(table)
+        .bind(column)
+        .try_map(|r: SqliteRow| r.try_get("Present"))
+        .fetch_one(&self.executor)
+        .await?;
+
+        if exists == 0 {
+            self.executor
+                .execute(
+                    format!(
+                        "ALTER TABLE {} ADD COLUMN {} {}",
+                        table, column, definition
+                    )
+                    .as_str(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn new_thumbnail(&self, thumbnail: ThumbnailRow) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT OR IGNORE INTO Thumbnails (Cid, Size, Mimetype, SourceCid, Height, Width, Ratio, IsAnimated) VALUES
-            (?, ?, ?, ?, ?, ?, ?, ?)"#,
+            INSERT OR IGNORE INTO Thumbnails (Cid, Size, Mimetype, SourceCid, Height, Width, Ratio, IsAnimated, HasAlpha) VALUES
+            (?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
         )
         .bind(thumbnail.cid)
         .bind(thumbnail.size)
@@ -210,6 +544,7 @@ impl Db {
         .bind(thumbnail.width)
         .bind(thumbnail.ratio)
         .bind(thumbnail.is_animated)
+        .bind(thumbnail.has_alpha)
         .execute(&self.executor)
         .await?;
         Ok(())
@@ -229,12 +564,12 @@ impl Db {
     pub async fn new_image(&self, image: ImageRow) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO Images (Cid, Height, Width, Ratio, PrimaryColor, Colors) VALUES
-            (?, ?, ?, ?, ?, ?) ON CONFLICT(Cid)
+            INSERT INTO Images (Cid, Height, Width, Ratio, PrimaryColor, Colors, Blurhash) VALUES
+            (?, ?, ?, ?, ?, ?, ?) ON CONFLICT(Cid)
                 DO UPDATE SET
                 Height=excluded.Height, Width=excluded.Width,
                 Ratio=excluded.Ratio, PrimaryColor=excluded.PrimaryColor,
-                Colors=excluded.Colors"#,
+                Colors=excluded.Colors, Blurhash=excluded.Blurhash"#,
         )
         .bind(image.cid)
         .bind(image.height)
@@ -242,147 +577,509 @@ impl Db {
         .bind(image.ratio)
         .bind(image.primary_color)
         .bind(image.colors)
+        .bind(image.blurhash)
         .execute(&self.executor)
         .await?;
         Ok(())
     }
 
-    pub async fn lookup_tag_id(&self, tags: Vec<Tag>) -> Result<Vec<TagRow>> {
-        if tags.is_empty() {
-            return Ok(vec![]);
-        }
-
-        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
-            r#"SELECT Id, Descriptor, Namespace
-        FROM Tags WHERE (Namespace, Descriptor) IN ("#,
-        );
-        let tags_len = tags.len();
-        for (i, t) in tags.into_iter().enumerate() {
-            if i < tags_len - 1 {
-                builder.push(", ");
-            }
-            builder.push("(");
-            builder.push_bind(t.namespace);
-            builder.push(",");
-            builder.push_bind(t.descriptor);
-            builder.push(")");
-        }
-        builder.push(")");
-        let query = builder.build();
-
-        let tag_rows = query
-            .try_map(|r: SqliteRow| {
-                let descriptor = r.try_get("Descriptor")?;
-                let namespace = r.try_get("Namespace")?;
-                let id = r.try_get("Id")?;
+    pub async fn new_image_exif(&self, exif: ImageExifRow) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO ImageExif (Cid, Taken, CameraMake, CameraModel, Lens, ExposureTime, FNumber, Iso, FocalLength, Latitude, Longitude) VALUES
+            (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(exif.cid)
+        .bind(exif.taken)
+        .bind(exif.camera_make)
+        .bind(exif.camera_model)
+        .bind(exif.lens)
+        .bind(exif.exposure_time)
+        .bind(exif.f_number)
+        .bind(exif.iso)
+        .bind(exif.focal_length)
+        .bind(exif.latitude)
+        .bind(exif.longitude)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
 
-                Ok(TagRow {
-                    id,
-                    descriptor,
-                    namespace,
-                })
-            })
-            .fetch_all(&self.executor)
+    pub async fn delete_image_exif(&self, cid: Vec<u8>) -> Result<()> {
+        sqlx::query("DELETE FROM ImageExif WHERE Cid=?")
+            .bind(cid)
+            .execute(&self.executor)
             .await?;
-
-        Ok(tag_rows)
+        Ok(())
     }
 
-    pub async fn file_row(&self, cid: Vec<u8>) -> Result<FileRow> {
-        let file_row =
-            sqlx::query("SELECT Cid, Mimetype, Size FROM Files WHERE Cid=?")
-                .bind(cid)
-                .try_map(|r: SqliteRow| {
-                    let cid = r.try_get("Cid")?;
-                    let mimetype = r.try_get("Mimetype")?;
-                    let size = r.try_get("Size")?;
-
-                    Ok(FileRow {
-                        cid,
-                        mimetype,
-                        size,
-                    })
-                })
-                .fetch_one(&self.executor)
-                .await?;
-
-        Ok(file_row)
+    pub async fn new_video(&self, video: VideoRow) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO Videos (Cid, Height, Width, Ratio, Duration, VideoCodec, AudioCodec, FrameRate) VALUES
+            (?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT(Cid)
+                DO UPDATE SET
+                Height=excluded.Height, Width=excluded.Width,
+                Ratio=excluded.Ratio, Duration=excluded.Duration,
+                VideoCodec=excluded.VideoCodec, AudioCodec=excluded.AudioCodec,
+                FrameRate=excluded.FrameRate"#,
+        )
+        .bind(video.cid)
+        .bind(video.height)
+        .bind(video.width)
+        .bind(video.ratio)
+        .bind(video.duration)
+        .bind(video.video_codec)
+        .bind(video.audio_codec)
+        .bind(video.frame_rate)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
     }
 
-    pub async fn image_row(&self, cid: Vec<u8>) -> Result<ImageRow> {
-        let row =
-            sqlx::query("SELECT Cid, Height, Width, Ratio, PrimaryColor, Colors FROM Images WHERE Cid=?")
-                .bind(cid)
-                .try_map(|r: SqliteRow| {
-                    let cid = r.try_get("Cid")?;
-                    let height = r.try_get("Height")?;
-                    let width = r.try_get("Width")?;
-                    let ratio = r.try_get("Ratio")?;
-                    let primary_color = r.try_get("PrimaryColor")?;
-                    let colors = r.try_get("Colors")?;
-
-                    Ok(ImageRow {
-                        cid,
-                        height,
-                        width,
-                        ratio,
-                        primary_color,
-                        colors,
-                    })
-                })
-                .fetch_one(&self.executor)
-                .await?;
-
-        Ok(row)
+    pub async fn new_audio(&self, audio: AudioRow) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO Audio (Cid, Title, Artist, Album, Duration, SampleRate, Channels) VALUES
+            (?, ?, ?, ?, ?, ?, ?) ON CONFLICT(Cid)
+                DO UPDATE SET
+                Title=excluded.Title, Artist=excluded.Artist,
+                Album=excluded.Album, Duration=excluded.Duration,
+                SampleRate=excluded.SampleRate, Channels=excluded.Channels"#,
+        )
+        .bind(audio.cid)
+        .bind(audio.title)
+        .bind(audio.artist)
+        .bind(audio.album)
+        .bind(audio.duration)
+        .bind(audio.sample_rate)
+        .bind(audio.channels)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
     }
 
-    pub async fn thumbnails_by_source_cid(
+    pub async fn replace_archive_pages(
         &self,
         cid: Vec<u8>,
-    ) -> Result<Vec<ThumbnailRow>> {
-        let thumbnail_rows = sqlx::query("SELECT Cid, Size, Mimetype, SourceCid, Height, Width, Ratio, IsAnimated FROM Thumbnails WHERE SourceCid=?")
+        pages: &[PageRow],
+    ) -> Result<()> {
+        sqlx::query("DELETE FROM ArchivePages WHERE Cid=?")
             .bind(cid)
-            .try_map(|r: SqliteRow| {
-                let cid = r.try_get("Cid")?;
-                let size = r.try_get("Size")?;
-                let mimetype = r.try_get("Mimetype")?;
-                let source_cid = r.try_get("SourceCid")?;
-                let height = r.try_get("Height")?;
-                let width = r.try_get("Width")?;
-                let ratio = r.try_get("Ratio")?;
-                let is_animated = r.try_get("IsAnimated")?;
+            .execute(&self.executor)
+            .await?;
 
-                Ok(ThumbnailRow {
-                    cid,
-                    size,
-                    mimetype,
-                    source_cid,
-                    height,
-                    width,
-                    ratio,
-                    is_animated,
-                })
+        for p in pages {
+            sqlx::query(
+                r#"
+                INSERT INTO ArchivePages (Cid, Page, Name, Mimetype, Width, Height) VALUES
+                (?, ?, ?, ?, ?, ?)"#,
+            )
+            .bind(p.cid.clone())
+            .bind(p.page)
+            .bind(p.name.clone())
+            .bind(p.mimetype.clone())
+            .bind(p.width)
+            .bind(p.height)
+            .execute(&self.executor)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Pages of an archive in reading order
+    pub async fn archive_pages(&self, cid: Vec<u8>) -> Result<Vec<PageRow>> {
+        let pages = sqlx::query(
+            "SELECT Cid, Page, Name, Mimetype, Width, Height FROM ArchivePages WHERE Cid=? ORDER BY Page",
+        )
+        .bind(cid)
+        .try_map(page_row)
+        .fetch_all(&self.executor)
+        .await?;
+
+        Ok(pages)
+    }
+
+    pub async fn archive_page(
+        &self,
+        cid: Vec<u8>,
+        page: u32,
+    ) -> Result<Option<PageRow>> {
+        let page = sqlx::query(
+            "SELECT Cid, Page, Name, Mimetype, Width, Height FROM ArchivePages WHERE Cid=? AND Page=?",
+        )
+        .bind(cid)
+        .bind(page)
+        .try_map(page_row)
+        .fetch_optional(&self.executor)
+        .await?;
+
+        Ok(page)
+    }
+
+    pub async fn replace_image_colors(
+        &self,
+        cid: Vec<u8>,
+        colors: &[ImageColorRow],
+    ) -> Result<()> {
+        sqlx::query("DELETE FROM ImageColors WHERE Cid=?")
+            .bind(cid)
+            .execute(&self.executor)
+            .await?;
+
+        for c in colors {
+            sqlx::query(
+                r#"
+                INSERT INTO ImageColors (Cid, Rank, L, A, B, Weight) VALUES
+                (?, ?, ?, ?, ?, ?)"#,
+            )
+            .bind(c.cid.clone())
+            .bind(c.rank)
+            .bind(c.l)
+            .bind(c.a)
+            .bind(c.b)
+            .bind(c.weight)
+            .execute(&self.executor)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn image_colors(
+        &self,
+        cid: Vec<u8>,
+    ) -> Result<Vec<ImageColorRow>> {
+        let rows = sqlx::query(
+            "SELECT Cid, Rank, L, A, B, Weight FROM ImageColors WHERE Cid=? ORDER BY Rank",
+        )
+        .bind(cid)
+        .try_map(|r: SqliteRow| {
+            let cid = r.try_get("Cid")?;
+            let rank = r.try_get("Rank")?;
+            let l = r.try_get("L")?;
+            let a = r.try_get("A")?;
+            let b = r.try_get("B")?;
+            let weight = r.try_get("Weight")?;
+
+            Ok(ImageColorRow {
+                cid,
+                rank,
+                l,
+                a,
+                b,
+                weight,
+            })
+        })
+        .fetch_all(&self.executor)
+        .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn new_image_hash(&self, hash: ImageHashRow) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO ImageHashes (Cid, DHash, PHash) VALUES
+            (?, ?, ?) ON CONFLICT(Cid)
+                DO UPDATE SET
+                DHash=excluded.DHash, PHash=excluded.PHash"#,
+        )
+        .bind(hash.cid)
+        .bind(hash.dhash as i64)
+        .bind(hash.phash as i64)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn image_hash(
+        &self,
+        cid: Vec<u8>,
+    ) -> Result<Option<ImageHashRow>> {
+        let row = sqlx::query(
+            "SELECT Cid, DHash, PHash FROM ImageHashes WHERE Cid=?",
+        )
+        .bind(cid)
+        .try_map(|r: SqliteRow| {
+            let cid = r.try_get("Cid")?;
+            let dhash: i64 = r.try_get("DHash")?;
+            let phash: i64 = r.try_get("PHash")?;
+
+            Ok(ImageHashRow {
+                cid,
+                dhash: dhash as u64,
+                phash: phash as u64,
+            })
+        })
+        .fetch_optional(&self.executor)
+        .await?;
+
+        Ok(row)
+    }
+
+    pub async fn image_hashes(&self) -> Result<Vec<ImageHashRow>> {
+        let rows = sqlx::query("SELECT Cid, DHash, PHash FROM ImageHashes")
+            .try_map(|r: SqliteRow| {
+                let cid = r.try_get("Cid")?;
+                let dhash: i64 = r.try_get("DHash")?;
+                let phash: i64 = r.try_get("PHash")?;
+
+                Ok(ImageHashRow {
+                    cid,
+                    dhash: dhash as u64,
+                    phash: phash as u64,
+                })
+            })
+            .fetch_all(&self.executor)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn lookup_tag_id(&self, tags: Vec<Tag>) -> Result<Vec<TagRow>> {
+        if tags.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            r#"SELECT Id, Descriptor, Namespace
+        FROM Tags WHERE (Namespace, Descriptor) IN ("#,
+        );
+        let tags_len = tags.len();
+        for (i, t) in tags.into_iter().enumerate() {
+            if i < tags_len - 1 {
+                builder.push(", ");
+            }
+            builder.push("(");
+            builder.push_bind(t.namespace);
+            builder.push(",");
+            builder.push_bind(t.descriptor);
+            builder.push(")");
+        }
+        builder.push(")");
+        let query = builder.build();
+
+        let tag_rows = query
+            .try_map(|r: SqliteRow| {
+                let descriptor = r.try_get("Descriptor")?;
+                let namespace = r.try_get("Namespace")?;
+                let id = r.try_get("Id")?;
+
+                Ok(TagRow {
+                    id,
+                    descriptor,
+                    namespace,
+                })
             })
             .fetch_all(&self.executor)
             .await?;
 
+        Ok(tag_rows)
+    }
+
+    pub async fn file_row(&self, cid: Vec<u8>) -> Result<FileRow> {
+        let file_row =
+            sqlx::query("SELECT Cid, Mimetype, Size FROM Files WHERE Cid=?")
+                .bind(cid)
+                .try_map(|r: SqliteRow| {
+                    let cid = r.try_get("Cid")?;
+                    let mimetype = r.try_get("Mimetype")?;
+                    let size = r.try_get("Size")?;
+
+                    Ok(FileRow {
+                        cid,
+                        mimetype,
+                        size,
+                    })
+                })
+                .fetch_one(&self.executor)
+                .await?;
+
+        Ok(file_row)
+    }
+
+    /// CID of every indexed file, oldest first
+    pub async fn file_cids(&self) -> Result<Vec<Vec<u8>>> {
+        let cids = sqlx::query("SELECT Cid FROM Files ORDER BY rowid")
+            .try_map(|r: SqliteRow| r.try_get("Cid"))
+            .fetch_all(&self.executor)
+            .await?;
+
+        Ok(cids)
+    }
+
+    /// Images that have not been analyzed have no row
+    pub async fn image_row(&self, cid: Vec<u8>) -> Result<Option<ImageRow>> {
+        let row =
+            sqlx::query("SELECT Cid, Height, Width, Ratio, PrimaryColor, Colors, Blurhash FROM Images WHERE Cid=?")
+                .bind(cid)
+                .try_map(image_row)
+                .fetch_optional(&self.executor)
+                .await?;
+
+        Ok(row)
+    }
+
+    /// Rows of whichever of the given CIDs are analyzed images
+    pub async fn image_rows(&self, cids: &[Vec<u8>]) -> Result<Vec<ImageRow>> {
+        let mut rows = vec![];
+        for chunk in cids.chunks(SQLITE_MAX_VARIABLES) {
+            let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "SELECT Cid, Height, Width, Ratio, PrimaryColor, Colors, Blurhash FROM Images WHERE Cid IN (",
+            );
+            let mut separated = builder.separated(", ");
+            for cid in chunk {
+                separated.push_bind(cid.clone());
+            }
+            builder.push(")");
+
+            rows.extend(
+                builder
+                    .build()
+                    .try_map(image_row)
+                    .fetch_all(&self.executor)
+                    .await?,
+            );
+        }
+        Ok(rows)
+    }
+
+    /// Images without EXIF data have no row
+    pub async fn image_exif(
+        &self,
+        cid: Vec<u8>,
+    ) -> Result<Option<ImageExifRow>> {
+        let row =
+            sqlx::query("SELECT Cid, Taken, CameraMake, CameraModel, Lens, ExposureTime, FNumber, Iso, FocalLength, Latitude, Longitude FROM ImageExif WHERE Cid=?")
+                .bind(cid)
+                .try_map(|r: SqliteRow| {
+                    Ok(ImageExifRow {
+                        cid: r.try_get("Cid")?,
+                        taken: r.try_get("Taken")?,
+                        camera_make: r.try_get("CameraMake")?,
+                        camera_model: r.try_get("CameraModel")?,
+                        lens: r.try_get("Lens")?,
+                        exposure_time: r.try_get("ExposureTime")?,
+                        f_number: r.try_get("FNumber")?,
+                        iso: r.try_get("Iso")?,
+                        focal_length: r.try_get("FocalLength")?,
+                        latitude: r.try_get("Latitude")?,
+                        longitude: r.try_get("Longitude")?,
+                    })
+                })
+                .fetch_optional(&self.executor)
+                .await?;
+
+        Ok(row)
+    }
+
+    /// Videos in containers hooya can't parse have no row
+    pub async fn video_row(&self, cid: Vec<u8>) -> Result<Option<VideoRow>> {
+        let row =
+            sqlx::query("SELECT Cid, Height, Width, Ratio, Duration, VideoCodec, AudioCodec, FrameRate FROM Videos WHERE Cid=?")
+                .bind(cid)
+                .try_map(|r: SqliteRow| {
+                    Ok(VideoRow {
+                        cid: r.try_get("Cid")?,
+                        height: r.try_get("Height")?,
+                        width: r.try_get("Width")?,
+                        ratio: r.try_get("Ratio")?,
+                        duration: r.try_get("Duration")?,
+                        video_codec: r.try_get("VideoCodec")?,
+                        audio_codec: r.try_get("AudioCodec")?,
+                        frame_rate: r.try_get("FrameRate")?,
+                    })
+                })
+                .fetch_optional(&self.executor)
+                .await?;
+
+        Ok(row)
+    }
+
+    pub async fn audio_row(&self, cid: Vec<u8>) -> Result<Option<AudioRow>> {
+        let row =
+            sqlx::query("SELECT Cid, Title, Artist, Album, Duration, SampleRate, Channels FROM Audio WHERE Cid=?")
+                .bind(cid)
+                .try_map(|r: SqliteRow| {
+                    Ok(AudioRow {
+                        cid: r.try_get("Cid")?,
+                        title: r.try_get("Title")?,
+                        artist: r.try_get("Artist")?,
+                        album: r.try_get("Album")?,
+                        duration: r.try_get("Duration")?,
+                        sample_rate: r.try_get("SampleRate")?,
+                        channels: r.try_get("Channels")?,
+                    })
+                })
+                .fetch_optional(&self.executor)
+                .await?;
+
+        Ok(row)
+    }
+
+    pub async fn thumbnails_by_source_cid(
+        &self,
+        cid: Vec<u8>,
+    ) -> Result<Vec<ThumbnailRow>> {
+        let thumbnail_rows = sqlx::query("SELECT Cid, Size, Mimetype, SourceCid, Height, Width, Ratio, IsAnimated, HasAlpha FROM Thumbnails WHERE SourceCid=?")
+            .bind(cid)
+            .try_map(thumbnail_row)
+            .fetch_all(&self.executor)
+            .await?;
+
+        Ok(thumbnail_rows)
+    }
+
+    /// Thumbnails of any of the given source CIDs
+    pub async fn thumbnails_by_source_cids(
+        &self,
+        cids: &[Vec<u8>],
+    ) -> Result<Vec<ThumbnailRow>> {
+        let mut thumbnail_rows = vec![];
+        for chunk in cids.chunks(SQLITE_MAX_VARIABLES) {
+            let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "SELECT Cid, Size, Mimetype, SourceCid, Height, Width, Ratio, IsAnimated, HasAlpha FROM Thumbnails WHERE SourceCid IN (",
+            );
+            let mut separated = builder.separated(", ");
+            for cid in chunk {
+                separated.push_bind(cid.clone());
+            }
+            builder.push(")");
+
+            thumbnail_rows.extend(
+                builder
+                    .build()
+                    .try_map(thumbnail_row)
+                    .fetch_all(&self.executor)
+                    .await?,
+            );
+        }
         Ok(thumbnail_rows)
     }
 
     pub async fn file_page(
         &self,
+        query: &Query,
         count: u32,
         offset: u32,
-        oldest_first: bool,
+        sort: Sort,
     ) -> Result<Vec<FileRow>> {
-        let query = if oldest_first {
-            "SELECT Cid, Mimetype, Size FROM Files ORDER BY Indexed LIMIT ? OFFSET ?"
-        } else {
-            "SELECT Cid, Mimetype, Size FROM Files ORDER BY Indexed DESC LIMIT ? OFFSET ?"
-        };
-        let file_rows = sqlx::query(query)
-            .bind(count)
-            .bind(offset)
+        let mut builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("SELECT Cid, Mimetype, Size FROM Files");
+        push_query_filter(&mut builder, query);
+        builder.push(match sort {
+            Sort::Newest => " ORDER BY Indexed DESC",
+            Sort::Oldest => " ORDER BY Indexed",
+        });
+        builder.push(" LIMIT ");
+        builder.push_bind(count);
+        builder.push(" OFFSET ");
+        builder.push_bind(offset);
+
+        let file_rows = builder
+            .build()
             .try_map(|r: SqliteRow| {
                 let cid = r.try_get("Cid")?;
                 let mimetype = r.try_get("Mimetype")?;
@@ -400,25 +1097,696 @@ impl Db {
         Ok(file_rows)
     }
 
-    pub async fn random_file(&self, count: u32) -> Result<Vec<FileRow>> {
-        let file_rows = sqlx::query(
-            "SELECT Cid, Mimetype, Size FROM Files ORDER BY RANDOM() LIMIT ?",
+    /// Count files matching a query, optionally only those indexed after
+    /// some point in time
+    pub async fn count_files(
+        &self,
+        query: &Query,
+        indexed_after: Option<String>,
+    ) -> Result<i64> {
+        let mut builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("SELECT COUNT(*) AS Count FROM Files");
+        push_query_filter(&mut builder, query);
+        if let Some(indexed_after) = indexed_after {
+            builder.push(" AND Indexed > ");
+            builder.push_bind(indexed_after);
+        }
+
+        let count = builder
+            .build()
+            .try_map(|r: SqliteRow| r.try_get("Count"))
+            .fetch_one(&self.executor)
+            .await?;
+
+        Ok(count)
+    }
+
+    pub async fn new_saved_search(&self, s: SavedSearchRow) -> Result<i64> {
+        let id = sqlx::query(
+            r#"
+            INSERT INTO SavedSearches (Name, Query, Sort, Owner) VALUES
+            (?, ?, ?, ?)"#,
+        )
+        .bind(s.name)
+        .bind(s.query)
+        .bind(s.sort)
+        .bind(s.owner)
+        .execute(&self.executor)
+        .await?
+        .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    pub async fn saved_searches(
+        &self,
+        owner: Option<String>,
+    ) -> Result<Vec<SavedSearchRow>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT Id, Name, Query, Sort, Owner, LastViewed FROM SavedSearches",
+        );
+        if let Some(owner) = owner {
+            builder.push(" WHERE Owner = ");
+            builder.push_bind(owner);
+        }
+        builder.push(" ORDER BY Name");
+
+        let rows = builder
+            .build()
+            .try_map(saved_search_from_row)
+            .fetch_all(&self.executor)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn saved_search(
+        &self,
+        id: i64,
+    ) -> Result<Option<SavedSearchRow>> {
+        let row = sqlx::query(
+            "SELECT Id, Name, Query, Sort, Owner, LastViewed FROM SavedSearches WHERE Id=?",
+        )
+        .bind(id)
+        .try_map(saved_search_from_row)
+        .fetch_optional(&self.executor)
+        .await?;
+
+        Ok(row)
+    }
+
+    pub async fn delete_saved_search(&self, id: i64) -> Result<()> {
+        sqlx::query("DELETE FROM SavedSearches WHERE Id=?")
+            .bind(id)
+            .execute(&self.executor)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn touch_saved_search(&self, id: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE SavedSearches SET LastViewed=CURRENT_TIMESTAMP WHERE Id=?",
         )
-        .bind(count)
+        .bind(id)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_analysis(
+        &self,
+        cid: Vec<u8>,
+        status: &str,
+        reason: Option<String>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO Analysis (Cid, Status, Reason) VALUES (?, ?, ?)
+            ON CONFLICT(Cid) DO UPDATE SET
+                Status=excluded.Status, Reason=excluded.Reason,
+                Updated=CURRENT_TIMESTAMP"#,
+        )
+        .bind(cid)
+        .bind(status)
+        .bind(reason)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn analysis(&self, cid: Vec<u8>) -> Result<Option<AnalysisRow>> {
+        let row = sqlx::query(
+            "SELECT Cid, Status, Reason, Updated FROM Analysis WHERE Cid=?",
+        )
+        .bind(cid)
         .try_map(|r: SqliteRow| {
-            let cid = r.try_get("Cid")?;
-            let mimetype = r.try_get("Mimetype")?;
-            let size = r.try_get("Size")?;
+            Ok(AnalysisRow {
+                cid: r.try_get("Cid")?,
+                status: r.try_get("Status")?,
+                reason: r.try_get("Reason")?,
+                updated: r.try_get("Updated")?,
+            })
+        })
+        .fetch_optional(&self.executor)
+        .await?;
 
-            Ok(FileRow {
-                cid,
-                mimetype,
-                size,
+        Ok(row)
+    }
+
+    pub async fn new_job(&self, kind: &str, cid: Vec<u8>) -> Result<i64> {
+        let id = sqlx::query("INSERT INTO Jobs (Kind, Cid) VALUES (?, ?)")
+            .bind(kind)
+            .bind(cid)
+            .execute(&self.executor)
+            .await?
+            .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    /// Mark the oldest pending job as running and return it
+    pub async fn claim_job(&self) -> Result<Option<JobRow>> {
+        let job = sqlx::query(
+            r#"
+            UPDATE Jobs SET State='running', Attempts=Attempts + 1,
+                Updated=CURRENT_TIMESTAMP
+            WHERE Id = (
+                SELECT Id FROM Jobs WHERE State='pending' ORDER BY Id LIMIT 1)
+            RETURNING Id, Kind, Cid, State, Error, Attempts, Created, Updated"#,
+        )
+        .try_map(job_row)
+        .fetch_optional(&self.executor)
+        .await?;
+
+        Ok(job)
+    }
+
+    /// Record the outcome of a running job
+    pub async fn finish_job(
+        &self,
+        id: i64,
+        error: Option<String>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE Jobs SET State=?, Error=?, Updated=CURRENT_TIMESTAMP
+            WHERE Id=?"#,
+        )
+        .bind(if error.is_some() { "failed" } else { "done" })
+        .bind(error)
+        .bind(id)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Put a failed job back in the queue
+    pub async fn retry_job(&self, id: i64) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+            UPDATE Jobs SET State='pending', Error=NULL,
+                Updated=CURRENT_TIMESTAMP
+            WHERE Id=? AND State='failed'"#,
+        )
+        .bind(id)
+        .execute(&self.executor)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("No failed job with ID {}", id));
+        }
+
+        Ok(())
+    }
+
+    /// IDs and CIDs of the pending and running jobs of one kind
+    pub async fn unfinished_jobs(
+        &self,
+        kind: &str,
+    ) -> Result<Vec<(i64, Vec<u8>)>> {
+        let jobs = sqlx::query(
+            "SELECT Id, Cid FROM Jobs WHERE Kind=? AND State IN ('pending', 'running')",
+        )
+        .bind(kind)
+        .try_map(|r: SqliteRow| Ok((r.try_get("Id")?, r.try_get("Cid")?)))
+        .fetch_all(&self.executor)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    /// Requeue jobs left running by a previous hooyad that exited mid-job
+    /// and drop the record of finished ones
+    pub async fn reset_jobs(&self) -> Result<()> {
+        sqlx::query("UPDATE Jobs SET State='pending' WHERE State='running'")
+            .execute(&self.executor)
+            .await?;
+        sqlx::query("DELETE FROM Jobs WHERE State='done'")
+            .execute(&self.executor)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn job(&self, id: i64) -> Result<JobRow> {
+        let job = sqlx::query(
+            "SELECT Id, Kind, Cid, State, Error, Attempts, Created, Updated FROM Jobs WHERE Id=?",
+        )
+        .bind(id)
+        .try_map(job_row)
+        .fetch_one(&self.executor)
+        .await?;
+
+        Ok(job)
+    }
+
+    /// Jobs in the order they were queued, optionally only those in one
+    /// state. A limit of 0 means no limit
+    pub async fn jobs(
+        &self,
+        state: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<JobRow>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT Id, Kind, Cid, State, Error, Attempts, Created, Updated FROM Jobs",
+        );
+        if let Some(state) = state {
+            builder.push(" WHERE State = ");
+            builder.push_bind(state);
+        }
+        builder.push(" ORDER BY Id");
+        if limit > 0 {
+            builder.push(" LIMIT ");
+            builder.push_bind(limit);
+        }
+
+        let jobs = builder
+            .build()
+            .try_map(job_row)
+            .fetch_all(&self.executor)
+            .await?;
+
+        Ok(jobs)
+    }
+
+    /// Start a reimport run, dropping the record of previous finished runs
+    pub async fn new_reimport_run(&self, query: &str) -> Result<i64> {
+        sqlx::query(
+            "DELETE FROM ReimportedFiles WHERE RunId IN (SELECT Id FROM ReimportRuns WHERE Finished IS NOT NULL)",
+        )
+        .execute(&self.executor)
+        .await?;
+        sqlx::query("DELETE FROM ReimportRuns WHERE Finished IS NOT NULL")
+            .execute(&self.executor)
+            .await?;
+
+        let id = sqlx::query("INSERT INTO ReimportRuns (Query) VALUES (?)")
+            .bind(query)
+            .execute(&self.executor)
+            .await?
+            .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    pub async fn reimport_run(&self, id: i64) -> Result<ReimportRunRow> {
+        let run = sqlx::query(
+            "SELECT Id, Query, Started, Finished FROM ReimportRuns WHERE Id=?",
+        )
+        .bind(id)
+        .try_map(|r: SqliteRow| {
+            Ok(ReimportRunRow {
+                id: r.try_get("Id")?,
+                query: r.try_get("Query")?,
+                started: r.try_get("Started")?,
+                finished: r.try_get("Finished")?,
             })
         })
+        .fetch_one(&self.executor)
+        .await?;
+
+        Ok(run)
+    }
+
+    /// Files matching a run's query that it has yet to reprocess, plus the
+    /// number it already has
+    pub async fn reimport_remaining(
+        &self,
+        id: i64,
+        query: &Query,
+    ) -> Result<(Vec<Vec<u8>>, u64)> {
+        let mut builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("SELECT Cid FROM Files");
+        push_query_filter(&mut builder, query);
+        builder.push(
+            " AND Cid NOT IN (SELECT Cid FROM ReimportedFiles WHERE RunId = ",
+        );
+        builder.push_bind(id);
+        builder.push(") ORDER BY rowid");
+
+        let remaining = builder
+            .build()
+            .try_map(|r: SqliteRow| r.try_get("Cid"))
+            .fetch_all(&self.executor)
+            .await?;
+
+        let done: i64 = sqlx::query(
+            "SELECT COUNT(*) AS Done FROM ReimportedFiles WHERE RunId=?",
+        )
+        .bind(id)
+        .try_map(|r: SqliteRow| r.try_get("Done"))
+        .fetch_one(&self.executor)
+        .await?;
+
+        Ok((remaining, done.try_into()?))
+    }
+
+    pub async fn mark_reimported(&self, id: i64, cid: Vec<u8>) -> Result<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO ReimportedFiles (RunId, Cid) VALUES (?, ?)",
+        )
+        .bind(id)
+        .bind(cid)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn finish_reimport_run(&self, id: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE ReimportRuns SET Finished=CURRENT_TIMESTAMP WHERE Id=?",
+        )
+        .bind(id)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Row IDs of every file matching a query, in a stable order. Cheap
+    /// enough to shuffle in memory even for large libraries, unlike sorting
+    /// whole rows with ORDER BY RANDOM()
+    pub async fn matching_row_ids(&self, query: &Query) -> Result<Vec<i64>> {
+        let mut builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("SELECT rowid FROM Files");
+        push_query_filter(&mut builder, query);
+        builder.push(" ORDER BY rowid");
+
+        let row_ids = builder
+            .build()
+            .try_map(|r: SqliteRow| r.try_get("rowid"))
+            .fetch_all(&self.executor)
+            .await?;
+
+        Ok(row_ids)
+    }
+
+    /// Which of the given CIDs are indexed files
+    pub async fn indexed_cids(&self, cids: &[Vec<u8>]) -> Result<Vec<Vec<u8>>> {
+        let mut indexed = vec![];
+        for chunk in cids.chunks(SQLITE_MAX_VARIABLES) {
+            let mut builder: QueryBuilder<Sqlite> =
+                QueryBuilder::new("SELECT Cid FROM Files WHERE Cid IN (");
+            let mut separated = builder.separated(", ");
+            for cid in chunk {
+                separated.push_bind(cid.clone());
+            }
+            builder.push(")");
+
+            indexed.extend(
+                builder
+                    .build()
+                    .try_map(|r: SqliteRow| r.try_get("Cid"))
+                    .fetch_all(&self.executor)
+                    .await?,
+            );
+        }
+        Ok(indexed)
+    }
+
+    /// Files with the given row IDs, in the same order as the IDs
+    pub async fn files_by_row_id(
+        &self,
+        row_ids: &[i64],
+    ) -> Result<Vec<FileRow>> {
+        let mut file_rows = vec![];
+        for chunk in row_ids.chunks(SQLITE_MAX_VARIABLES) {
+            let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "SELECT rowid, Cid, Mimetype, Size FROM Files WHERE rowid IN (",
+            );
+            let mut separated = builder.separated(", ");
+            for id in chunk {
+                separated.push_bind(*id);
+            }
+            builder.push(")");
+
+            file_rows.extend(
+                builder
+                    .build()
+                    .try_map(|r: SqliteRow| {
+                        let row_id: i64 = r.try_get("rowid")?;
+                        let cid = r.try_get("Cid")?;
+                        let mimetype = r.try_get("Mimetype")?;
+                        let size = r.try_get("Size")?;
+
+                        Ok((
+                            row_id,
+                            FileRow {
+                                cid,
+                                mimetype,
+                                size,
+                            },
+                        ))
+                    })
+                    .fetch_all(&self.executor)
+                    .await?,
+            );
+        }
+
+        let positions: HashMap<i64, usize> =
+            row_ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        file_rows.sort_by_key(|(row_id, _)| positions.get(row_id).copied());
+
+        Ok(file_rows.into_iter().map(|(_, f)| f).collect())
+    }
+
+    /// Size and modification time a file in a watched directory had when it
+    /// was imported, if it was
+    pub async fn watched_file(&self, path: &str) -> Result<Option<(i64, i64)>> {
+        let stamp =
+            sqlx::query("SELECT Size, Modified FROM WatchedFiles WHERE Path=?")
+                .bind(path)
+                .try_map(|r: SqliteRow| {
+                    Ok((r.try_get("Size")?, r.try_get("Modified")?))
+                })
+                .fetch_optional(&self.executor)
+                .await?;
+
+        Ok(stamp)
+    }
+
+    pub async fn mark_watched(
+        &self,
+        path: &str,
+        size: i64,
+        modified: i64,
+        cid: Vec<u8>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO WatchedFiles (Path, Size, Modified, Cid) VALUES
+            (?, ?, ?, ?) ON CONFLICT(Path)
+                DO UPDATE SET
+                Size=excluded.Size, Modified=excluded.Modified,
+                Cid=excluded.Cid"#,
+        )
+        .bind(path)
+        .bind(size)
+        .bind(modified)
+        .bind(cid)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn add_file_name(&self, cid: Vec<u8>, name: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT OR IGNORE INTO FileNames (Cid, Name) VALUES (?, ?)",
+        )
+        .bind(cid)
+        .bind(name)
+        .execute(&self.executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Names a file was uploaded under, first used first
+    pub async fn file_names(&self, cid: Vec<u8>) -> Result<Vec<String>> {
+        let names = sqlx::query(
+            "SELECT Name FROM FileNames WHERE Cid=? ORDER BY rowid",
+        )
+        .bind(cid)
+        .try_map(|r: SqliteRow| r.try_get("Name"))
         .fetch_all(&self.executor)
         .await?;
+        Ok(names)
+    }
+}
 
-        Ok(file_rows)
+fn saved_search_from_row(r: SqliteRow) -> sqlx::Result<SavedSearchRow> {
+    let id = r.try_get("Id")?;
+    let name = r.try_get("Name")?;
+    let query = r.try_get("Query")?;
+    let sort = r.try_get("Sort")?;
+    let owner = r.try_get("Owner")?;
+    let last_viewed = r.try_get("LastViewed")?;
+
+    Ok(SavedSearchRow {
+        id,
+        name,
+        query,
+        sort,
+        owner,
+        last_viewed,
+    })
+}
+
+fn page_row(r: SqliteRow) -> sqlx::Result<PageRow> {
+    Ok(PageRow {
+        cid: r.try_get("Cid")?,
+        page: r.try_get("Page")?,
+        name: r.try_get("Name")?,
+        mimetype: r.try_get("Mimetype")?,
+        width: r.try_get("Width")?,
+        height: r.try_get("Height")?,
+    })
+}
+
+fn image_row(r: SqliteRow) -> sqlx::Result<ImageRow> {
+    Ok(ImageRow {
+        cid: r.try_get("Cid")?,
+        height: r.try_get("Height")?,
+        width: r.try_get("Width")?,
+        ratio: r.try_get("Ratio")?,
+        primary_color: r.try_get("PrimaryColor")?,
+        colors: r.try_get("Colors")?,
+        blurhash: r.try_get("Blurhash")?,
+    })
+}
+
+fn thumbnail_row(r: SqliteRow) -> sqlx::Result<ThumbnailRow> {
+    Ok(ThumbnailRow {
+        cid: r.try_get("Cid")?,
+        size: r.try_get("Size")?,
+        mimetype: r.try_get("Mimetype")?,
+        source_cid: r.try_get("SourceCid")?,
+        height: r.try_get("Height")?,
+        width: r.try_get("Width")?,
+        ratio: r.try_get("Ratio")?,
+        is_animated: r.try_get("IsAnimated")?,
+        has_alpha: r.try_get("HasAlpha")?,
+    })
+}
+
+fn job_row(r: SqliteRow) -> sqlx::Result<JobRow> {
+    Ok(JobRow {
+        id: r.try_get("Id")?,
+        kind: r.try_get("Kind")?,
+        cid: r.try_get("Cid")?,
+        state: r.try_get("State")?,
+        error: r.try_get("Error")?,
+        attempts: r.try_get("Attempts")?,
+        created: r.try_get("Created")?,
+        updated: r.try_get("Updated")?,
+    })
+}
+
+/// Append a WHERE clause restricting Files to those matching every term of
+/// the query
+fn push_query_filter(builder: &mut QueryBuilder<Sqlite>, query: &Query) {
+    builder.push(" WHERE 1");
+    for term in &query.terms {
+        builder.push(" AND ");
+        push_term_condition(builder, term);
+    }
+}
+
+fn push_term_condition(builder: &mut QueryBuilder<Sqlite>, term: &Term) {
+    match term {
+        Term::Tag(tag) => {
+            builder.push(
+                "Cid IN (SELECT FileCid FROM TagMap, Tags WHERE TagId = Id AND Namespace = ",
+            );
+            builder.push_bind(tag.namespace.clone());
+            builder.push(" AND Descriptor = ");
+            builder.push_bind(tag.descriptor.clone());
+            builder.push(")");
+        }
+        Term::Color { lab, max_distance } => {
+            // Compare squared CIE76 distance since SQLite lacks SQRT()
+            builder.push("Cid IN (SELECT Cid FROM ImageColors WHERE (L - ");
+            builder.push_bind(lab.l);
+            builder.push(") * (L - ");
+            builder.push_bind(lab.l);
+            builder.push(") + (A - ");
+            builder.push_bind(lab.a);
+            builder.push(") * (A - ");
+            builder.push_bind(lab.a);
+            builder.push(") + (B - ");
+            builder.push_bind(lab.b);
+            builder.push(") * (B - ");
+            builder.push_bind(lab.b);
+            builder.push(") <= ");
+            builder.push_bind(max_distance * max_distance);
+            builder.push(")");
+        }
+        Term::Camera(camera) => {
+            // Models don't always repeat the make, eg "Canon" + "EOS R5"
+            builder.push(
+                "Cid IN (SELECT Cid FROM ImageExif WHERE (IFNULL(CameraMake, '') || ' ' || IFNULL(CameraModel, '')) LIKE ",
+            );
+            builder.push_bind(like_contains(camera));
+            builder.push(" ESCAPE '\\')");
+        }
+        Term::Lens(lens) => {
+            builder.push("Cid IN (SELECT Cid FROM ImageExif WHERE Lens LIKE ");
+            builder.push_bind(like_contains(lens));
+            builder.push(" ESCAPE '\\')");
+        }
+        Term::Taken { from, to } => {
+            // Partial dates compare against the same-length prefix of the
+            // capture date, so `2023-05` covers the whole month
+            builder.push(
+                "Cid IN (SELECT Cid FROM ImageExif WHERE Taken IS NOT NULL",
+            );
+            if let Some(from) = from {
+                builder.push(" AND SUBSTR(Taken, 1, ");
+                builder.push_bind(from.len() as i64);
+                builder.push(") >= ");
+                builder.push_bind(from.clone());
+            }
+            if let Some(to) = to {
+                builder.push(" AND SUBSTR(Taken, 1, ");
+                builder.push_bind(to.len() as i64);
+                builder.push(") <= ");
+                builder.push_bind(to.clone());
+            }
+            builder.push(")");
+        }
+        Term::Analysis(status) => {
+            builder.push("Cid IN (SELECT Cid FROM Analysis WHERE Status = ");
+            builder.push_bind(status.to_string());
+            builder.push(")");
+        }
+        Term::Mimetype(mimetype) => {
+            if mimetype.contains('/') {
+                builder.push("Mimetype = ");
+                builder.push_bind(mimetype.clone());
+            } else {
+                builder.push("Mimetype LIKE ");
+                builder.push_bind(format!("{}/%", mimetype));
+            }
+        }
+        Term::Not(inner) => {
+            builder.push("NOT ");
+            push_term_condition(builder, inner);
+        }
+    }
+}
+
+/// LIKE pattern for values containing `s`, escaped with `\`. `_` is left
+/// as a wildcard, standing in for a space in queries
+fn like_contains(s: &str) -> String {
+    let escaped = s.replace('\\', "\\\\").replace('%', "\\%");
+    format!("%{}%", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_patterns_escape_percent() {
+        assert_eq!(like_contains("eos_r5"), "%eos_r5%");
+        assert_eq!(like_contains("100%"), "%100\\%%");
+        assert_eq!(like_contains("a\\b"), "%a\\\\b%");
     }
 }
@@ -0,0 +1,674 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::Value;
+
+use crate::proto::Tag;
+
+/// Name and value pairs read from metadata, eg ("keywords", "sunset").
+/// Fields of nested JSON objects are joined with dots, eg "tags.artist"
+pub type Fields = Vec<(String, String)>;
+
+// Extensions of the sidecar metadata files read for tags
+const SIDECAR_EXTENSIONS: [&str; 3] = ["json", "txt", "xmp"];
+
+// XMP properties and the fields they are read as
+const XMP_FIELDS: [(&[u8], &str); 4] = [
+    (b"dc:subject", "keywords"),
+    (b"dc:title", "title"),
+    (b"dc:description", "description"),
+    (b"dc:source", "source"),
+];
+
+// Datasets of the IPTC-IIM application record and the fields they are read
+// as
+const IPTC_FIELDS: [(u8, &str); 4] = [
+    (5, "title"),
+    (25, "keywords"),
+    (115, "source"),
+    (120, "description"),
+];
+
+// Photoshop image resource holding IPTC-IIM data
+const PHOTOSHOP_IPTC: u16 = 0x0404;
+
+// Embedded metadata is only looked for in this much of the start of a file,
+// where every writer puts it
+const MAX_HEADER_BYTES: u64 = 4 * 1024 * 1024;
+
+// Namespaces whose descriptors are kept as written rather than typed like
+// tags
+const VERBATIM_NAMESPACES: [&str; 3] = ["source", "title", "description"];
+
+/// How values of one metadata field become tags
+#[derive(Clone, Debug)]
+struct Mapping {
+    // None when values are tags themselves, eg artist:someone
+    namespace: Option<String>,
+    // Whether a value is a space-separated list, as boorus write them
+    split: bool,
+}
+
+/// Which tag namespace each metadata field is imported into. Fields not in
+/// the map are ignored
+#[derive(Clone, Debug)]
+pub struct FieldMap(HashMap<String, Mapping>);
+
+impl Default for FieldMap {
+    fn default() -> Self {
+        let mut map = FieldMap(HashMap::new());
+        for entry in [
+            // XMP and IPTC keywords, Hydrus tags and most gallery-dl
+            // extractors
+            "keywords=*",
+            "tags=*",
+            // e621 and friends
+            "tags.general=general",
+            "tags.artist=artist",
+            "tags.character=character",
+            "tags.copyright=copyright",
+            "tags.meta=meta",
+            // Danbooru
+            "tag_string_general=general,split",
+            "tag_string_artist=artist,split",
+            "tag_string_character=character,split",
+            "tag_string_copyright=copyright,split",
+            "tag_string_meta=meta,split",
+            "artist=artist",
+            "rating=rating",
+            "title=title",
+            "description=description",
+            "source=source",
+        ] {
+            map.set(entry).unwrap();
+        }
+        map
+    }
+}
+
+impl FieldMap {
+    /// Map a field as described by a `field=namespace` entry. A namespace of
+    /// `*` takes values as tags themselves, a `,split` suffix reads values
+    /// as space-separated lists and an empty namespace ignores the field
+    pub fn set(&mut self, entry: &str) -> Result<()> {
+        let (field, namespace) = entry.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("Expected field=namespace, got \"{}\"", entry)
+        })?;
+        if field.is_empty() {
+            return Err(anyhow::anyhow!("No field in \"{}\"", entry));
+        }
+
+        let (namespace, split) = match namespace.strip_suffix(",split") {
+            Some(n) => (n, true),
+            None => (namespace, false),
+        };
+
+        match namespace {
+            "" => {
+                self.0.remove(field);
+            }
+            "*" => {
+                self.0.insert(
+                    field.to_string(),
+                    Mapping {
+                        namespace: None,
+                        split,
+                    },
+                );
+            }
+            n => {
+                self.0.insert(
+                    field.to_string(),
+                    Mapping {
+                        namespace: Some(n.to_string()),
+                        split,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tags named by the mapped fields, without duplicates
+    pub fn tags(&self, fields: &[(String, String)]) -> Vec<Tag> {
+        let mut tags = vec![];
+        for (field, value) in fields {
+            let mapping = match self.0.get(field) {
+                Some(m) => m,
+                None => continue,
+            };
+
+            let values: Vec<&str> = if mapping.split {
+                value.split_whitespace().collect()
+            } else {
+                vec![value.trim()]
+            };
+
+            for value in values.into_iter().filter(|v| !v.is_empty()) {
+                let tag = match &mapping.namespace {
+                    Some(namespace)
+                        if VERBATIM_NAMESPACES
+                            .contains(&namespace.as_str()) =>
+                    {
+                        Tag {
+                            namespace: namespace.clone(),
+                            descriptor: value.to_string(),
+                        }
+                    }
+                    Some(namespace) => Tag {
+                        namespace: namespace.clone(),
+                        descriptor: as_typed(value),
+                    },
+                    None => {
+                        let tag = Tag::from(value);
+                        Tag {
+                            namespace: as_typed(&tag.namespace),
+                            descriptor: as_typed(&tag.descriptor),
+                        }
+                    }
+                };
+
+                if !tag.descriptor.is_empty() && !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+        tags
+    }
+}
+
+/// Keywords, titles, descriptions and sources embedded in a file as XMP or,
+/// for JPEGs, IPTC, reading only as far as metadata can be. Metadata that
+/// doesn't parse is skipped
+pub fn read_embedded_fields(path: &Path) -> Result<Fields> {
+    let header = read_header(fs::File::open(path)?)?;
+    Ok(embedded_fields(&header))
+}
+
+/// The part of a file that holds its metadata: the segments before the
+/// image data of a JPEG, or the first `MAX_HEADER_BYTES` of anything else
+fn read_header(fh: fs::File) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(fh).take(MAX_HEADER_BYTES);
+    let mut header = vec![0; 2];
+    if reader.read_exact(&mut header).is_err() {
+        return Ok(vec![]);
+    }
+    if header != [0xFF, 0xD8] {
+        reader.read_to_end(&mut header)?;
+        return Ok(header);
+    }
+
+    let mut marker = [0; 4];
+    while reader.read_exact(&mut marker[..2]).is_ok() {
+        header.extend_from_slice(&marker[..2]);
+        // Image data starts at SOS; metadata segments all come before it
+        if marker[0] != 0xFF || marker[1] == 0xDA || marker[1] == 0xD9 {
+            break;
+        }
+        if reader.read_exact(&mut marker[2..]).is_err() {
+            break;
+        }
+        header.extend_from_slice(&marker[2..]);
+
+        let len = u16::from_be_bytes([marker[2], marker[3]]) as u64;
+        let read = (&mut reader)
+            .take(len.saturating_sub(2))
+            .read_to_end(&mut header)?;
+        if (read as u64) < len.saturating_sub(2) {
+            break;
+        }
+    }
+
+    Ok(header)
+}
+
+/// Keywords, titles, descriptions and sources in XMP or, for JPEGs, IPTC
+/// within `data`. Metadata that doesn't parse is skipped
+fn embedded_fields(data: &[u8]) -> Fields {
+    let mut fields = find_xmp(data)
+        .and_then(|xmp| parse_xmp(xmp).ok())
+        .unwrap_or_default();
+    fields.extend(iptc_fields(data));
+    fields
+}
+
+/// Metadata files next to `path` that describe it, as written by gallery-dl
+/// (`a.jpg.json`), Hydrus exports (`a.jpg.txt`) and photo editors
+/// (`a.jpg.xmp`, `a.xmp`)
+pub fn sidecar_paths(path: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![];
+    if let Some(name) = path.file_name() {
+        for ext in SIDECAR_EXTENSIONS {
+            let mut sidecar_name = name.to_os_string();
+            sidecar_name.push(".");
+            sidecar_name.push(ext);
+            candidates.push(path.with_file_name(sidecar_name));
+        }
+    }
+    if path.extension().is_some() {
+        candidates.push(path.with_extension("xmp"));
+    }
+
+    candidates
+        .into_iter()
+        .filter(|p| p != path && p.is_file())
+        .collect()
+}
+
+/// Whether `path` is a sidecar of another file next to it, as found by
+/// `sidecar_paths`
+pub fn is_sidecar(path: &Path) -> bool {
+    let ext = match path.extension().and_then(|e| e.to_str()) {
+        Some(e) if SIDECAR_EXTENSIONS.contains(&e) => e,
+        _ => return false,
+    };
+    if path.with_extension("").is_file() {
+        return true;
+    }
+
+    // a.xmp describes any a.* beside it
+    ext == "xmp"
+        && path.parent().and_then(|dir| fs::read_dir(dir).ok()).map_or(
+            false,
+            |mut entries| {
+                entries.any(|e| {
+                    e.map_or(false, |e| {
+                        let sibling = e.path();
+                        sibling != path
+                            && sibling.extension().is_some()
+                            && sibling.file_stem() == path.file_stem()
+                            && sibling.is_file()
+                    })
+                })
+            },
+        )
+}
+
+/// Read the fields of a JSON, text (one tag per line) or XMP sidecar
+pub fn read_sidecar(path: &Path) -> Result<Fields> {
+    let contents = fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => Ok(json_fields(&serde_json::from_str(&contents)?)),
+        Some("txt") => Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| ("tags".to_string(), l.to_string()))
+            .collect()),
+        Some("xmp") => parse_xmp(&contents),
+        _ => Err(anyhow::anyhow!("{} is not a sidecar", path.display())),
+    }
+}
+
+/// Fields of a JSON value, with nested objects' keys joined by dots and
+/// arrays giving a field per element
+pub fn json_fields(value: &Value) -> Fields {
+    let mut fields = vec![];
+    flatten_json("", value, &mut fields);
+    fields
+}
+
+/// Lowercase with words joined by underscores, the way tags are typed
+fn as_typed(s: &str) -> String {
+    s.split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .to_lowercase()
+}
+
+fn flatten_json(key: &str, value: &Value, fields: &mut Fields) {
+    match value {
+        Value::String(s) => fields.push((key.to_string(), s.clone())),
+        Value::Number(n) => fields.push((key.to_string(), n.to_string())),
+        Value::Array(values) => {
+            for v in values {
+                flatten_json(key, v, fields);
+            }
+        }
+        Value::Object(o) => {
+            for (k, v) in o {
+                let key = match key {
+                    "" => k.clone(),
+                    _ => format!("{}.{}", key, k),
+                };
+                flatten_json(&key, v, fields);
+            }
+        }
+        Value::Bool(_) | Value::Null => {}
+    }
+}
+
+/// The XMP packet of a file, which every format embeds uncompressed
+fn find_xmp(data: &[u8]) -> Option<&str> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+
+    let start = find_bytes(data, START)?;
+    let end = start + find_bytes(&data[start..], END)? + END.len();
+    std::str::from_utf8(&data[start..end]).ok()
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_xmp(xmp: &str) -> Result<Fields> {
+    let mut reader = Reader::from_str(xmp);
+    reader.trim_text(true);
+
+    let mut fields = vec![];
+    // Field of the property being read and how many elements deep into it
+    // the reader is; values sit in rdf:li elements of a list or directly in
+    // the property
+    let mut current: Option<(&str, usize)> = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                current = match current {
+                    Some((field, depth)) => Some((field, depth + 1)),
+                    None => xmp_field(e.name().as_ref()).map(|f| (f, 0)),
+                };
+                attribute_fields(&e, &mut fields)?;
+            }
+            Event::Empty(e) => attribute_fields(&e, &mut fields)?,
+            Event::End(_) => {
+                current = match current {
+                    Some((field, depth)) if depth > 0 => {
+                        Some((field, depth - 1))
+                    }
+                    _ => None,
+                };
+            }
+            Event::Text(t) => {
+                if let Some((field, _)) = current {
+                    fields
+                        .push((field.to_string(), t.unescape()?.into_owned()));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(fields)
+}
+
+/// Simple properties may also be written as attributes of rdf:Description
+fn attribute_fields(e: &BytesStart, fields: &mut Fields) -> Result<()> {
+    for attr in e.attributes() {
+        let attr = attr?;
+        if let Some(field) = xmp_field(attr.key.as_ref()) {
+            fields
+                .push((field.to_string(), attr.unescape_value()?.into_owned()));
+        }
+    }
+    Ok(())
+}
+
+fn xmp_field(name: &[u8]) -> Option<&'static str> {
+    XMP_FIELDS
+        .iter()
+        .find(|(property, _)| *property == name)
+        .map(|(_, field)| *field)
+}
+
+fn iptc_fields(data: &[u8]) -> Fields {
+    let mut fields = vec![];
+    let iim = match jpeg_photoshop_resource(data, PHOTOSHOP_IPTC) {
+        Some(iim) => iim,
+        None => return fields,
+    };
+
+    // Datasets are a 0x1C tag marker, record and dataset numbers and a
+    // big-endian length
+    let mut i = 0;
+    while i + 5 <= iim.len() && iim[i] == 0x1C {
+        let record = iim[i + 1];
+        let dataset = iim[i + 2];
+        let len = u16::from_be_bytes([iim[i + 3], iim[i + 4]]) as usize;
+        // Extended lengths are only used for binary datasets like previews,
+        // which come after the text ones
+        if len & 0x8000 != 0 {
+            break;
+        }
+        let value = match iim.get(i + 5..i + 5 + len) {
+            Some(v) => v,
+            None => break,
+        };
+
+        if record == 2 {
+            if let Some((_, field)) =
+                IPTC_FIELDS.iter().find(|(d, _)| *d == dataset)
+            {
+                fields.push((
+                    field.to_string(),
+                    String::from_utf8_lossy(value).trim().to_string(),
+                ));
+            }
+        }
+        i += 5 + len;
+    }
+
+    fields
+}
+
+/// Find an image resource in the Photoshop APP13 segment of a JPEG
+fn jpeg_photoshop_resource(data: &[u8], id: u16) -> Option<&[u8]> {
+    const PHOTOSHOP_SIGNATURE: &[u8] = b"Photoshop 3.0\0";
+
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut pos = 2;
+    while data.get(pos) == Some(&0xFF) {
+        let marker = *data.get(pos + 1)?;
+        // Image data starts at SOS; metadata segments all come before it
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?])
+            as usize;
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+
+        if marker == 0xED && segment.starts_with(PHOTOSHOP_SIGNATURE) {
+            return photoshop_resource(
+                &segment[PHOTOSHOP_SIGNATURE.len()..],
+                id,
+            );
+        }
+        pos += 2 + len;
+    }
+
+    None
+}
+
+fn photoshop_resource(mut data: &[u8], id: u16) -> Option<&[u8]> {
+    while data.len() >= 12 && data.starts_with(b"8BIM") {
+        let resource_id = u16::from_be_bytes([data[4], data[5]]);
+        // The name is a Pascal string padded to an even length
+        let name_len = data[6] as usize;
+        let size_offset = 6 + ((name_len + 2) & !1);
+        let size = u32::from_be_bytes(
+            data.get(size_offset..size_offset + 4)?.try_into().ok()?,
+        ) as usize;
+        let start = size_offset + 4;
+        let body = data.get(start..start + size)?;
+
+        if resource_id == id {
+            return Some(body);
+        }
+        // Bodies are padded to an even length too
+        data = data.get(start + ((size + 1) & !1)..)?;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(namespace: &str, descriptor: &str) -> Tag {
+        Tag {
+            namespace: namespace.to_string(),
+            descriptor: descriptor.to_string(),
+        }
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Fields {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// An image resource with an empty name
+    fn resource(id: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = b"8BIM".to_vec();
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        data.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            data.push(0);
+        }
+        data
+    }
+
+    fn dataset(record: u8, dataset: u8, value: &str) -> Vec<u8> {
+        let mut data = vec![0x1C, record, dataset];
+        data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
+        data
+    }
+
+    /// A JPEG holding only an APP13 segment with the given IPTC-IIM data
+    fn jpeg_with_iptc(iim: &[u8]) -> Vec<u8> {
+        let mut segment = b"Photoshop 3.0\0".to_vec();
+        segment.extend(resource(PHOTOSHOP_IPTC, iim));
+
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xED];
+        data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        data.extend(segment);
+        data.extend_from_slice(&[0xFF, 0xDA]);
+        data
+    }
+
+    #[test]
+    fn photoshop_resource_skips_other_resources() {
+        let mut data = resource(0x0409, b"odd");
+        data.extend(resource(PHOTOSHOP_IPTC, b"iptc"));
+
+        assert_eq!(
+            photoshop_resource(&data, PHOTOSHOP_IPTC),
+            Some(&b"iptc"[..])
+        );
+        assert_eq!(photoshop_resource(&data, 0x0422), None);
+    }
+
+    #[test]
+    fn photoshop_resource_rejects_truncated_data() {
+        let data = resource(PHOTOSHOP_IPTC, b"iptc");
+        assert_eq!(
+            photoshop_resource(&data[..data.len() - 2], PHOTOSHOP_IPTC),
+            None
+        );
+    }
+
+    #[test]
+    fn iptc_fields_reads_application_record() {
+        let mut iim = dataset(1, 90, "ignored");
+        iim.extend(dataset(2, 25, "sunset"));
+        iim.extend(dataset(2, 25, "beach"));
+        iim.extend(dataset(2, 5, " Evening "));
+        iim.extend(dataset(2, 80, "byline"));
+
+        assert_eq!(
+            iptc_fields(&jpeg_with_iptc(&iim)),
+            fields(&[
+                ("keywords", "sunset"),
+                ("keywords", "beach"),
+                ("title", "Evening"),
+            ])
+        );
+    }
+
+    #[test]
+    fn iptc_fields_ignores_non_jpegs() {
+        let iim = dataset(2, 25, "sunset");
+        assert!(iptc_fields(&jpeg_with_iptc(&iim)[2..]).is_empty());
+    }
+
+    #[test]
+    fn parse_xmp_reads_lists_and_attributes() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+            <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+              <rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/"
+                  dc:source="https://example.com/1">
+                <dc:subject>
+                  <rdf:Bag>
+                    <rdf:li>sunset</rdf:li>
+                    <rdf:li>beach &amp; sea</rdf:li>
+                  </rdf:Bag>
+                </dc:subject>
+                <dc:title>
+                  <rdf:Alt><rdf:li xml:lang="x-default">Evening</rdf:li></rdf:Alt>
+                </dc:title>
+                <dc:creator><rdf:Seq><rdf:li>someone</rdf:li></rdf:Seq></dc:creator>
+              </rdf:Description>
+            </rdf:RDF>
+          </x:xmpmeta>"#;
+
+        assert_eq!(
+            parse_xmp(xmp).unwrap(),
+            fields(&[
+                ("source", "https://example.com/1"),
+                ("keywords", "sunset"),
+                ("keywords", "beach & sea"),
+                ("title", "Evening"),
+            ])
+        );
+    }
+
+    #[test]
+    fn field_map_tags_by_mapping() {
+        let tags = FieldMap::default().tags(&fields(&[
+            ("keywords", "Blue Sky"),
+            ("keywords", "artist:Some One"),
+            ("tag_string_artist", "a_b  c"),
+            ("title", "Evening  Walk"),
+            ("unmapped", "ignored"),
+            ("keywords", "blue sky"),
+        ]));
+
+        assert_eq!(
+            tags,
+            vec![
+                tag("general", "blue_sky"),
+                tag("artist", "some_one"),
+                tag("artist", "a_b"),
+                tag("artist", "c"),
+                tag("title", "Evening  Walk"),
+            ]
+        );
+    }
+
+    #[test]
+    fn field_map_set_overrides_and_removes() {
+        let mut field_map = FieldMap::default();
+        field_map.set("keywords=").unwrap();
+        field_map.set("creator=artist").unwrap();
+        assert!(field_map.set("no-namespace").is_err());
+
+        let tags = field_map
+            .tags(&fields(&[("keywords", "sunset"), ("creator", "Some One")]));
+        assert_eq!(tags, vec![tag("artist", "some_one")]);
+    }
+}
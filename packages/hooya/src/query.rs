@@ -0,0 +1,249 @@
+use anyhow::Result;
+use std::str::FromStr;
+
+use crate::analysis::AnalysisStatus;
+use crate::color::{self, Lab};
+use crate::proto::Tag;
+
+/// Default maximum ΔE between a queried color and a palette color
+pub const DEFAULT_COLOR_DISTANCE: f32 = 10.0;
+
+/// A single whitespace-delimited component of a search query
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    /// Match files carrying this tag, eg `artist:foo`
+    Tag(Tag),
+    /// Match images with a palette color close to this one, eg
+    /// `color:#aabbcc` or `color:#aabbcc~20` for a looser match
+    Color { lab: Lab, max_distance: f32 },
+    /// Match photos whose camera make or model contains this, eg
+    /// `camera:fujifilm`. `_` stands in for a space, eg `camera:eos_r5`
+    Camera(String),
+    /// Match photos whose lens model contains this, eg `lens:50mm`
+    Lens(String),
+    /// Match photos taken within a range of (possibly partial) dates, both
+    /// ends inclusive, eg `taken:2023-05`, `taken:2021..2022-06` or
+    /// `taken:..2020`
+    Taken {
+        from: Option<String>,
+        to: Option<String>,
+    },
+    /// Match files of a mimetype, eg `type:image/png`, or of any subtype
+    /// given just the top-level type, eg `type:video`
+    Mimetype(String),
+    /// Match files by how their analysis went, eg `analysis:failed`
+    Analysis(AnalysisStatus),
+    /// Match files not matching the inner term, eg `-artist:foo`
+    Not(Box<Term>),
+}
+
+/// Conjunction of terms, all of which a file must satisfy to match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    pub terms: Vec<Term>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+impl Query {
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+}
+
+impl FromStr for Term {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(rest) = s.strip_prefix('-') {
+            return Ok(Term::Not(Box::new(Term::from_str(rest)?)));
+        }
+
+        let tag = Tag::from(s);
+        if tag.namespace.is_empty() || tag.descriptor.is_empty() {
+            return Err(anyhow::anyhow!("Malformed query term \"{}\"", s));
+        }
+
+        match tag.namespace.as_str() {
+            "color" => {
+                let (hex, max_distance) = match tag.descriptor.split_once('~') {
+                    Some((hex, d)) => (hex, d.parse()?),
+                    None => (tag.descriptor.as_str(), DEFAULT_COLOR_DISTANCE),
+                };
+
+                Ok(Term::Color {
+                    lab: Lab::from(color::parse_hex(hex)?),
+                    max_distance,
+                })
+            }
+            "camera" => Ok(Term::Camera(tag.descriptor)),
+            "lens" => Ok(Term::Lens(tag.descriptor)),
+            "type" => Ok(Term::Mimetype(tag.descriptor)),
+            "analysis" => {
+                Ok(Term::Analysis(AnalysisStatus::from_str(&tag.descriptor)?))
+            }
+            "taken" => {
+                let (from, to) = match tag.descriptor.split_once("..") {
+                    Some((from, to)) => (from, to),
+                    None => (tag.descriptor.as_str(), tag.descriptor.as_str()),
+                };
+
+                let bound = |d: &str| -> Result<Option<String>> {
+                    if d.is_empty() {
+                        return Ok(None);
+                    }
+                    if !d.chars().all(|c| c.is_ascii_digit() || c == '-') {
+                        return Err(anyhow::anyhow!(
+                            "Malformed date \"{}\"",
+                            d
+                        ));
+                    }
+                    Ok(Some(d.to_string()))
+                };
+
+                Ok(Term::Taken {
+                    from: bound(from)?,
+                    to: bound(to)?,
+                })
+            }
+            _ => Ok(Term::Tag(tag)),
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let terms = s
+            .split_whitespace()
+            .map(Term::from_str)
+            .collect::<Result<Vec<Term>>>()?;
+
+        Ok(Query { terms })
+    }
+}
+
+impl FromStr for Sort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" | "newest" => Ok(Sort::Newest),
+            "oldest" => Ok(Sort::Oldest),
+            _ => Err(anyhow::anyhow!("Unknown sort order \"{}\"", s)),
+        }
+    }
+}
+
+impl ToString for Sort {
+    fn to_string(&self) -> String {
+        match self {
+            Sort::Newest => "newest",
+            Sort::Oldest => "oldest",
+        }
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(s: &str) -> Term {
+        Term::from_str(s).unwrap()
+    }
+
+    fn tag(namespace: &str, descriptor: &str) -> Term {
+        Term::Tag(Tag {
+            namespace: namespace.to_string(),
+            descriptor: descriptor.to_string(),
+        })
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(
+            term("-artist:foo"),
+            Term::Not(Box::new(tag("artist", "foo")))
+        );
+        assert_eq!(
+            term("--artist:foo"),
+            Term::Not(Box::new(Term::Not(Box::new(tag("artist", "foo")))))
+        );
+        assert!(Term::from_str("-").is_err());
+    }
+
+    #[test]
+    fn query_terms() {
+        let query = Query::from_str("  artist:foo -artist:bar  ").unwrap();
+        assert_eq!(
+            query.terms,
+            vec![
+                tag("artist", "foo"),
+                Term::Not(Box::new(tag("artist", "bar")))
+            ]
+        );
+        assert_eq!(term("sunset"), tag("general", "sunset"));
+        assert!(Query::from_str("").unwrap().is_empty());
+        assert!(Query::from_str("artist:").is_err());
+    }
+
+    #[test]
+    fn camera_and_lens() {
+        assert_eq!(term("camera:eos_r5"), Term::Camera("eos_r5".to_string()));
+        assert_eq!(term("lens:50mm"), Term::Lens("50mm".to_string()));
+    }
+
+    #[test]
+    fn taken_ranges() {
+        let taken = |from: Option<&str>, to: Option<&str>| Term::Taken {
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+        };
+
+        assert_eq!(
+            term("taken:2023-05"),
+            taken(Some("2023-05"), Some("2023-05"))
+        );
+        assert_eq!(
+            term("taken:2021..2022-06"),
+            taken(Some("2021"), Some("2022-06"))
+        );
+        assert_eq!(term("taken:..2020"), taken(None, Some("2020")));
+        assert_eq!(term("taken:2020.."), taken(Some("2020"), None));
+        assert!(Term::from_str("taken:yesterday").is_err());
+    }
+
+    #[test]
+    fn mimetype() {
+        assert_eq!(
+            term("type:image/png"),
+            Term::Mimetype("image/png".to_string())
+        );
+        assert_eq!(term("type:video"), Term::Mimetype("video".to_string()));
+        assert_eq!(
+            term("-type:video"),
+            Term::Not(Box::new(Term::Mimetype("video".to_string())))
+        );
+        assert!(Term::from_str("type:").is_err());
+    }
+
+    #[test]
+    fn analysis_status() {
+        assert_eq!(
+            term("analysis:failed"),
+            Term::Analysis(AnalysisStatus::Failed)
+        );
+        assert_eq!(
+            term("analysis:unsupported"),
+            Term::Analysis(AnalysisStatus::Unsupported)
+        );
+        assert!(Term::from_str("analysis:sideways").is_err());
+    }
+}
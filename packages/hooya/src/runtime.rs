@@ -1,16 +1,184 @@
-use crate::local::{self, FileRow, ImageRow, TagMapRow, ThumbnailRow};
-use crate::proto::{File, Tag, Thumbnail};
+use crate::analysis::{AnalysisStatus, Unsupported};
+use crate::bktree::BkTree;
+use crate::color::Lab;
+use crate::image::{ThumbnailFormat, Transparency};
+use crate::jobs::{JobKind, JobState};
+use crate::local::{
+    self, AudioRow, FileRow, ImageColorRow, ImageExifRow, ImageHashRow,
+    ImageRow, JobRow, PageRow, SavedSearchRow, TagMapRow, ThumbnailRow,
+    VideoRow,
+};
+use crate::metadata::FieldMap;
+use crate::proto::{
+    Analysis, DuplicateGroup, Exif, File, Job, ReverseSearchResult,
+    SavedSearch, SimilarFile, Tag, Thumbnail,
+};
+use crate::query::{Query, Sort};
+use crate::thumb_cache::ThumbCache;
+use crate::watch::{AfterImport, FileStamp, Inbox, DEFAULT_SETTLE};
 use anyhow::Result;
+use futures_util::FutureExt;
+use image::{DynamicImage, Frame};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{BufReader, Cursor, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::sync::{broadcast, Notify, OnceCell};
+
+// Number of dominant colors kept per image
+const PALETTE_SIZE: usize = 6;
+
+// Animated thumbnails are only made up to this size as every frame adds to
+// the file
+const MAX_ANIMATED_THUMB_EDGE: u32 = 640;
+
+// Smallest thumbnail generated on demand
+const MIN_THUMB_EDGE: u32 = 16;
+
+// Job updates buffered per watcher before the slowest start missing them
+const JOB_UPDATES_CAPACITY: usize = 256;
+
+/// Which still thumbnails are generated and how they are encoded
+#[derive(Clone, Debug)]
+pub struct ThumbnailConfig {
+    // Long edges; sizes larger than the source are skipped
+    pub sizes: Vec<u32>,
+    pub format: ThumbnailFormat,
+    // 1-100, for lossy formats
+    pub quality: u8,
+    pub transparency: Transparency,
+    // Largest long edge generated on demand for sizes not made at import
+    pub max_on_demand_edge: u32,
+    // Bytes on disk allowed for on-demand thumbnails before the least
+    // recently used are deleted
+    pub cache_bytes: u64,
+}
 
 pub struct Runtime {
     pub filestore_path: PathBuf,
     pub db: local::Db,
+    thumbnail_config: ThumbnailConfig,
+    // Whether artist and album tags of audio files become hooya tags
+    audio_tags: bool,
+    // How XMP and IPTC keywords, titles and sources embedded in images
+    // become hooya tags, or None to ignore them
+    embedded_tags: Option<FieldMap>,
+    // pHash of every image, kept in memory to answer similarity queries
+    similarity_index: RwLock<BkTree<IndexedImage>>,
+    thumb_cache: Mutex<ThumbCache>,
+    // Held while an on-demand thumbnail is generated so concurrent requests
+    // for it wait for the first instead of duplicating the work
+    generating: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+    // Wakes idle workers when a job is queued
+    jobs_available: Notify,
+    job_updates: broadcast::Sender<Job>,
+    ffmpeg_installed: OnceCell<bool>,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            sizes: vec![320, 640, 1280],
+            format: ThumbnailFormat::Jpeg,
+            quality: 85,
+            transparency: Transparency::default(),
+            max_on_demand_edge: 2048,
+            cache_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// An image in the similarity index. Its difference hash confirms matches
+/// found by perceptual hash
+#[derive(Clone, PartialEq)]
+struct IndexedImage {
+    cid: Vec<u8>,
+    dhash: u64,
+}
+
+impl From<ImageHashRow> for IndexedImage {
+    fn from(h: ImageHashRow) -> Self {
+        IndexedImage {
+            cid: h.cid,
+            dhash: h.dhash,
+        }
+    }
 }
 
 impl Runtime {
+    pub async fn new(
+        filestore_path: PathBuf,
+        db: local::Db,
+        thumbnail_config: ThumbnailConfig,
+        audio_tags: bool,
+        embedded_tags: Option<FieldMap>,
+    ) -> Result<Self> {
+        let mut similarity_index = BkTree::default();
+        for h in db.image_hashes().await? {
+            similarity_index.insert(h.phash, h.into());
+        }
+
+        let thumb_cache = load_thumb_cache(
+            &filestore_path.join("thumbs"),
+            &thumbnail_config,
+        )?;
+
+        db.reset_jobs().await?;
+        let (job_updates, _) = broadcast::channel(JOB_UPDATES_CAPACITY);
+
+        Ok(Runtime {
+            filestore_path,
+            db,
+            thumbnail_config,
+            audio_tags,
+            embedded_tags,
+            similarity_index: RwLock::new(similarity_index),
+            thumb_cache: Mutex::new(thumb_cache),
+            generating: Mutex::new(HashMap::new()),
+            jobs_available: Notify::new(),
+            job_updates,
+            ffmpeg_installed: OnceCell::new(),
+        })
+    }
+
     pub async fn import_from_filestore(&self, cid: Vec<u8>) -> Result<()> {
+        self.index_file(cid.clone()).await?;
+        self.analyze(cid).await
+    }
+
+    /// Index a freshly stored file and queue its analysis, if it is of a
+    /// type that has any
+    pub async fn index_upload(&self, cid: Vec<u8>) -> Result<Option<Job>> {
+        if self.index_file(cid.clone()).await?.is_none() {
+            // Quick to conclude there is nothing to do, and records as much
+            self.analyze(cid).await?;
+            return Ok(None);
+        }
+
+        self.db
+            .set_analysis(
+                cid.clone(),
+                &AnalysisStatus::Pending.to_string(),
+                None,
+            )
+            .await?;
+        Ok(Some(self.enqueue_job(JobKind::Analyze, cid).await?))
+    }
+
+    /// Record the size and type of a stored file. Gives the matcher type of
+    /// files that have further detail to extract
+    async fn index_file(
+        &self,
+        cid: Vec<u8>,
+    ) -> Result<Option<infer::MatcherType>> {
         let cid_store_path = self.derive_store_path(&cid)?;
 
         let size: i64 =
@@ -27,18 +195,253 @@ impl Runtime {
 
         self.db.new_file(f).await?;
 
-        // Extract additional detail about the file given its type
-        if let Some(inferred_mimetype) = inferred {
-            match inferred_mimetype.matcher_type() {
-                infer::MatcherType::Image => {
-                    self.import_image(cid, &mimetype.unwrap()).await?
+        Ok(inferred.map(|i| i.matcher_type()).filter(|t| {
+            matches!(
+                t,
+                infer::MatcherType::Image
+                    | infer::MatcherType::Video
+                    | infer::MatcherType::Audio
+                    | infer::MatcherType::Archive
+            )
+        }))
+    }
+
+    /// Extract additional detail about an indexed file given its type and
+    /// record how that went. Files hooyad has no decoder for are not an
+    /// error; they stay stored but unanalyzed
+    pub async fn analyze(&self, cid: Vec<u8>) -> Result<()> {
+        // Decoders of untrusted input are known to panic, which would
+        // otherwise take a worker down with them
+        let result = AssertUnwindSafe(self.analyze_by_type(cid.clone()))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|panic| {
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(anyhow::anyhow!("Decoder panicked: {}", msg))
+            });
+
+        let (status, reason) = match &result {
+            Ok(()) => (AnalysisStatus::Analyzed, None),
+            Err(e) if e.is::<Unsupported>() => {
+                (AnalysisStatus::Unsupported, Some(e.to_string()))
+            }
+            Err(e) => (AnalysisStatus::Failed, Some(e.to_string())),
+        };
+        self.db
+            .set_analysis(cid, &status.to_string(), reason)
+            .await?;
+
+        match result {
+            Err(e) if !e.is::<Unsupported>() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn analyze_by_type(&self, cid: Vec<u8>) -> Result<()> {
+        let inferred = infer::get_from_path(self.derive_store_path(&cid)?)?
+            .ok_or_else(|| Unsupported("Unknown file type".to_string()))?;
+        let mimetype = inferred.mime_type();
+
+        match inferred.matcher_type() {
+            infer::MatcherType::Image => self.import_image(cid, mimetype).await,
+            infer::MatcherType::Video => self.import_video(cid, mimetype).await,
+            infer::MatcherType::Audio => self.import_audio(cid, mimetype).await,
+            infer::MatcherType::Archive if mimetype == "application/zip" => {
+                self.import_archive(cid).await
+            }
+            _ => {
+                Err(Unsupported(format!("No analysis for {}", mimetype)).into())
+            }
+        }
+    }
+
+    pub async fn enqueue_job(
+        &self,
+        kind: JobKind,
+        cid: Vec<u8>,
+    ) -> Result<Job> {
+        let id = self.db.new_job(&kind.to_string(), cid).await?;
+        let job = job_from_row(self.db.job(id).await?);
+
+        self.jobs_available.notify_one();
+        let _ = self.job_updates.send(job.clone());
+
+        Ok(job)
+    }
+
+    pub async fn job(&self, id: i64) -> Result<Job> {
+        Ok(job_from_row(self.db.job(id).await?))
+    }
+
+    pub async fn jobs(
+        &self,
+        state: Option<JobState>,
+        limit: u32,
+    ) -> Result<Vec<Job>> {
+        let jobs = self
+            .db
+            .jobs(state.map(|s| s.to_string()), limit)
+            .await?
+            .into_iter()
+            .map(job_from_row)
+            .collect();
+
+        Ok(jobs)
+    }
+
+    /// Queue a failed job to run again
+    pub async fn retry_job(&self, id: i64) -> Result<Job> {
+        self.db.retry_job(id).await?;
+        let job = self.job(id).await?;
+
+        self.jobs_available.notify_one();
+        let _ = self.job_updates.send(job.clone());
+
+        Ok(job)
+    }
+
+    /// Every change of state of any job from here on
+    pub fn watch_jobs(&self) -> broadcast::Receiver<Job> {
+        self.job_updates.subscribe()
+    }
+
+    /// Copy a file on this machine into the filestore and index it, giving
+    /// its CID and whether it was stored already
+    pub async fn store_local_file(
+        &self,
+        path: &Path,
+    ) -> Result<(Vec<u8>, bool)> {
+        let tmp_name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let tmp_path = self.filestore_path.join("tmp").join(tmp_name);
+
+        let mut sha_context = crate::cid::new_digest_context();
+        let mut tmp = fs::File::create(&tmp_path)?;
+        for chunk in crate::ChunkedReader::new(fs::File::open(path)?) {
+            let chunk = chunk?;
+            sha_context.update(&chunk);
+            tmp.write_all(&chunk)?;
+        }
+
+        let cid = crate::cid::wrap_digest(sha_context.finish())?;
+        let cid_store_path = self.derive_store_path(&cid)?;
+        if let Some(name) = path.file_name() {
+            self.db
+                .add_file_name(cid.clone(), &name.to_string_lossy())
+                .await?;
+        }
+
+        if cid_store_path.is_file()
+            && self.db.file_row(cid.clone()).await.is_ok()
+        {
+            fs::remove_file(tmp_path)?;
+            return Ok((cid, true));
+        }
+
+        if let Some(parent) = cid_store_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(tmp_path, cid_store_path)?;
+        self.index_upload(cid.clone()).await?;
+
+        Ok((cid, false))
+    }
+
+    /// Import files dropped into `root` as they finish being written,
+    /// forever. Imports are recorded so a restart doesn't repeat them
+    pub async fn watch_dir(
+        &self,
+        root: &Path,
+        tags: Vec<Tag>,
+        after: &AfterImport,
+    ) -> Result<()> {
+        after.check(root)?;
+        let mut inbox = Inbox::new(root, DEFAULT_SETTLE, false)?;
+
+        loop {
+            for path in inbox.next().await? {
+                if let Err(e) =
+                    self.import_watched(root, &path, &tags, after).await
+                {
+                    log::error!("Error importing {}: {}", path.display(), e);
                 }
-                infer::MatcherType::Video => {}
-                _ => {}
             }
         }
+    }
 
-        Ok(())
+    async fn import_watched(
+        &self,
+        root: &Path,
+        path: &Path,
+        tags: &[Tag],
+        after: &AfterImport,
+    ) -> Result<()> {
+        let stamp = FileStamp::of(path)?;
+        let path_str = path.to_string_lossy();
+        let seen = self.db.watched_file(&path_str).await?
+            == Some((stamp.size as i64, stamp.modified));
+        if stamp.size == 0 || seen {
+            return Ok(());
+        }
+
+        let (cid, _) = self.store_local_file(path).await?;
+        if !tags.is_empty() {
+            self.tag_cid(cid.clone(), tags.to_vec()).await?;
+        }
+        self.db
+            .mark_watched(&path_str, stamp.size as i64, stamp.modified, cid)
+            .await?;
+
+        after.apply(root, path)
+    }
+
+    /// Run queued jobs one at a time, forever. Start one of these per worker
+    pub async fn work(&self) {
+        loop {
+            // Register interest before checking the queue so a job queued in
+            // between still wakes this worker
+            let notified = self.jobs_available.notified();
+
+            let row = match self.db.claim_job().await {
+                Ok(Some(row)) => row,
+                Ok(None) => {
+                    notified.await;
+                    continue;
+                }
+                Err(e) => {
+                    log::error!("Could not claim job: {}", e);
+                    notified.await;
+                    continue;
+                }
+            };
+
+            let id = row.id;
+            let _ = self.job_updates.send(job_from_row(row.clone()));
+
+            let result = match JobKind::from_str(&row.kind) {
+                Ok(JobKind::Analyze) => self.analyze(row.cid).await,
+                Ok(JobKind::Reimport) => {
+                    self.import_from_filestore(row.cid).await
+                }
+                Ok(JobKind::RegenerateThumbnails) => {
+                    self.regenerate_thumbnails(row.cid).await.map(|_| ())
+                }
+                Err(e) => Err(e),
+            };
+
+            let error = result.err().map(|e| e.to_string());
+            if let Err(e) = self.db.finish_job(id, error).await {
+                log::error!("Could not record outcome of job {}: {}", id, e);
+                continue;
+            }
+
+            if let Ok(job) = self.job(id).await {
+                let _ = self.job_updates.send(job);
+            }
+        }
     }
 
     pub async fn indexed_file(&self, cid: Vec<u8>) -> Result<File> {
@@ -51,11 +454,13 @@ impl Runtime {
 
         // The reason for this cute misdirection is that indexed (ie local)
         // File may not always map 1-to-1 with the concept of Files on the network
+        let names = self.db.file_names(file_row.cid.clone()).await?;
         let file = File {
             cid: file_row.cid,
             mimetype: file_row.mimetype,
             size: file_row.size,
             ext_file,
+            names,
         };
 
         Ok(file)
@@ -132,7 +537,12 @@ impl Runtime {
         Ok(final_dir.join(encoded_cid))
     }
 
-    pub fn derive_thumb_path(&self, cid: &[u8], size: u32) -> Result<PathBuf> {
+    pub fn derive_thumb_path(
+        &self,
+        cid: &[u8],
+        size: u32,
+        animated: bool,
+    ) -> Result<PathBuf> {
         // TODO May be more useful to keep the encoded version around instead
         // of (de|en)coding it often?
         let encoded_cid = crate::cid::encode(cid);
@@ -153,27 +563,54 @@ impl Runtime {
             .join(size.to_string())
             .join(prefix);
 
-        Ok(final_dir.join([encoded_cid, size.to_string()].join("_thumb")))
+        let mut file_name = [encoded_cid, size.to_string()].join("_thumb");
+        if animated {
+            file_name.push_str("_animated");
+        }
+
+        Ok(final_dir.join(file_name))
     }
 
+    /// A page of files matching a query in an order that is random but
+    /// fixed for a given seed, so paging through with the same seed never
+    /// repeats or skips a file. A seed is picked if none is given and
+    /// returned so the sequence can be continued
     pub async fn random_local_file(
         &self,
         count: u32,
-    ) -> Result<Vec<crate::proto::File>> {
-        let files = self
-            .db
-            .random_file(count)
-            .await?
-            .into_iter()
-            .map(|f| crate::proto::File {
-                cid: f.cid,
-                mimetype: f.mimetype,
-                size: f.size,
-                ext_file: None, // TODO INNER JOIN
-            })
-            .collect();
+        query: &str,
+        seed: Option<u64>,
+        page_token: String,
+    ) -> Result<(Vec<crate::proto::File>, u64, String)> {
+        let query = Query::from_str(query)?;
+        let seed = seed.unwrap_or_else(rand::random);
+        let offset: usize = if page_token.is_empty() {
+            0
+        } else {
+            page_token.parse()?
+        };
+
+        let mut row_ids = self.db.matching_row_ids(&query).await?;
+        // StdRng may change algorithm between rand releases, which would
+        // break returned seeds; ChaCha8 is fixed
+        row_ids.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+
+        let page_ids = row_ids
+            .get(offset..)
+            .unwrap_or_default()
+            .iter()
+            .take(count as usize)
+            .copied()
+            .collect::<Vec<i64>>();
+
+        let mut files = vec![];
+        for f in self.db.files_by_row_id(&page_ids).await? {
+            files.push(self.indexed_file(f.cid).await?);
+        }
+
+        let next_page_token = (offset + page_ids.len()).to_string();
 
-        Ok(files)
+        Ok((files, seed, next_page_token))
     }
 
     pub async fn local_file_page(
@@ -181,140 +618,1342 @@ impl Runtime {
         page_size: u32,
         page_token: String,
         oldest_first: bool,
+        query: &str,
     ) -> Result<(Vec<crate::proto::File>, String)> {
-        let offset: u32 = page_token.parse()?;
-        let files = self
-            .db
-            .file_page(page_size, offset, oldest_first)
-            .await?
+        let sort = if oldest_first {
+            Sort::Oldest
+        } else {
+            Sort::Newest
+        };
+
+        self.search_page(&Query::from_str(query)?, sort, page_size, page_token)
+            .await
+    }
+
+    async fn search_page(
+        &self,
+        query: &Query,
+        sort: Sort,
+        page_size: u32,
+        page_token: String,
+    ) -> Result<(Vec<crate::proto::File>, String)> {
+        let offset: u32 = if page_token.is_empty() {
+            0
+        } else {
+            page_token.parse()?
+        };
+        let file_rows =
+            self.db.file_page(query, page_size, offset, sort).await?;
+        let mut images = self.page_images(&file_rows).await?;
+        let files = file_rows
             .into_iter()
             .map(|f| crate::proto::File {
+                ext_file: images
+                    .remove(&f.cid)
+                    .map(crate::proto::file::ExtFile::Image),
                 cid: f.cid,
                 mimetype: f.mimetype,
                 size: f.size,
-                ext_file: None, // TODO INNER JOIN
+                names: vec![],
             })
             .collect();
 
         Ok((files, (offset + page_size).to_string()))
     }
 
+    /// Image info of every analyzed image in a page, looked up in batches
+    /// rather than per file
+    async fn page_images(
+        &self,
+        file_rows: &[FileRow],
+    ) -> Result<HashMap<Vec<u8>, crate::proto::Image>> {
+        let cids = file_rows
+            .iter()
+            .filter(|f| {
+                f.mimetype
+                    .as_deref()
+                    .map_or(false, |m| m.starts_with("image"))
+            })
+            .map(|f| f.cid.clone())
+            .collect::<Vec<Vec<u8>>>();
+        if cids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut thumbnails: HashMap<Vec<u8>, Vec<Thumbnail>> = HashMap::new();
+        for t in self.db.thumbnails_by_source_cids(&cids).await? {
+            thumbnails
+                .entry(t.source_cid.clone())
+                .or_default()
+                .push(thumbnail_from_row(&t));
+        }
+
+        let images = self
+            .db
+            .image_rows(&cids)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let thumbnails =
+                    thumbnails.remove(&row.cid).unwrap_or_default();
+                (row.cid.clone(), image_from_row(row, thumbnails))
+            })
+            .collect();
+
+        Ok(images)
+    }
+
+    pub async fn create_saved_search(
+        &self,
+        name: String,
+        query: String,
+        sort: String,
+        owner: String,
+    ) -> Result<SavedSearch> {
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Saved search must be named"));
+        }
+
+        // Reject anything that would fail later when executed
+        Query::from_str(&query)?;
+        let sort = Sort::from_str(&sort)?.to_string();
+
+        let id = self
+            .db
+            .new_saved_search(SavedSearchRow {
+                id: 0,
+                name: name.clone(),
+                query: query.clone(),
+                sort: sort.clone(),
+                owner: owner.clone(),
+                last_viewed: None,
+            })
+            .await?;
+
+        Ok(SavedSearch {
+            id,
+            name,
+            query,
+            sort,
+            owner,
+            new_count: 0,
+        })
+    }
+
+    pub async fn saved_searches(
+        &self,
+        owner: String,
+    ) -> Result<Vec<SavedSearch>> {
+        let owner = if owner.is_empty() { None } else { Some(owner) };
+        let mut saved_searches = vec![];
+
+        for s in self.db.saved_searches(owner).await? {
+            let query = Query::from_str(&s.query)?;
+            let new_count = self
+                .db
+                .count_files(&query, s.last_viewed)
+                .await?
+                .try_into()?;
+
+            saved_searches.push(SavedSearch {
+                id: s.id,
+                name: s.name,
+                query: s.query,
+                sort: s.sort,
+                owner: s.owner,
+                new_count,
+            });
+        }
+
+        Ok(saved_searches)
+    }
+
+    /// Delete a saved search, returning whether there was one to delete
+    pub async fn delete_saved_search(
+        &self,
+        id: i64,
+        owner: String,
+    ) -> Result<bool> {
+        if self.owned_saved_search(id, owner).await?.is_none() {
+            return Ok(false);
+        }
+        self.db.delete_saved_search(id).await?;
+        Ok(true)
+    }
+
+    /// A page of a saved search's results, or None if there is no such
+    /// search
+    pub async fn execute_saved_search(
+        &self,
+        id: i64,
+        owner: String,
+        page_size: u32,
+        page_token: String,
+    ) -> Result<Option<(Vec<crate::proto::File>, String)>> {
+        let saved_search = match self.owned_saved_search(id, owner).await? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let query = Query::from_str(&saved_search.query)?;
+        let sort = Sort::from_str(&saved_search.sort)?;
+        let first_page = page_token.is_empty() || page_token == "0";
+
+        let page = self
+            .search_page(&query, sort, page_size, page_token)
+            .await?;
+
+        // Viewing the first page of results counts as having seen everything
+        // new in this search
+        if first_page {
+            self.db.touch_saved_search(id).await?;
+        }
+
+        Ok(Some(page))
+    }
+
+    /// A saved search, if it belongs to `owner`. An empty owner matches any
+    async fn owned_saved_search(
+        &self,
+        id: i64,
+        owner: String,
+    ) -> Result<Option<SavedSearchRow>> {
+        Ok(self
+            .db
+            .saved_search(id)
+            .await?
+            .filter(|s| owner.is_empty() || s.owner == owner))
+    }
+
     pub async fn import_image(
         &self,
         cid: Vec<u8>,
         mimetype: &str,
     ) -> Result<()> {
         let cid_store_path = self.derive_store_path(&cid)?;
-        let (decoded_image, exif_data) =
-            crate::image::read(&cid_store_path, mimetype)?;
+        let (decoded_image, exif_data) = crate::image::read(
+            BufReader::new(fs::File::open(&cid_store_path)?),
+            mimetype,
+        )?;
+
+        // The still decoded above is the first frame and serves as the
+        // poster, so a broken animation only costs the animated thumbnails
+        let frames = crate::image::read_frames(
+            BufReader::new(fs::File::open(&cid_store_path)?),
+            mimetype,
+            MAX_ANIMATED_THUMB_EDGE,
+        )
+        .ok()
+        .flatten();
         let img_width = decoded_image.width();
         let img_height = decoded_image.height();
 
+        let palette = crate::image::palette(&decoded_image, PALETTE_SIZE);
+
         self.db
             .new_image(ImageRow {
                 cid: cid.clone(),
                 height: img_height,
                 width: img_width,
                 ratio: f64::from(img_width) / f64::from(img_height),
-                primary_color: vec![],
-                colors: vec![],
+                primary_color: palette
+                    .first()
+                    .map(|s| s.rgb.to_vec())
+                    .unwrap_or_default(),
+                colors: palette.iter().flat_map(|s| s.rgb).collect(),
+                // Nice to have, so not worth failing the import over
+                blurhash: crate::image::blurhash(&decoded_image).ok(),
+            })
+            .await?;
+
+        let color_rows: Vec<ImageColorRow> = palette
+            .iter()
+            .enumerate()
+            .map(|(rank, s)| ImageColorRow {
+                cid: cid.clone(),
+                rank: rank as u32,
+                l: s.lab.l,
+                a: s.lab.a,
+                b: s.lab.b,
+                weight: s.weight,
+            })
+            .collect();
+        self.db
+            .replace_image_colors(cid.clone(), &color_rows)
+            .await?;
+
+        if let Some(field_map) = &self.embedded_tags {
+            let fields =
+                crate::metadata::read_embedded_fields(&cid_store_path)?;
+            let tags = field_map.tags(&fields);
+            if !tags.is_empty() {
+                self.tag_cid(cid.clone(), tags).await?;
+            }
+        }
+
+        if let Some(exif_data) = exif_data.as_ref() {
+            let info = crate::image::exif_info(exif_data);
+            self.db
+                .new_image_exif(ImageExifRow {
+                    cid: cid.clone(),
+                    taken: info.taken,
+                    camera_make: info.camera_make,
+                    camera_model: info.camera_model,
+                    lens: info.lens,
+                    exposure_time: info.exposure_time,
+                    f_number: info.f_number,
+                    iso: info.iso,
+                    focal_length: info.focal_length,
+                    latitude: info.latitude,
+                    longitude: info.longitude,
+                })
+                .await?;
+        } else {
+            // EXIF read by an earlier import mustn't outlive a reimport that
+            // finds none
+            self.db.delete_image_exif(cid.clone()).await?;
+        }
+
+        // A reimport may hash differently, and the old hashes mustn't linger
+        let old_hash = self.db.image_hash(cid.clone()).await?;
+        let hash = ImageHashRow {
+            cid: cid.clone(),
+            dhash: crate::image::dhash(&decoded_image),
+            phash: crate::image::phash(&decoded_image),
+        };
+        self.db
+            .new_image_hash(ImageHashRow {
+                cid: cid.clone(),
+                ..hash
+            })
+            .await?;
+        {
+            let mut index = self.similarity_index.write().unwrap();
+            if let Some(old_hash) = old_hash {
+                index.remove(old_hash.phash, &old_hash.into());
+            }
+            index.insert(hash.phash, hash.into());
+        }
+
+        self.write_thumbnails(&cid, &decoded_image, frames.as_deref())
+            .await
+    }
+
+    pub async fn import_video(
+        &self,
+        cid: Vec<u8>,
+        mimetype: &str,
+    ) -> Result<()> {
+        let cid_store_path = self.derive_store_path(&cid)?;
+        let info = match crate::video::read_info(&cid_store_path, mimetype)? {
+            Some(info) => info,
+            // An MP4 without video is sniffed as one but is really audio
+            None if crate::video::is_mp4(mimetype) => {
+                return self.import_audio(cid, "audio/m4a").await
+            }
+            None => {
+                return Err(
+                    Unsupported(format!("No demuxer for {}", mimetype)).into()
+                )
+            }
+        };
+
+        self.db
+            .new_video(VideoRow {
+                cid: cid.clone(),
+                height: info.height,
+                width: info.width,
+                ratio: f64::from(info.width) / f64::from(info.height),
+                duration: info.duration,
+                video_codec: info.video_codec,
+                audio_codec: info.audio_codec,
+                frame_rate: info.frame_rate,
+            })
+            .await?;
+
+        // Without ffmpeg videos are indexed but have no thumbnails, and any
+        // made while it was installed are kept
+        match crate::video::poster_frame(&cid_store_path, info.duration).await?
+        {
+            Some(poster) => self.write_thumbnails(&cid, &poster, None).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn import_audio(
+        &self,
+        cid: Vec<u8>,
+        mimetype: &str,
+    ) -> Result<()> {
+        let cid_store_path = self.derive_store_path(&cid)?;
+        let info = crate::audio::read_info(&cid_store_path, mimetype)?
+            .ok_or_else(|| {
+                Unsupported(format!("No tag reader for {}", mimetype))
+            })?;
+
+        self.db
+            .new_audio(AudioRow {
+                cid: cid.clone(),
+                title: info.title,
+                artist: info.artist.clone(),
+                album: info.album.clone(),
+                duration: info.duration,
+                sample_rate: info.sample_rate,
+                channels: info.channels,
+            })
+            .await?;
+
+        if self.audio_tags {
+            // Written the way tags are typed, eg artist:daft_punk
+            let tags: Vec<Tag> =
+                [("artist", info.artist), ("album", info.album)]
+                    .into_iter()
+                    .filter_map(|(namespace, descriptor)| {
+                        Some(Tag {
+                            namespace: namespace.to_string(),
+                            descriptor: descriptor?
+                                .to_lowercase()
+                                .replace(' ', "_"),
+                        })
+                    })
+                    .collect();
+
+            if !tags.is_empty() {
+                self.tag_cid(cid.clone(), tags).await?;
+            }
+        }
+
+        // Cover art is a nicety; one that fails to decode doesn't fail the
+        // import
+        match info.cover.and_then(|c| image::load_from_memory(&c).ok()) {
+            Some(cover) => self.write_thumbnails(&cid, &cover, None).await,
+            None => self.db.delete_old_thumbnails(cid.clone()).await,
+        }
+    }
+
+    /// Index the image pages of a ZIP/CBZ archive and thumbnail its cover
+    pub async fn import_archive(&self, cid: Vec<u8>) -> Result<()> {
+        let cid_store_path = self.derive_store_path(&cid)?;
+        let pages = crate::archive::read_pages(&cid_store_path)?
+            .ok_or_else(|| Unsupported("No images in archive".to_string()))?;
+
+        let cover =
+            crate::archive::read_page_image(&cid_store_path, &pages[0].name)?;
+
+        let page_rows: Vec<PageRow> = pages
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| PageRow {
+                cid: cid.clone(),
+                page: i as u32,
+                name: p.name,
+                mimetype: p.mimetype,
+                width: p.width,
+                height: p.height,
             })
+            .collect();
+        self.db
+            .replace_archive_pages(cid.clone(), &page_rows)
             .await?;
 
-        // Clear out old thumbnails as this generates new ones
-        self.db.delete_old_thumbnails(cid.clone()).await?;
+        self.write_thumbnails(&cid, &cover, None).await
+    }
 
-        // Thumbnail sizes to generate
-        let t_sizes_long_edge = vec![320, 640, 1280];
+    /// Where in the filestore a page of an archive is, and its name and
+    /// mimetype within it
+    pub async fn archive_page(
+        &self,
+        cid: Vec<u8>,
+        page: u32,
+    ) -> Result<(PathBuf, PageRow)> {
+        let page = self
+            .db
+            .archive_page(cid.clone(), page)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No page {}", page))?;
+
+        Ok((self.derive_store_path(&cid)?, page))
+    }
+
+    /// Thumbnail a still at each size smaller than it, plus an animated
+    /// variant at the smaller sizes when frames are given. The previous
+    /// thumbnails are only replaced once all of these have been made
+    async fn write_thumbnails(
+        &self,
+        cid: &[u8],
+        still: &DynamicImage,
+        frames: Option<&[Frame]>,
+    ) -> Result<()> {
+        let rendered = match self.render_thumbnails(cid, still, frames) {
+            Ok(r) => r,
+            Err(e) => {
+                // Partly rendered thumbnails are left behind as temporaries
+                for &size in &self.thumbnail_config.sizes {
+                    for animated in [false, true] {
+                        let path =
+                            self.derive_thumb_path(cid, size, animated)?;
+                        let _ = fs::remove_file(path.with_extension("tmp"));
+                    }
+                }
+                return Err(e);
+            }
+        };
+
+        self.db.delete_old_thumbnails(cid.to_vec()).await?;
+        for (tmp_path, path, row) in rendered {
+            fs::rename(tmp_path, path)?;
+            self.db.new_thumbnail(row).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Encode thumbnails next to where they belong, to be moved into place
+    /// once they have all been made. Gives the temporary and final paths of
+    /// each with its row
+    fn render_thumbnails(
+        &self,
+        cid: &[u8],
+        still: &DynamicImage,
+        frames: Option<&[Frame]>,
+    ) -> Result<Vec<(PathBuf, PathBuf, ThumbnailRow)>> {
+        let img_width = still.width();
+        let img_height = still.height();
+
+        let config = &self.thumbnail_config;
+        let mut rendered = vec![];
 
         // Read file and thumbnail for every size listed
-        for t_size_long_edge in t_sizes_long_edge {
+        for &t_size_long_edge in &config.sizes {
             if img_width < t_size_long_edge && img_height < t_size_long_edge {
                 continue;
             }
 
             let thumb_store_path =
-                self.derive_thumb_path(&cid, t_size_long_edge)?;
+                self.derive_thumb_path(cid, t_size_long_edge, false)?;
+            let thumb_tmp_path = thumb_store_path.with_extension("tmp");
 
             let parent = thumb_store_path.parent().unwrap();
             if !parent.is_dir() {
                 std::fs::create_dir_all(parent)?;
             }
 
-            let (thumb_height, thumb_width) = crate::image::thumbnail(
-                &decoded_image,
-                exif_data.as_ref(),
-                &thumb_store_path,
+            let thumb = crate::image::thumbnail(
+                still,
+                &thumb_tmp_path,
                 t_size_long_edge,
+                config.format,
+                config.quality,
+                config.transparency,
             )?;
 
-            let fh = std::fs::File::open(thumb_store_path)?;
-            let size = fh.metadata()?.len().try_into().unwrap(); // TODO
+            let (thumb_cid, size) = hash_thumbnail(&thumb_tmp_path)?;
 
-            let chunks = crate::ChunkedReader::new(fh);
-            let mut sha_context = crate::cid::new_digest_context();
+            rendered.push((
+                thumb_tmp_path,
+                thumb_store_path,
+                ThumbnailRow {
+                    cid: thumb_cid,
+                    size,
+                    mimetype: thumb.format.mimetype().to_string(),
+                    source_cid: cid.to_vec(),
+                    ratio: f64::from(img_width) / f64::from(img_height),
+                    height: thumb.height.into(),
+                    width: thumb.width.into(),
+                    is_animated: false,
+                    has_alpha: thumb.has_alpha,
+                },
+            ));
 
-            for c in chunks {
-                sha_context.update(&c?);
-            }
+            let frames = match frames {
+                Some(f) if t_size_long_edge <= MAX_ANIMATED_THUMB_EDGE => f,
+                _ => continue,
+            };
 
-            let thumb_cid = crate::cid::wrap_digest(sha_context.finish())?;
+            let anim_store_path =
+                self.derive_thumb_path(cid, t_size_long_edge, true)?;
+            let anim_tmp_path = anim_store_path.with_extension("tmp");
+            let (anim_height, anim_width) = crate::image::animated_thumbnail(
+                frames,
+                &anim_tmp_path,
+                t_size_long_edge,
+            )?;
 
-            self.db
-                .new_thumbnail(ThumbnailRow {
-                    cid: thumb_cid,
+            let (anim_cid, size) = hash_thumbnail(&anim_tmp_path)?;
+
+            rendered.push((
+                anim_tmp_path,
+                anim_store_path,
+                ThumbnailRow {
+                    cid: anim_cid,
                     size,
-                    mimetype: mimetype.to_string(),
-                    source_cid: cid.clone(),
+                    mimetype: "image/gif".to_string(),
+                    source_cid: cid.to_vec(),
                     ratio: f64::from(img_width) / f64::from(img_height),
-                    height: thumb_height.into(),
-                    width: thumb_width.into(),
-                    is_animated: false,
+                    height: anim_height.into(),
+                    width: anim_width.into(),
+                    is_animated: true,
+                    // GIF transparency is all-or-nothing per pixel; clients
+                    // only need to know about it for the stills
+                    has_alpha: false,
+                },
+            ));
+        }
+
+        Ok(rendered)
+    }
+
+    /// Path and mimetype of a thumbnail at any long edge, generating and
+    /// caching it first if it wasn't made at import
+    pub async fn cid_thumbnail(
+        &self,
+        cid: &[u8],
+        long_edge: u32,
+        animated: bool,
+    ) -> Result<(PathBuf, String)> {
+        let path = self.derive_thumb_path(cid, long_edge, animated)?;
+
+        if !path.is_file() {
+            let max_edge = self.thumbnail_config.max_on_demand_edge;
+            if !(MIN_THUMB_EDGE..=max_edge).contains(&long_edge) {
+                return Err(anyhow::anyhow!(
+                    "Thumbnails are only generated between {} and {}px",
+                    MIN_THUMB_EDGE,
+                    max_edge
+                ));
+            }
+
+            let lock = self
+                .generating
+                .lock()
+                .unwrap()
+                .entry(path.clone())
+                .or_default()
+                .clone();
+            let _guard = lock.lock().await;
+
+            // Whoever held the lock before may have just made it
+            if !path.is_file() {
+                let generated = self
+                    .generate_thumbnail(cid, long_edge, animated, &path)
+                    .await;
+
+                // Once an earlier holder removed the entry, a later request
+                // may have put a lock of its own there, which must stay
+                let mut generating = self.generating.lock().unwrap();
+                if generating
+                    .get(&path)
+                    .map_or(false, |l| Arc::ptr_eq(l, &lock))
+                {
+                    generating.remove(&path);
+                }
+                drop(generating);
+                generated?;
+            }
+        }
+
+        self.thumb_cache.lock().unwrap().touch(&path);
+
+        let mimetype = infer::get_from_path(&path)?
+            .map(|i| i.mime_type())
+            .unwrap_or("application/octet-stream");
+
+        Ok((path, mimetype.to_string()))
+    }
+
+    async fn generate_thumbnail(
+        &self,
+        cid: &[u8],
+        long_edge: u32,
+        animated: bool,
+        path: &Path,
+    ) -> Result<()> {
+        let mimetype = self
+            .db
+            .file_row(cid.to_vec())
+            .await?
+            .mimetype
+            .unwrap_or_default();
+        let cid_store_path = self.derive_store_path(cid)?;
+
+        let parent = path.parent().unwrap();
+        if !parent.is_dir() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Written aside and moved into place so requests that find the file
+        // never read it half-written
+        let tmp_path = path.with_extension("tmp");
+        let too_large =
+            || anyhow::anyhow!("Thumbnail would be larger than its source");
+
+        // Decoding and encoding are blocking work, so they run on threads
+        // of their own
+        if animated {
+            if long_edge > MAX_ANIMATED_THUMB_EDGE {
+                return Err(anyhow::anyhow!(
+                    "Animated thumbnails are only generated up to {}px",
+                    MAX_ANIMATED_THUMB_EDGE
+                ));
+            }
+
+            let tmp_path = tmp_path.clone();
+            tokio::task::spawn_blocking(move || {
+                let frames = crate::image::read_frames(
+                    BufReader::new(fs::File::open(&cid_store_path)?),
+                    &mimetype,
+                    long_edge,
+                )?
+                .ok_or_else(|| anyhow::anyhow!("Source is not animated"))?;
+
+                // Frames are only shrunk, so a smaller first frame means a
+                // smaller source
+                let (width, height) = frames[0].buffer().dimensions();
+                if width.max(height) < long_edge {
+                    return Err(too_large());
+                }
+
+                crate::image::animated_thumbnail(&frames, &tmp_path, long_edge)
+            })
+            .await??;
+        } else {
+            let still = if mimetype.starts_with("image") {
+                tokio::task::spawn_blocking(move || {
+                    crate::image::read(
+                        BufReader::new(fs::File::open(&cid_store_path)?),
+                        &mimetype,
+                    )
                 })
-                .await?;
+                .await??
+                .0
+            } else if mimetype.starts_with("video") {
+                let video_row =
+                    self.db.video_row(cid.to_vec()).await?.ok_or_else(
+                        || anyhow::anyhow!("Video has not been analyzed"),
+                    )?;
+                crate::video::poster_frame(&cid_store_path, video_row.duration)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("ffmpeg is not installed"))?
+            } else {
+                return Err(anyhow::anyhow!("Source has no thumbnails"));
+            };
+
+            if still.width().max(still.height()) < long_edge {
+                return Err(too_large());
+            }
+
+            let (tmp_path, config) =
+                (tmp_path.clone(), self.thumbnail_config.clone());
+            tokio::task::spawn_blocking(move || {
+                crate::image::thumbnail(
+                    &still,
+                    &tmp_path,
+                    long_edge,
+                    config.format,
+                    config.quality,
+                    config.transparency,
+                )
+            })
+            .await??;
+        }
+
+        fs::rename(&tmp_path, path)?;
+
+        // Sizes made at import are never evicted
+        if self.thumbnail_config.sizes.contains(&long_edge) {
+            return Ok(());
+        }
+
+        let size = fs::metadata(path)?.len();
+        let evicted = self
+            .thumb_cache
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), size);
+        for p in evicted {
+            // Already gone is as good as deleted
+            let _ = fs::remove_file(p);
         }
 
         Ok(())
     }
 
-    pub async fn ext_file_info(
+    /// Whether a file's thumbnails differ from the configured sizes and
+    /// format in a way that reprocessing it would fix
+    pub async fn thumbnails_outdated(&self, cid: Vec<u8>) -> Result<bool> {
+        let mimetype = match self.db.file_row(cid.clone()).await?.mimetype {
+            Some(m) => m,
+            None => return Ok(false),
+        };
+
+        // Size of whatever the thumbnails are made from, going by how the
+        // file was analyzed
+        let (width, height) = if let Some(image_row) =
+            self.db.image_row(cid.clone()).await?
+        {
+            (image_row.width, image_row.height)
+        } else if let Some(video_row) = self.db.video_row(cid.clone()).await? {
+            // Without ffmpeg they can't be made, so reprocessing the
+            // video would only find the same
+            if !self.ffmpeg_installed().await {
+                return Ok(false);
+            }
+            (video_row.width, video_row.height)
+        } else if self.db.audio_row(cid.clone()).await?.is_some() {
+            // A missing cover expects no thumbnails at all
+            self.audio_cover_size(&cid, &mimetype)?.unwrap_or((0, 0))
+        } else if let Some(page) = self.db.archive_page(cid.clone(), 0).await? {
+            (page.width, page.height)
+        } else {
+            return Ok(false);
+        };
+
+        let config = &self.thumbnail_config;
+        let expected: BTreeSet<u32> = config
+            .sizes
+            .iter()
+            .copied()
+            .filter(|s| width >= *s || height >= *s)
+            .collect();
+
+        let thumbnails = self.db.thumbnails_by_source_cid(cid.clone()).await?;
+        let stills: BTreeSet<u32> = thumbnails
+            .iter()
+            .filter(|t| !t.is_animated)
+            .map(thumb_long_edge)
+            .collect();
+        let animated: BTreeSet<u32> = thumbnails
+            .iter()
+            .filter(|t| t.is_animated)
+            .map(thumb_long_edge)
+            .collect();
+
+        // Whether the source is animated at all is only known from its
+        // existing animated thumbnails
+        let up_to_date = stills == expected
+            && thumbnails
+                .iter()
+                .filter(|t| !t.is_animated)
+                .all(|t| t.mimetype == still_mimetype(config, t.has_alpha))
+            && (animated.is_empty()
+                || animated
+                    == expected
+                        .iter()
+                        .copied()
+                        .filter(|s| *s <= MAX_ANIMATED_THUMB_EDGE)
+                        .collect());
+
+        Ok(!up_to_date)
+    }
+
+    /// Bring a file's thumbnails in line with the configured sizes and
+    /// format, reprocessing the file if they differ. Returns whether anything
+    /// was regenerated
+    pub async fn regenerate_thumbnails(&self, cid: Vec<u8>) -> Result<bool> {
+        if !self.thumbnails_outdated(cid.clone()).await? {
+            return Ok(false);
+        }
+
+        let thumbnails = self.db.thumbnails_by_source_cid(cid.clone()).await?;
+        self.import_from_filestore(cid.clone()).await?;
+
+        // Sizes no longer configured would otherwise linger on disk
+        let thumb_path = |t: &ThumbnailRow| {
+            self.derive_thumb_path(&cid, thumb_long_edge(t), t.is_animated)
+        };
+        let current: HashSet<PathBuf> = self
+            .db
+            .thumbnails_by_source_cid(cid.clone())
+            .await?
+            .iter()
+            .map(thumb_path)
+            .collect::<Result<_>>()?;
+        for t in &thumbnails {
+            let path = thumb_path(t)?;
+            if !current.contains(&path) && path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Dimensions of an audio file's cover art, read from the image header.
+    /// None when it has none or it can't be decoded, as at import
+    fn audio_cover_size(
         &self,
-        cid: Vec<u8>,
+        cid: &[u8],
         mimetype: &str,
-    ) -> Result<Option<crate::proto::file::ExtFile>> {
-        let ret = if mimetype.starts_with("image") {
-            let image_row = self.db.image_row(cid.clone()).await?;
+    ) -> Result<Option<(u32, u32)>> {
+        // Audio-only MP4s are stored under their sniffed video mimetype
+        let mimetype = if crate::video::is_mp4(mimetype) {
+            "audio/m4a"
+        } else {
+            mimetype
+        };
+
+        let cover =
+            crate::audio::read_info(&self.derive_store_path(cid)?, mimetype)?
+                .and_then(|info| info.cover);
+        Ok(cover.and_then(|c| {
+            image::io::Reader::new(Cursor::new(c))
+                .with_guessed_format()
+                .ok()?
+                .into_dimensions()
+                .ok()
+        }))
+    }
+
+    /// Whether ffmpeg is available to grab video poster frames, checked once
+    async fn ffmpeg_installed(&self) -> bool {
+        *self
+            .ffmpeg_installed
+            .get_or_init(crate::video::ffmpeg_installed)
+            .await
+    }
+
+    /// Images whose perceptual hash is within `max_distance` bits of this
+    /// one's, closest first and excluding the image itself. None when the
+    /// image has no hash
+    pub async fn similar_files(
+        &self,
+        cid: &[u8],
+        max_distance: u32,
+        count: usize,
+    ) -> Result<Option<Vec<SimilarFile>>> {
+        let phash = match self.db.image_hash(cid.to_vec()).await? {
+            Some(h) => h.phash,
+            None => return Ok(None),
+        };
+
+        let similar = self
+            .similarity_index
+            .read()
+            .unwrap()
+            .find(phash, max_distance)
+            .into_iter()
+            .filter(|(i, _)| i.cid.as_slice() != cid)
+            .take(if count == 0 { usize::MAX } else { count })
+            .map(|(i, distance)| SimilarFile {
+                cid: i.cid.clone(),
+                distance,
+            })
+            .collect();
+
+        Ok(Some(similar))
+    }
 
-            let colors: Vec<Vec<u8>> =
-                image_row.colors.chunks(3).map(|s| s.into()).collect();
-            let thumbnails = self
+    /// Find indexed images resembling an image which is not itself indexed.
+    /// Candidates come from the pHash index and are ranked by hash distance
+    /// then by how closely their palettes match
+    pub async fn reverse_search(
+        &self,
+        data: Vec<u8>,
+        max_distance: u32,
+        count: usize,
+    ) -> Result<Vec<ReverseSearchResult>> {
+        // Decoding a probe as large as an upload may be is blocking work
+        let (phash, palette) = tokio::task::spawn_blocking(move || {
+            let mimetype = match infer::get(&data) {
+                Some(i) if i.matcher_type() == infer::MatcherType::Image => {
+                    i.mime_type()
+                }
+                _ => return Err(anyhow::anyhow!("Not a recognized image")),
+            };
+
+            let (decoded_image, _) =
+                crate::image::read(Cursor::new(data.as_slice()), mimetype)?;
+            Ok((
+                crate::image::phash(&decoded_image),
+                crate::image::palette(&decoded_image, PALETTE_SIZE),
+            ))
+        })
+        .await??;
+
+        // Don't hold the lock across DB lookups
+        let candidates: Vec<(Vec<u8>, u32)> = self
+            .similarity_index
+            .read()
+            .unwrap()
+            .find(phash, max_distance)
+            .into_iter()
+            .map(|(i, d)| (i.cid.clone(), d))
+            .collect();
+
+        let mut results = vec![];
+        for (cid, distance) in candidates {
+            let colors: Vec<Lab> = self
                 .db
-                .thumbnails_by_source_cid(cid)
+                .image_colors(cid.clone())
                 .await?
                 .iter()
-                .map(|t| Thumbnail {
-                    cid: t.cid.clone(),
-                    size: t.size,
-                    mimetype: t.mimetype.clone(),
-                    source_cid: t.source_cid.clone(),
-                    height: t.height,
-                    width: t.width,
-                    aspect_ratio: t.ratio as f32,
-                    is_animated: t.is_animated,
+                .map(|c| Lab {
+                    l: c.l,
+                    a: c.a,
+                    b: c.b,
                 })
                 .collect();
 
-            Some(crate::proto::file::ExtFile::Image(crate::proto::Image {
-                height: image_row.height.into(),
-                width: image_row.width.into(),
-                aspect_ratio: image_row.ratio as f32,
-                colors,
-                thumbnails,
-            }))
+            results.push(ReverseSearchResult {
+                cid,
+                distance,
+                color_distance: crate::color::palette_distance(
+                    &palette, &colors,
+                ),
+            });
+        }
+
+        results.sort_by(|x, y| {
+            x.distance
+                .cmp(&y.distance)
+                .then(x.color_distance.total_cmp(&y.color_distance))
+        });
+        if count > 0 {
+            results.truncate(count);
+        }
+
+        Ok(results)
+    }
+
+    /// Group images whose perceptual and difference hashes are both
+    /// transitively within `max_distance` bits of each other. Distances are
+    /// relative to the first file of each group. This walks the whole
+    /// index, so it is meant to be run off the async runtime
+    pub fn find_duplicates(&self, max_distance: u32) -> Vec<DuplicateGroup> {
+        let index = self.similarity_index.read().unwrap();
+        let entries: Vec<(u64, &IndexedImage)> = index.iter().collect();
+        let hashes: HashMap<&Vec<u8>, u64> =
+            entries.iter().map(|(h, i)| (&i.cid, *h)).collect();
+
+        let mut grouped: HashSet<&Vec<u8>> = HashSet::new();
+        let mut groups = vec![];
+
+        for (hash, image) in &entries {
+            let cid = &image.cid;
+            if grouped.contains(cid) {
+                continue;
+            }
+
+            // Flood-fill out from this image through its near neighbors. The
+            // pHash finds candidates; the dHash weeds out chance matches
+            let mut members = vec![cid];
+            let mut to_visit = vec![(*hash, image.dhash)];
+            grouped.insert(cid);
+            while let Some((phash, dhash)) = to_visit.pop() {
+                for (neighbor, _) in index.find(phash, max_distance) {
+                    let confirmed =
+                        crate::bktree::hamming_distance(dhash, neighbor.dhash)
+                            <= max_distance;
+                    if confirmed && grouped.insert(&neighbor.cid) {
+                        members.push(&neighbor.cid);
+                        to_visit.push((hashes[&neighbor.cid], neighbor.dhash));
+                    }
+                }
+            }
+
+            if members.len() < 2 {
+                continue;
+            }
+
+            groups.push(DuplicateGroup {
+                file: members
+                    .into_iter()
+                    .map(|c| SimilarFile {
+                        cid: c.clone(),
+                        distance: crate::bktree::hamming_distance(
+                            *hash, hashes[c],
+                        ),
+                    })
+                    .collect(),
+            });
+        }
+
+        groups
+    }
+
+    pub async fn ext_file_info(
+        &self,
+        cid: Vec<u8>,
+        mimetype: &str,
+    ) -> Result<Option<crate::proto::file::ExtFile>> {
+        let ret = if mimetype.starts_with("image") {
+            match self.db.image_row(cid.clone()).await? {
+                Some(image_row) => {
+                    let thumbnails = self.thumbnails(cid).await?;
+
+                    Some(crate::proto::file::ExtFile::Image(image_from_row(
+                        image_row, thumbnails,
+                    )))
+                }
+                None => None,
+            }
+        } else if mimetype.starts_with("video") {
+            match self.db.video_row(cid.clone()).await? {
+                Some(video_row) => {
+                    let thumbnails = self.thumbnails(cid).await?;
+
+                    Some(crate::proto::file::ExtFile::Video(
+                        crate::proto::Video {
+                            height: video_row.height.into(),
+                            width: video_row.width.into(),
+                            aspect_ratio: video_row.ratio as f32,
+                            duration: video_row.duration as f32,
+                            video_codec: video_row.video_codec,
+                            audio_codec: video_row.audio_codec,
+                            frame_rate: video_row.frame_rate as f32,
+                            thumbnails,
+                        },
+                    ))
+                }
+                None => None,
+            }
+        } else if mimetype == "application/zip" {
+            let pages = self.db.archive_pages(cid.clone()).await?;
+            if pages.is_empty() {
+                None
+            } else {
+                let thumbnails = self.thumbnails(cid).await?;
+
+                Some(crate::proto::file::ExtFile::Document(
+                    crate::proto::Document {
+                        page_count: pages.len() as u32,
+                        pages: pages
+                            .into_iter()
+                            .map(|p| crate::proto::Page {
+                                number: p.page,
+                                name: p.name,
+                                mimetype: p.mimetype,
+                                width: p.width.into(),
+                                height: p.height.into(),
+                            })
+                            .collect(),
+                        thumbnails,
+                    },
+                ))
+            }
+        } else if mimetype.starts_with("audio") {
+            match self.db.audio_row(cid.clone()).await? {
+                Some(audio_row) => {
+                    let thumbnails = self.thumbnails(cid).await?;
+
+                    Some(crate::proto::file::ExtFile::Audio(
+                        crate::proto::Audio {
+                            title: audio_row.title,
+                            artist: audio_row.artist,
+                            album: audio_row.album,
+                            duration: audio_row.duration as f32,
+                            sample_rate: audio_row.sample_rate.unwrap_or(0),
+                            channels: audio_row.channels.unwrap_or(0).into(),
+                            thumbnails,
+                        },
+                    ))
+                }
+                None => None,
+            }
         } else {
             None
         };
 
         Ok(ret)
     }
+
+    /// How analysis of a file went. Files indexed before this was recorded
+    /// have none
+    pub async fn analysis(&self, cid: Vec<u8>) -> Result<Option<Analysis>> {
+        let analysis = self.db.analysis(cid).await?.map(|r| Analysis {
+            status: r.status,
+            reason: r.reason.unwrap_or_default(),
+            updated: r.updated,
+        });
+
+        Ok(analysis)
+    }
+
+    /// Capture details of a photo, if it had any EXIF data
+    pub async fn exif(&self, cid: Vec<u8>) -> Result<Option<Exif>> {
+        let exif = self.db.image_exif(cid).await?.map(|r| Exif {
+            taken: r.taken,
+            camera_make: r.camera_make,
+            camera_model: r.camera_model,
+            lens: r.lens,
+            exposure_time: r.exposure_time,
+            f_number: r.f_number,
+            iso: r.iso,
+            focal_length: r.focal_length,
+            latitude: r.latitude,
+            longitude: r.longitude,
+        });
+
+        Ok(exif)
+    }
+
+    async fn thumbnails(&self, cid: Vec<u8>) -> Result<Vec<Thumbnail>> {
+        let thumbnails = self
+            .db
+            .thumbnails_by_source_cid(cid)
+            .await?
+            .iter()
+            .map(thumbnail_from_row)
+            .collect();
+
+        Ok(thumbnails)
+    }
+}
+
+fn image_from_row(
+    row: ImageRow,
+    thumbnails: Vec<Thumbnail>,
+) -> crate::proto::Image {
+    crate::proto::Image {
+        height: row.height.into(),
+        width: row.width.into(),
+        aspect_ratio: row.ratio as f32,
+        colors: row.colors.chunks(3).map(|s| s.into()).collect(),
+        thumbnails,
+        blurhash: row.blurhash,
+    }
+}
+
+fn thumbnail_from_row(row: &ThumbnailRow) -> Thumbnail {
+    Thumbnail {
+        cid: row.cid.clone(),
+        size: row.size,
+        mimetype: row.mimetype.clone(),
+        source_cid: row.source_cid.clone(),
+        height: row.height,
+        width: row.width,
+        aspect_ratio: row.ratio as f32,
+        is_animated: row.is_animated,
+        has_alpha: row.has_alpha,
+    }
+}
+
+fn job_from_row(row: JobRow) -> Job {
+    Job {
+        id: row.id,
+        kind: row.kind,
+        cid: row.cid,
+        state: row.state,
+        error: row.error.unwrap_or_default(),
+        attempts: row.attempts,
+        created: row.created,
+        updated: row.updated,
+    }
+}
+
+/// CID and size of a freshly written thumbnail
+fn hash_thumbnail(path: &Path) -> Result<(Vec<u8>, i64)> {
+    let fh = std::fs::File::open(path)?;
+    let size = fh.metadata()?.len().try_into()?;
+
+    let chunks = crate::ChunkedReader::new(fh);
+    let mut sha_context = crate::cid::new_digest_context();
+
+    for c in chunks {
+        sha_context.update(&c?);
+    }
+
+    Ok((crate::cid::wrap_digest(sha_context.finish())?, size))
+}
+
+/// Mimetype still thumbnails are written as under a config, given whether
+/// they kept any transparency
+fn still_mimetype(config: &ThumbnailConfig, has_alpha: bool) -> &'static str {
+    match config.transparency {
+        Transparency::Keep(alpha_format)
+            if has_alpha && !config.format.has_alpha() =>
+        {
+            alpha_format.mimetype()
+        }
+        _ => config.format.mimetype(),
+    }
+}
+
+fn thumb_long_edge(t: &ThumbnailRow) -> u32 {
+    t.width.max(t.height) as u32
+}
+
+/// Whether a thumbnail path is of an animated variant
+fn is_animated_thumb(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map_or(false, |n| n.ends_with("_animated"))
+}
+
+/// Rebuild the on-demand thumbnail cache from disk after a restart, treating
+/// older files as less recently used. Directories of sizes made at import
+/// are left out
+fn load_thumb_cache(
+    thumbs_path: &Path,
+    config: &ThumbnailConfig,
+) -> Result<ThumbCache> {
+    let mut cached = vec![];
+    if thumbs_path.is_dir() {
+        for size_dir in fs::read_dir(thumbs_path)? {
+            let size_dir = size_dir?;
+            let pinned = size_dir
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+                .map_or(true, |s| config.sizes.contains(&s));
+            if pinned || !size_dir.file_type()?.is_dir() {
+                continue;
+            }
+
+            for prefix_dir in fs::read_dir(size_dir.path())? {
+                let prefix_dir = prefix_dir?;
+                if !prefix_dir.file_type()?.is_dir() {
+                    continue;
+                }
+
+                for thumb in fs::read_dir(prefix_dir.path())? {
+                    let thumb = thumb?;
+                    let path = thumb.path();
+
+                    // Left behind by generation that was interrupted
+                    if path.extension().map_or(false, |e| e == "tmp") {
+                        fs::remove_file(path)?;
+                        continue;
+                    }
+
+                    let metadata = thumb.metadata()?;
+                    if !metadata.is_file() {
+                        continue;
+                    }
+
+                    // Stills made before --thumbnail-format changed would
+                    // otherwise keep being served in the old format
+                    if !is_animated_thumb(&path) {
+                        let mimetype =
+                            infer::get_from_path(&path)?.map(|i| i.mime_type());
+                        let current = [
+                            still_mimetype(config, false),
+                            still_mimetype(config, true),
+                        ];
+                        if !mimetype.map_or(false, |m| current.contains(&m)) {
+                            fs::remove_file(path)?;
+                            continue;
+                        }
+                    }
+
+                    cached.push((
+                        metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                        path,
+                        metadata.len(),
+                    ));
+                }
+            }
+        }
+    }
+
+    cached.sort();
+
+    let mut thumb_cache = ThumbCache::new(config.cache_bytes);
+    for (_, path, size) in cached {
+        // The cap may have been lowered since these were made
+        for p in thumb_cache.insert(path, size) {
+            let _ = fs::remove_file(p);
+        }
+    }
+
+    Ok(thumb_cache)
 }
@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Least-recently-used bookkeeping for thumbnails generated on demand,
+/// capped by their total size on disk. Only tracks paths; deleting whatever
+/// it evicts is up to the caller
+pub struct ThumbCache {
+    capacity: u64,
+    total: u64,
+    tick: u64,
+    entries: HashMap<PathBuf, Entry>,
+    // Tracked paths by when they were last used, oldest first
+    by_last_used: BTreeMap<u64, PathBuf>,
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+impl ThumbCache {
+    pub fn new(capacity: u64) -> Self {
+        ThumbCache {
+            capacity,
+            total: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            by_last_used: BTreeMap::new(),
+        }
+    }
+
+    /// Track a thumbnail as the most recently used, returning the paths
+    /// evicted to stay under capacity. The new thumbnail itself is never
+    /// evicted
+    pub fn insert(&mut self, path: PathBuf, size: u64) -> Vec<PathBuf> {
+        self.tick += 1;
+        if let Some(old) = self.entries.insert(
+            path.clone(),
+            Entry {
+                size,
+                last_used: self.tick,
+            },
+        ) {
+            self.total -= old.size;
+            self.by_last_used.remove(&old.last_used);
+        }
+        self.total += size;
+        self.by_last_used.insert(self.tick, path);
+
+        let mut evicted = vec![];
+        while self.total > self.capacity {
+            // The newest entry is the one just inserted
+            if self.by_last_used.len() < 2 {
+                break;
+            }
+
+            let (_, p) = self.by_last_used.pop_first().unwrap();
+            self.total -= self.entries.remove(&p).unwrap().size;
+            evicted.push(p);
+        }
+
+        evicted
+    }
+
+    /// Mark a thumbnail as just used. Untracked paths, eg thumbnails made at
+    /// import, are ignored
+    pub fn touch(&mut self, path: &Path) {
+        self.tick += 1;
+        if let Some(e) = self.entries.get_mut(path) {
+            let p = self.by_last_used.remove(&e.last_used).unwrap();
+            e.last_used = self.tick;
+            self.by_last_used.insert(self.tick, p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from(name)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ThumbCache::new(10);
+        assert!(cache.insert(path("a"), 4).is_empty());
+        assert!(cache.insert(path("b"), 4).is_empty());
+        cache.touch(Path::new("a"));
+
+        assert_eq!(cache.insert(path("c"), 4), vec![path("b")]);
+        assert_eq!(cache.insert(path("d"), 4), vec![path("a")]);
+    }
+
+    #[test]
+    fn evicts_until_under_capacity() {
+        let mut cache = ThumbCache::new(10);
+        cache.insert(path("a"), 3);
+        cache.insert(path("b"), 3);
+        cache.insert(path("c"), 3);
+
+        assert_eq!(cache.insert(path("d"), 7), vec![path("a"), path("b")]);
+    }
+
+    #[test]
+    fn never_evicts_the_new_thumbnail() {
+        let mut cache = ThumbCache::new(10);
+        cache.insert(path("a"), 4);
+
+        assert_eq!(cache.insert(path("big"), 20), vec![path("a")]);
+        assert_eq!(cache.insert(path("b"), 1), vec![path("big")]);
+    }
+
+    #[test]
+    fn reinserting_replaces_the_size() {
+        let mut cache = ThumbCache::new(10);
+        cache.insert(path("a"), 6);
+        cache.insert(path("a"), 2);
+
+        assert!(cache.insert(path("b"), 8).is_empty());
+        cache.touch(Path::new("untracked"));
+        assert_eq!(cache.insert(path("c"), 1), vec![path("a")]);
+    }
+}
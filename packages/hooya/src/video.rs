@@ -0,0 +1,205 @@
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Result;
+use image::{DynamicImage, ImageFormat};
+use matroska::{Matroska, Settings, Tracktype};
+use mp4::{Mp4Reader, TrackType};
+use tokio::process::Command;
+
+use crate::analysis::Unsupported;
+
+// Invoked from $PATH; poster frames are skipped when it isn't installed
+const FFMPEG: &str = "ffmpeg";
+
+// ffmpeg is killed if it takes longer than this to grab a poster frame
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Stream details of a video as described by its container
+pub struct VideoInfo {
+    pub width: u32,
+    pub height: u32,
+    // Seconds
+    pub duration: f64,
+    pub video_codec: String,
+    pub audio_codec: Option<String>,
+    pub frame_rate: f64,
+}
+
+/// Whether a video is in an MP4/QuickTime container, which may hold only
+/// audio
+pub fn is_mp4(mimetype: &str) -> bool {
+    matches!(mimetype, "video/mp4" | "video/x-m4v" | "video/quicktime")
+}
+
+/// Read stream details from the container of an MP4/QuickTime or
+/// Matroska/WebM file. Other containers, and MP4s without a video track,
+/// give None
+pub fn read_info(path: &Path, mimetype: &str) -> Result<Option<VideoInfo>> {
+    let info = match mimetype {
+        m if is_mp4(m) => match read_mp4_info(path)? {
+            Some(info) => info,
+            None => return Ok(None),
+        },
+        "video/webm" | "video/x-matroska" => read_matroska_info(path)?,
+        _ => return Ok(None),
+    };
+
+    // Dimensions are needed for the aspect ratio
+    if info.width == 0 || info.height == 0 {
+        return Err(Unsupported("Video has no dimensions".to_string()).into());
+    }
+
+    Ok(Some(info))
+}
+
+fn read_mp4_info(path: &Path) -> Result<Option<VideoInfo>> {
+    let fh = fs::File::open(path)?;
+    let size = fh.metadata()?.len();
+    let reader = Mp4Reader::read_header(BufReader::new(fh), size)?;
+
+    let mut video = None;
+    let mut audio_codec = None;
+    for track in reader.tracks().values() {
+        // Codecs the mp4 crate doesn't know by name are reported by their
+        // sample entry fourcc, eg av01
+        let codec = track
+            .media_type()
+            .map(|m| m.to_string())
+            .or_else(|_| track.box_type().map(|b| b.to_string()))
+            .unwrap_or_default();
+
+        match track.track_type()? {
+            TrackType::Video if video.is_none() => video = Some((track, codec)),
+            TrackType::Audio if audio_codec.is_none() => {
+                audio_codec = Some(codec)
+            }
+            _ => {}
+        }
+    }
+
+    let (track, video_codec) = match video {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    Ok(Some(VideoInfo {
+        width: track.width().into(),
+        height: track.height().into(),
+        duration: reader.duration().as_secs_f64(),
+        video_codec,
+        audio_codec,
+        frame_rate: track.frame_rate(),
+    }))
+}
+
+fn read_matroska_info(path: &Path) -> Result<VideoInfo> {
+    let mkv = Matroska::open(fs::File::open(path)?)?;
+
+    // Codec IDs look like V_VP9 or A_OPUS
+    let codec_name = |codec_id: &str| {
+        codec_id
+            .split_once('_')
+            .map(|(_, c)| c)
+            .unwrap_or(codec_id)
+            .to_lowercase()
+    };
+
+    let video = mkv
+        .tracks
+        .iter()
+        .find(|t| t.tracktype == Tracktype::Video)
+        .ok_or_else(|| Unsupported("No video track".to_string()))?;
+    let audio_codec = mkv
+        .tracks
+        .iter()
+        .find(|t| t.tracktype == Tracktype::Audio)
+        .map(|t| codec_name(&t.codec_id));
+
+    let (width, height) = match &video.settings {
+        Settings::Video(v) => (v.pixel_width, v.pixel_height),
+        _ => {
+            return Err(Unsupported(
+                "Video track has no dimensions".to_string(),
+            )
+            .into())
+        }
+    };
+
+    // Frame rate is only implied by the default duration of each frame
+    let frame_rate = match video.default_duration {
+        Some(d) if !d.is_zero() => 1.0 / d.as_secs_f64(),
+        _ => 0.0,
+    };
+
+    Ok(VideoInfo {
+        width: width.try_into()?,
+        height: height.try_into()?,
+        duration: mkv.info.duration.map(|d| d.as_secs_f64()).unwrap_or(0.0),
+        video_codec: codec_name(&video.codec_id),
+        audio_codec,
+        frame_rate,
+    })
+}
+
+/// Whether ffmpeg can be run from $PATH
+pub async fn ffmpeg_installed() -> bool {
+    Command::new(FFMPEG)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok()
+}
+
+/// Grab a single frame a tenth of the way into a video (past most fades from
+/// black) with ffmpeg. Gives None when ffmpeg is not installed
+pub async fn poster_frame(
+    path: &Path,
+    duration: f64,
+) -> Result<Option<DynamicImage>> {
+    let child = Command::new(FFMPEG)
+        .args(["-v", "error", "-ss"])
+        .arg(format!("{:.3}", duration / 10.0))
+        .arg("-i")
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Dropping the output future on timeout kills ffmpeg
+        .kill_on_drop(true)
+        .spawn();
+
+    let child = match child {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let output = tokio::time::timeout(FFMPEG_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "ffmpeg timed out after {}s",
+                FFMPEG_TIMEOUT.as_secs()
+            )
+        })??;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffmpeg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(Some(image::load_from_memory_with_format(
+        &output.stdout,
+        ImageFormat::Png,
+    )?))
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::Result;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::metadata;
+
+/// Name of the file `hooya watch` keeps inside a watched directory to
+/// remember what it imported
+pub const PROCESSED_LOG_NAME: &str = ".hooya-watch";
+
+/// How long a file must go unchanged before it is taken to be fully
+/// written, when no event says so
+pub const DEFAULT_SETTLE: Duration = Duration::from_secs(2);
+
+// Suffixes browsers and downloaders give files still being written
+const PARTIAL_SUFFIXES: [&str; 5] =
+    [".part", ".crdownload", ".download", ".tmp", ".partial"];
+
+/// Size and modification time of a file, which together tell whether it is
+/// the same file seen before
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    // Nanoseconds since the epoch
+    pub modified: i64,
+}
+
+/// What to do with a file once it has been imported
+#[derive(Clone, Debug, Default)]
+pub enum AfterImport {
+    #[default]
+    Keep,
+    Delete,
+    /// Move into this directory, keeping the path relative to the watched
+    /// one
+    Archive(PathBuf),
+}
+
+/// A watched directory, yielding files once they have finished being
+/// written: when closed after writing, moved in, or left untouched for the
+/// settle time
+pub struct Inbox {
+    root: PathBuf,
+    settle: Duration,
+    // Whether sidecars are held back, to be read with the files they
+    // describe
+    skip_sidecars: bool,
+    events: mpsc::Receiver<notify::Result<Event>>,
+    // Set when events were dropped, so the tree must be looked at again
+    missed: Arc<AtomicBool>,
+    // Files not yet settled, with how they looked when last changed
+    pending: HashMap<PathBuf, (FileStamp, Instant)>,
+    tick: Interval,
+    // Dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+}
+
+/// Files already imported from a watched directory, kept in a file so they
+/// aren't imported again after a restart
+pub struct ProcessedLog {
+    path: PathBuf,
+    entries: HashMap<PathBuf, FileStamp>,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64;
+
+        Ok(FileStamp {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+impl AfterImport {
+    /// Archiving into the watched directory would import files again
+    pub fn check(&self, root: &Path) -> Result<()> {
+        if let AfterImport::Archive(archive_dir) = self {
+            let archive_dir = archive_dir
+                .canonicalize()
+                .unwrap_or_else(|_| archive_dir.clone());
+            if archive_dir.starts_with(root.canonicalize()?) {
+                return Err(anyhow::anyhow!(
+                    "Archive directory {} is inside watched directory {}",
+                    archive_dir.display(),
+                    root.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, root: &Path, path: &Path) -> Result<()> {
+        match self {
+            AfterImport::Keep => {}
+            AfterImport::Delete => fs::remove_file(path)?,
+            AfterImport::Archive(archive_dir) => {
+                let relative = path.strip_prefix(root).unwrap_or(path);
+                let dest = archive_dir.join(relative);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // Renaming fails across filesystems, where copying doesn't
+                if fs::rename(path, &dest).is_err() {
+                    fs::copy(path, &dest)?;
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Inbox {
+    /// Start watching `root` and everything below it. Files already there
+    /// are yielded too, once settled. With `skip_sidecars`, sidecar
+    /// metadata files next to others are never yielded
+    pub fn new(
+        root: &Path,
+        settle: Duration,
+        skip_sidecars: bool,
+    ) -> Result<Self> {
+        let (tx, events) = mpsc::channel(1024);
+        let missed = Arc::new(AtomicBool::new(false));
+        let watcher_missed = missed.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |e: notify::Result<Event>| {
+                // Never block the notify thread while files are imported;
+                // what doesn't fit is made up for by a rescan
+                if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(e)
+                {
+                    watcher_missed.store(true, Ordering::Relaxed);
+                }
+            })?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+
+        let mut tick = tokio::time::interval(settle / 2);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut inbox = Inbox {
+            root: root.to_path_buf(),
+            settle,
+            skip_sidecars,
+            events,
+            missed,
+            pending: HashMap::new(),
+            tick,
+            _watcher: watcher,
+        };
+        inbox.seed(root)?;
+
+        Ok(inbox)
+    }
+
+    /// Wait for files that have finished being written
+    pub async fn next(&mut self) -> Result<Vec<PathBuf>> {
+        let mut ready = vec![];
+        loop {
+            tokio::select! {
+                event = self.events.recv() => {
+                    let event = match event {
+                        Some(e) => e?,
+                        None => {
+                            return Err(anyhow::anyhow!("Watcher stopped"))
+                        }
+                    };
+                    self.handle(event, &mut ready);
+                }
+                _ = self.tick.tick() => {
+                    if self.missed.swap(false, Ordering::Relaxed) {
+                        self.rescan();
+                    }
+                    self.settled(&mut ready)
+                }
+            }
+
+            if !ready.is_empty() {
+                return Ok(ready);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event, ready: &mut Vec<PathBuf>) {
+        // The watcher's own queue overflowed
+        if event.need_rescan() {
+            self.rescan();
+            return;
+        }
+
+        let finished = matches!(
+            event.kind,
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+
+        for path in event.paths {
+            if !self.is_candidate(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                // Files in a directory moved in raise no events of their own
+                let _ = self.seed(&path);
+                continue;
+            }
+
+            let stamp = match FileStamp::of(&path) {
+                Ok(s) => s,
+                Err(_) => {
+                    self.pending.remove(&path);
+                    continue;
+                }
+            };
+            if finished {
+                self.pending.remove(&path);
+                ready.push(path);
+            } else {
+                self.pending.insert(path, (stamp, Instant::now()));
+            }
+        }
+    }
+
+    /// Move pending files unchanged for the settle time to `ready`
+    fn settled(&mut self, ready: &mut Vec<PathBuf>) {
+        let mut done = vec![];
+        for (path, (stamp, changed)) in self.pending.iter_mut() {
+            match FileStamp::of(path) {
+                Ok(s) if s != *stamp => {
+                    *stamp = s;
+                    *changed = Instant::now();
+                }
+                Ok(_) if changed.elapsed() >= self.settle => {
+                    done.push((path.clone(), true))
+                }
+                Ok(_) => {}
+                // Gone before it settled
+                Err(_) => done.push((path.clone(), false)),
+            }
+        }
+
+        for (path, exists) in done {
+            self.pending.remove(&path);
+            // A sidecar may have been written before the file it describes
+            if exists && self.is_candidate(&path) {
+                ready.push(path);
+            }
+        }
+    }
+
+    /// Queue every file again after events were lost. Ones imported already
+    /// are skipped by the caller's record of them
+    fn rescan(&mut self) {
+        let root = self.root.clone();
+        let _ = self.seed(&root);
+    }
+
+    /// Queue every file below `dir`
+    fn seed(&mut self, dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !self.is_candidate(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                self.seed(&path)?;
+            } else if let Ok(stamp) = FileStamp::of(&path) {
+                self.pending.insert(path, (stamp, Instant::now()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hidden files, including the processed log, downloads still in
+    /// progress and held back sidecars are never imported
+    fn is_candidate(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let hidden = relative
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+        let name = path.to_string_lossy();
+        let partial = PARTIAL_SUFFIXES.iter().any(|s| name.ends_with(s));
+        let sidecar = self.skip_sidecars && metadata::is_sidecar(path);
+
+        !hidden && !partial && !sidecar
+    }
+}
+
+impl ProcessedLog {
+    /// Read the log, dropping entries for files that no longer exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut entries = HashMap::new();
+        if path.exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let mut fields = line.splitn(3, '\t');
+                let (size, modified, file) =
+                    match (fields.next(), fields.next(), fields.next()) {
+                        (Some(s), Some(m), Some(f)) => (s, m, f),
+                        _ => continue,
+                    };
+                let file = PathBuf::from(file);
+                if !file.exists() {
+                    continue;
+                }
+                entries.insert(
+                    file,
+                    FileStamp {
+                        size: size.parse()?,
+                        modified: modified.parse()?,
+                    },
+                );
+            }
+        }
+
+        let log = ProcessedLog {
+            path: path.to_path_buf(),
+            entries,
+        };
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    /// Whether this file was imported before, unchanged since
+    pub fn contains(&self, file: &Path, stamp: FileStamp) -> bool {
+        self.entries.get(file) == Some(&stamp)
+    }
+
+    pub fn record(&mut self, file: &Path, stamp: FileStamp) -> Result<()> {
+        let mut fh = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(fh, "{}", log_line(file, stamp))?;
+
+        self.entries.insert(file.to_path_buf(), stamp);
+        Ok(())
+    }
+
+    fn rewrite(&self) -> Result<()> {
+        let mut contents = String::new();
+        for (file, stamp) in &self.entries {
+            contents.push_str(&log_line(file, *stamp));
+            contents.push('\n');
+        }
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+}
+
+fn log_line(file: &Path, stamp: FileStamp) -> String {
+    format!("{}\t{}\t{}", stamp.size, stamp.modified, file.display())
+}